@@ -0,0 +1,330 @@
+//! The optional admin listener: runtime controls for failover drills
+//! (readiness, root health, injected latency, sequence resets, shutdown),
+//! request stats
+//! and captured requests. It is served on its own listener, so public
+//! traffic can never reach these endpoints.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::capture::RequestLog;
+use crate::handler::{Handler, Middleware, Request, Response};
+use crate::probes::Probes;
+use crate::sequences::SequenceTable;
+
+/// Highest latency `/latency?ms=` accepts, as every public request waits it.
+const MAX_LATENCY_MS: u64 = 10_000;
+
+/// Runtime settings changed through the admin API, and the stats it reports.
+#[derive(Debug)]
+pub(crate) struct Controls {
+    started_at: Instant,
+    root_healthy: AtomicBool,
+    latency_ms: AtomicU64,
+    requests: AtomicU64,
+    /// Responses by status class, `1xx` to `5xx`.
+    responses: [AtomicU64; 5],
+    /// Set by `POST /shutdown`, for [`Server::wait`](crate::Server::wait).
+    shutdown_requested: Mutex<bool>,
+    shutdown: Condvar,
+}
+
+impl Controls {
+    pub(crate) fn new() -> Self {
+        Controls {
+            started_at: Instant::now(),
+            root_healthy: AtomicBool::new(true),
+            latency_ms: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            responses: Default::default(),
+            shutdown_requested: Mutex::new(false),
+            shutdown: Condvar::new(),
+        }
+    }
+
+    fn request_shutdown(&self) {
+        *self
+            .shutdown_requested
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = true;
+        self.shutdown.notify_all();
+    }
+
+    /// Blocks until `POST /shutdown` is called.
+    pub(crate) fn wait_for_shutdown(&self) {
+        let requested = self
+            .shutdown_requested
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        drop(
+            self.shutdown
+                .wait_while(requested, |requested| !*requested)
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+    }
+
+    fn record(&self, status: u16) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let class = usize::from(status / 100);
+        if let Some(count) = class.checked_sub(1).and_then(|i| self.responses.get(i)) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn stats_json(&self, probes: &Probes) -> String {
+        let [r1, r2, r3, r4, r5] = &self.responses;
+        let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        format!(
+            concat!(
+                "{{\"uptime_secs\":{},\"ready\":{},\"root\":\"{}\",\"latency_ms\":{},",
+                "\"requests\":{},\"responses\":{{\"1xx\":{},\"2xx\":{},\"3xx\":{},",
+                "\"4xx\":{},\"5xx\":{}}}}}\n"
+            ),
+            self.started_at.elapsed().as_secs(),
+            probes.is_ready(),
+            if self.root_healthy.load(Ordering::Relaxed) {
+                "healthy"
+            } else {
+                "unhealthy"
+            },
+            self.latency_ms.load(Ordering::Relaxed),
+            count(&self.requests),
+            count(r1),
+            count(r2),
+            count(r3),
+            count(r4),
+            count(r5),
+        )
+    }
+}
+
+/// Applies the admin controls to public requests and counts their responses.
+pub(crate) struct Runtime {
+    controls: Arc<Controls>,
+}
+
+impl Runtime {
+    pub(crate) fn new(controls: Arc<Controls>) -> Self {
+        Runtime { controls }
+    }
+}
+
+impl Middleware for Runtime {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let latency = self.controls.latency_ms.load(Ordering::Relaxed);
+        if latency > 0 {
+            thread::sleep(Duration::from_millis(latency));
+        }
+        let response =
+            if request.path() == "/" && !self.controls.root_healthy.load(Ordering::Relaxed) {
+                Response::new(503)
+                    .with_header("Content-Type", "text/plain; charset=utf-8")
+                    .with_body(&b"UNHEALTHY"[..])
+            } else {
+                next.handle(request)
+            };
+        self.controls.record(response.status());
+        response
+    }
+}
+
+/// Answers the admin endpoints. Every successful call returns the stats.
+pub(crate) struct AdminHandler {
+    controls: Arc<Controls>,
+    probes: Arc<Probes>,
+    sequences: SequenceTable,
+    requests: Option<Arc<RequestLog>>,
+}
+
+impl AdminHandler {
+    pub(crate) fn new(
+        controls: Arc<Controls>,
+        probes: Arc<Probes>,
+        sequences: SequenceTable,
+        requests: Option<Arc<RequestLog>>,
+    ) -> Self {
+        AdminHandler {
+            controls,
+            probes,
+            sequences,
+            requests,
+        }
+    }
+
+    fn stats(&self) -> Response {
+        Response::new(200)
+            .with_header("Content-Type", "application/json")
+            .with_header("Cache-Control", "no-store")
+            .with_body(self.controls.stats_json(&self.probes).into_bytes())
+    }
+
+    fn set_latency(&self, request: &Request) -> Response {
+        let ms = request
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("ms="))
+            .and_then(|ms| ms.parse::<u64>().ok())
+            .filter(|&ms| ms <= MAX_LATENCY_MS);
+        match ms {
+            Some(ms) => {
+                self.controls.latency_ms.store(ms, Ordering::Relaxed);
+                self.stats()
+            }
+            None => Response::new(400)
+                .with_header("Content-Type", "text/plain; charset=utf-8")
+                .with_body(format!("expected ?ms=<0 to {}>\n", MAX_LATENCY_MS).into_bytes()),
+        }
+    }
+}
+
+impl Handler for AdminHandler {
+    fn handle(&self, request: &Request) -> Response {
+        let allow = match request.path() {
+            "/stats" => "GET, HEAD",
+            "/ready" | "/unready" | "/root/healthy" | "/root/unhealthy" | "/latency"
+            | "/sequences/reset" | "/shutdown" => "POST",
+            "/requests" if self.requests.is_some() => "GET, HEAD, DELETE",
+            _ => return Response::new(404),
+        };
+        if !allow.split(", ").any(|method| method == request.method()) {
+            return Response::new(405).with_header("Allow", allow);
+        }
+        match request.path() {
+            "/ready" => self.probes.set_ready(true),
+            "/unready" => self.probes.set_ready(false),
+            "/root/healthy" => self.controls.root_healthy.store(true, Ordering::Relaxed),
+            "/root/unhealthy" => self.controls.root_healthy.store(false, Ordering::Relaxed),
+            "/latency" => return self.set_latency(request),
+            "/sequences/reset" => self.sequences.reset(),
+            "/shutdown" => {
+                self.probes.start_draining();
+                self.controls.request_shutdown();
+            }
+            "/requests" => {
+                let Some(requests) = &self.requests else {
+                    return Response::new(404);
+                };
+                if request.method() != "DELETE" {
+                    return requests.respond();
+                }
+                requests.clear();
+                return Response::new(204);
+            }
+            _ => {}
+        }
+        self.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AdminHandler, Controls, Runtime};
+    use crate::handler::{Chain, Handler, Request, Response};
+    use crate::probes::Probes;
+    use crate::sequences::SequenceTable;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn setup() -> (AdminHandler, Chain, Arc<Probes>) {
+        let controls = Arc::new(Controls::new());
+        let probes = Arc::new(Probes::new(Duration::ZERO));
+        let sequences = SequenceTable::new(HashMap::new()).unwrap();
+        let admin = AdminHandler::new(Arc::clone(&controls), Arc::clone(&probes), sequences, None);
+        let public = Chain::new(
+            vec![Box::new(Runtime::new(controls))],
+            Box::new(|_: &Request| Response::text("OK")),
+        );
+        (admin, public, probes)
+    }
+
+    fn body(response: &Response) -> String {
+        String::from_utf8_lossy(response.body()).into_owned()
+    }
+
+    #[test]
+    fn toggles_readiness_and_root_health() {
+        let (admin, public, probes) = setup();
+        assert_eq!(
+            admin.handle(&Request::new("POST", "/unready")).status(),
+            200
+        );
+        assert!(!probes.is_ready());
+        admin.handle(&Request::new("POST", "/ready"));
+        assert!(probes.is_ready());
+
+        admin.handle(&Request::new("POST", "/root/unhealthy"));
+        let root = public.handle(&Request::new("GET", "/"));
+        assert_eq!((root.status(), body(&root).as_str()), (503, "UNHEALTHY"));
+        assert_eq!(public.handle(&Request::new("GET", "/other")).status(), 200);
+        admin.handle(&Request::new("POST", "/root/healthy"));
+        assert_eq!(public.handle(&Request::new("GET", "/")).status(), 200);
+    }
+
+    #[test]
+    fn requests_a_shutdown() {
+        let (admin, _, probes) = setup();
+        assert_eq!(
+            admin.handle(&Request::new("GET", "/shutdown")).status(),
+            405
+        );
+        let response = admin.handle(&Request::new("POST", "/shutdown"));
+        assert!(body(&response).contains("\"ready\":false"));
+        assert!(!probes.is_ready());
+        // Returns at once now that the shutdown was requested.
+        admin.controls.wait_for_shutdown();
+    }
+
+    #[test]
+    fn injects_latency() {
+        let (admin, public, _) = setup();
+        let response = admin.handle(&Request::new("POST", "/latency?ms=50"));
+        assert!(body(&response).contains("\"latency_ms\":50"));
+        let started = Instant::now();
+        public.handle(&Request::new("GET", "/"));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            admin
+                .handle(&Request::new("POST", "/latency?ms=x"))
+                .status(),
+            400
+        );
+        let unbounded = admin.handle(&Request::new("POST", "/latency?ms=18446744073709551615"));
+        assert_eq!(unbounded.status(), 400);
+        assert!(body(&admin.handle(&Request::new("GET", "/stats"))).contains("\"latency_ms\":50"));
+    }
+
+    #[test]
+    fn reports_stats() {
+        let (admin, public, _) = setup();
+        admin.handle(&Request::new("POST", "/root/unhealthy"));
+        public.handle(&Request::new("GET", "/"));
+        public.handle(&Request::new("GET", "/x"));
+        let stats = body(&admin.handle(&Request::new("GET", "/stats")));
+        assert!(
+            stats.contains("\"ready\":true,\"root\":\"unhealthy\""),
+            "{}",
+            stats
+        );
+        assert!(
+            stats.contains(
+                "\"requests\":2,\"responses\":{\"1xx\":0,\"2xx\":1,\"3xx\":0,\"4xx\":0,\"5xx\":1}"
+            ),
+            "{}",
+            stats
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_paths_and_methods() {
+        let (admin, _, _) = setup();
+        assert_eq!(admin.handle(&Request::new("GET", "/")).status(), 404);
+        let response = admin.handle(&Request::new("GET", "/ready"));
+        assert_eq!(response.status(), 405);
+        assert_eq!(response.header("allow"), Some("POST"));
+        assert_eq!(admin.handle(&Request::new("POST", "/stats")).status(), 405);
+    }
+}
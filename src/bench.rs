@@ -0,0 +1,357 @@
+//! A small HTTP/1.1 load generator, behind `ok bench`: concurrent `GET`s over
+//! plain TCP, one thread per connection, validated with the same parser the
+//! server uses for requests.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::http1::{header_value, read_response};
+
+/// How long a run lasts when neither a duration nor a request count is set.
+const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+
+/// Read and write timeout for each request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A benchmark of one URL.
+///
+/// ```no_run
+/// use ok::Bench;
+///
+/// let report = Bench::new("http://localhost:8080/").unwrap().connections(8).run();
+/// print!("{}", report);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bench {
+    authority: String,
+    path: String,
+    connections: usize,
+    requests: Option<u64>,
+    duration: Option<Duration>,
+    keep_alive: bool,
+}
+
+impl Bench {
+    /// `target` is `host:port` with an optional path, optionally prefixed
+    /// with `http://`.
+    pub fn new(target: &str) -> io::Result<Self> {
+        if target.starts_with("https://") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bench only supports plain http targets",
+            ));
+        }
+        let target = target.strip_prefix("http://").unwrap_or(target);
+        let (authority, path) = match target.find('/') {
+            Some(slash) => target.split_at(slash),
+            None => (target, "/"),
+        };
+        if authority.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("missing host in bench target {:?}", target),
+            ));
+        }
+        Ok(Bench {
+            authority: authority.to_string(),
+            path: path.to_string(),
+            connections: 1,
+            requests: None,
+            duration: None,
+            keep_alive: true,
+        })
+    }
+
+    /// Number of concurrent connections, each on its own thread. Defaults
+    /// to 1.
+    pub fn connections(mut self, connections: usize) -> Self {
+        self.connections = connections.max(1);
+        self
+    }
+
+    /// Stops after this many requests.
+    pub fn requests(mut self, requests: u64) -> Self {
+        self.requests = Some(requests);
+        self
+    }
+
+    /// Stops after this long. Defaults to 10 seconds unless a request count
+    /// is set.
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// Reuses connections the server keeps open. Defaults to true.
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// The URL requested, for display.
+    pub fn url(&self) -> String {
+        format!("http://{}{}", self.authority, self.path)
+    }
+
+    /// Runs the benchmark. Failed requests are counted in the report rather
+    /// than returned as errors.
+    pub fn run(&self) -> BenchReport {
+        let duration = match (self.duration, self.requests) {
+            (Some(duration), _) => Some(duration),
+            (None, Some(_)) => None,
+            (None, None) => Some(DEFAULT_DURATION),
+        };
+        let started = Instant::now();
+        let deadline = duration.map(|duration| started + duration);
+        let sent = AtomicU64::new(0);
+        let next = || {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return false;
+            }
+            match self.requests {
+                Some(limit) => sent.fetch_add(1, Ordering::Relaxed) < limit,
+                None => true,
+            }
+        };
+        let mut report = thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.connections)
+                .map(|_| scope.spawn(|| self.worker(&next)))
+                .collect();
+            let mut report = BenchReport::default();
+            for worker in workers {
+                if let Ok(partial) = worker.join() {
+                    report.merge(partial);
+                }
+            }
+            report
+        });
+        report.elapsed = started.elapsed();
+        report.latencies.sort_unstable();
+        report
+    }
+
+    /// Sends requests over one connection at a time until `next` says stop.
+    fn worker(&self, next: &dyn Fn() -> bool) -> BenchReport {
+        let mut report = BenchReport::default();
+        let mut connection: Option<TcpStream> = None;
+        while next() {
+            let mut stream = match connection.take() {
+                Some(stream) => stream,
+                None => match self.connect() {
+                    Ok(stream) => stream,
+                    Err(_) => {
+                        report.error("connect");
+                        continue;
+                    }
+                },
+            };
+            let started = Instant::now();
+            match self.request(&mut stream) {
+                Ok((status, reusable)) => {
+                    report.latencies.push(started.elapsed());
+                    *report.statuses.entry(status).or_default() += 1;
+                    if reusable {
+                        connection = Some(stream);
+                    }
+                }
+                Err(e) => report.error(match e.kind() {
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => "timeout",
+                    io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe => "closed",
+                    io::ErrorKind::InvalidData => "invalid response",
+                    _ => "other",
+                }),
+            }
+        }
+        report
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect(&self.authority)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    /// Sends one request, returning its status and whether the connection
+    /// can carry another.
+    fn request(&self, stream: &mut TcpStream) -> io::Result<(u16, bool)> {
+        let connection = if self.keep_alive {
+            "keep-alive"
+        } else {
+            "close"
+        };
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: {}\r\n\r\n",
+            self.path, self.authority, connection
+        )?;
+        let (status, head, _) = read_response(stream)?;
+        let reusable = self.keep_alive
+            && head.starts_with("HTTP/1.1 ")
+            && !header_value(&head, "connection").is_some_and(|v| v.eq_ignore_ascii_case("close"))
+            && (header_value(&head, "content-length").is_some()
+                || matches!(status, 100..=199 | 204 | 304));
+        Ok((status, reusable))
+    }
+}
+
+/// The outcome of a [`Bench`] run.
+#[derive(Clone, Debug, Default)]
+pub struct BenchReport {
+    elapsed: Duration,
+    /// Latencies of the answered requests, sorted once the run ends.
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<u16, u64>,
+    errors: BTreeMap<&'static str, u64>,
+}
+
+impl BenchReport {
+    fn error(&mut self, kind: &'static str) {
+        *self.errors.entry(kind).or_default() += 1;
+    }
+
+    fn merge(&mut self, other: BenchReport) {
+        self.latencies.extend(other.latencies);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+        for (kind, count) in other.errors {
+            *self.errors.entry(kind).or_default() += count;
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Number of requests that got a response, whatever its status.
+    pub fn responses(&self) -> u64 {
+        self.latencies.len() as u64
+    }
+
+    /// Responses per second.
+    pub fn throughput(&self) -> f64 {
+        self.responses() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// The latency below which `percent` of the responses arrived, or `None`
+    /// without responses.
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        let rank = (percent / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        let last = self.latencies.len().checked_sub(1)?;
+        Some(self.latencies[rank.saturating_sub(1).min(last)])
+    }
+
+    /// Response counts by status.
+    pub fn statuses(&self) -> &BTreeMap<u16, u64> {
+        &self.statuses
+    }
+
+    /// Failed requests by kind: `connect`, `timeout`, `closed`,
+    /// `invalid response` or `other`.
+    pub fn errors(&self) -> &BTreeMap<&'static str, u64> {
+        &self.errors
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Responses: {} in {:.2?} ({:.1} req/s)",
+            self.responses(),
+            self.elapsed,
+            self.throughput()
+        )?;
+        if let Some(max) = self.percentile(100.0) {
+            let latency = |percent| self.percentile(percent).unwrap_or_default();
+            writeln!(
+                f,
+                "Latency:   p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+                latency(50.0),
+                latency(90.0),
+                latency(99.0),
+                max
+            )?;
+        }
+        let statuses: Vec<String> = self
+            .statuses
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status, count))
+            .collect();
+        writeln!(f, "Statuses:  {}", none_if_empty(&statuses))?;
+        let errors: Vec<String> = self
+            .errors
+            .iter()
+            .map(|(kind, count)| format!("{}: {}", kind, count))
+            .collect();
+        writeln!(f, "Errors:    {}", none_if_empty(&errors))
+    }
+}
+
+fn none_if_empty(counts: &[String]) -> String {
+    if counts.is_empty() {
+        "none".into()
+    } else {
+        counts.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bench, BenchReport};
+    use crate::Server;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_targets() {
+        let bench = Bench::new("http://localhost:8080/ready?x=1").unwrap();
+        assert_eq!(bench.url(), "http://localhost:8080/ready?x=1");
+        assert_eq!(
+            Bench::new("localhost:8080").unwrap().url(),
+            "http://localhost:8080/"
+        );
+        assert!(Bench::new("https://localhost:8443/").is_err());
+        assert!(Bench::new("http:///").is_err());
+    }
+
+    #[test]
+    fn reports_percentiles() {
+        let report = BenchReport {
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            ..BenchReport::default()
+        };
+        assert_eq!(report.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(report.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(100.0), Some(Duration::from_millis(100)));
+        assert_eq!(BenchReport::default().percentile(50.0), None);
+    }
+
+    #[test]
+    fn benchmarks_a_server() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .start()
+            .unwrap();
+        let target = server.local_addr().to_string();
+        let report = Bench::new(&target)
+            .unwrap()
+            .connections(4)
+            .requests(40)
+            .run();
+        assert_eq!(report.responses(), 40, "{}", report);
+        assert_eq!(report.statuses().get(&200), Some(&40));
+        assert!(report.errors().is_empty(), "{}", report);
+        assert!(report.to_string().contains("Statuses:  200: 40\n"));
+        server.shutdown();
+    }
+}
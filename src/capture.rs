@@ -0,0 +1,178 @@
+//! A bounded in-memory log of recent requests, for contract tests that assert
+//! which requests a client made. It is read with `GET /__requests` (or
+//! `GET /requests` on the admin listener) and cleared with `DELETE`.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::handler::{Handler, Middleware, Request, Response};
+use crate::{json_body, json_pairs, json_string};
+
+/// The public path of the captured requests.
+const CAPTURE_PATH: &str = "/__requests";
+
+/// Bodies are stored up to this size; `body_size` still reports the full one.
+const MAX_CAPTURED_BODY: usize = 64 * 1024;
+
+/// The most recent requests, oldest first, each already rendered as JSON.
+pub(crate) struct RequestLog {
+    capacity: usize,
+    entries: Mutex<VecDeque<String>>,
+}
+
+impl RequestLog {
+    pub(crate) fn new(capacity: usize) -> Self {
+        RequestLog {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn record(&self, request: &Request, status: u16) {
+        let entry = entry_json(request, status);
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// The captured requests as a JSON array.
+    pub(crate) fn respond(&self) -> Response {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let body = format!(
+            "[{}]\n",
+            entries.iter().cloned().collect::<Vec<_>>().join(",")
+        );
+        Response::new(200)
+            .with_header("Content-Type", "application/json")
+            .with_header("Cache-Control", "no-store")
+            .with_body(body.into_bytes())
+    }
+}
+
+fn entry_json(request: &Request, status: u16) -> String {
+    let time_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis());
+    let stored = &request.body()[..request.body().len().min(MAX_CAPTURED_BODY)];
+    format!(
+        "{{\"time_ms\":{},\"peer\":{},\"method\":{},\"path\":{},\"query\":{},\
+         \"headers\":{},\"body_size\":{},{},\"status\":{}}}",
+        time_ms,
+        json_string(request.peer()),
+        json_string(request.method()),
+        json_string(request.path()),
+        request.query().map_or("null".into(), json_string),
+        json_pairs(request.headers()),
+        request.body().len(),
+        json_body(stored),
+        status,
+    )
+}
+
+/// Records every public request with the status it got, and serves
+/// [`CAPTURE_PATH`].
+pub(crate) struct Capture {
+    log: Arc<RequestLog>,
+}
+
+impl Capture {
+    pub(crate) fn new(log: Arc<RequestLog>) -> Self {
+        Capture { log }
+    }
+}
+
+impl Middleware for Capture {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        if request.path() == CAPTURE_PATH {
+            return match request.method() {
+                "GET" | "HEAD" => self.log.respond(),
+                "DELETE" => {
+                    self.log.clear();
+                    Response::new(204)
+                }
+                _ => Response::new(405).with_header("Allow", "GET, HEAD, DELETE"),
+            };
+        }
+        let response = next.handle(request);
+        self.log.record(request, response.status());
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capture, RequestLog, MAX_CAPTURED_BODY};
+    use crate::handler::{Chain, Handler, Request, Response};
+    use std::sync::Arc;
+
+    fn chain(capacity: usize) -> Chain {
+        Chain::new(
+            vec![Box::new(Capture::new(Arc::new(RequestLog::new(capacity))))],
+            Box::new(|request: &Request| match request.path() {
+                "/" => Response::text("OK"),
+                _ => Response::new(404),
+            }),
+        )
+    }
+
+    fn list(chain: &Chain) -> String {
+        let response = chain.handle(&Request::new("GET", "/__requests"));
+        String::from_utf8_lossy(response.body()).into_owned()
+    }
+
+    #[test]
+    fn captures_requests_with_their_status() {
+        let chain = chain(10);
+        let request = Request::new("POST", "/missing?x=1")
+            .with_header("Content-Type", "text/plain")
+            .with_body("hi");
+        chain.handle(&request);
+        let captured = list(&chain);
+        assert!(captured.starts_with("[{\"time_ms\":"), "{}", captured);
+        assert!(
+            captured.ends_with(concat!(
+                "\"peer\":\"unknown\",\"method\":\"POST\",\"path\":\"/missing\",",
+                "\"query\":\"x=1\",\"headers\":[[\"Content-Type\",\"text/plain\"]],",
+                "\"body_size\":2,\"body\":\"hi\",\"status\":404}]\n"
+            )),
+            "{}",
+            captured
+        );
+    }
+
+    #[test]
+    fn keeps_the_most_recent_requests() {
+        let chain = chain(2);
+        for path in ["/a", "/b", "/c"] {
+            chain.handle(&Request::new("GET", path));
+        }
+        let captured = list(&chain);
+        assert!(!captured.contains("\"/a\""), "{}", captured);
+        assert!(captured.contains("\"/b\"") && captured.contains("\"/c\""));
+
+        let deleted = chain.handle(&Request::new("DELETE", "/__requests"));
+        assert_eq!(deleted.status(), 204);
+        assert_eq!(list(&chain), "[]\n");
+        let post = chain.handle(&Request::new("POST", "/__requests"));
+        assert_eq!(post.header("allow"), Some("GET, HEAD, DELETE"));
+    }
+
+    #[test]
+    fn truncates_large_bodies() {
+        let chain = chain(1);
+        chain.handle(&Request::new("PUT", "/").with_body(vec![b'a'; MAX_CAPTURED_BODY + 1]));
+        let captured = list(&chain);
+        assert!(captured.contains(&format!("\"body_size\":{}", MAX_CAPTURED_BODY + 1)));
+        assert!(captured.contains(&format!("\"body\":\"{}\"", "a".repeat(MAX_CAPTURED_BODY))));
+    }
+}
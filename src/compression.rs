@@ -0,0 +1,570 @@
+//! Response compression: `Accept-Encoding` negotiation and a small deflate
+//! encoder (LZ77 with fixed Huffman codes) wrapped as `gzip` or, for the
+//! `deflate` coding, zlib. Fixed bodies are compressed once when they are
+//! built; other bodies when they are sent, reading streamed files of up to
+//! [`MAX_FILE_SIZE`] into memory first. Larger files are sent uncompressed.
+
+use std::borrow::Cow;
+use std::io;
+use std::sync::Arc;
+
+use crate::handler::{Request, Response};
+
+/// Bodies smaller than this are sent as they are: the headers would cost
+/// more than compression saves.
+const MIN_SIZE: usize = 256;
+
+/// Streamed file bodies larger than this are sent as they are, rather than
+/// read into memory to be compressed.
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Content types worth compressing, by prefix; images, fonts and archives
+/// other than SVG are compressed already.
+const COMPRESSIBLE: &[&str] = &[
+    "text/",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/yaml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+/// A content coding the server can apply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(self, body: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Gzip => gzip(body),
+            Encoding::Deflate => zlib(body),
+        }
+    }
+}
+
+/// Compressed copies of a fixed body, each kept only if it is smaller.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Precompressed {
+    gzip: Option<Vec<u8>>,
+    deflate: Option<Vec<u8>>,
+}
+
+impl Precompressed {
+    fn get(&self, encoding: Encoding) -> Option<&[u8]> {
+        match encoding {
+            Encoding::Gzip => self.gzip.as_deref(),
+            Encoding::Deflate => self.deflate.as_deref(),
+        }
+    }
+}
+
+/// Prepares the compressed variants of a response that is sent many times,
+/// such as a route or the favicon.
+pub(crate) fn precompress(response: Response) -> Response {
+    if !compressible(&response) {
+        return response;
+    }
+    let smaller = |encoding: Encoding| {
+        let encoded = encoding.encode(response.body());
+        (encoded.len() < response.body().len()).then_some(encoded)
+    };
+    let precompressed = Precompressed {
+        gzip: smaller(Encoding::Gzip),
+        deflate: smaller(Encoding::Deflate),
+    };
+    response.with_precompressed(Arc::new(precompressed))
+}
+
+/// Encodes `response` with the best coding the request accepts. Responses it
+/// applies to get `Vary: Accept-Encoding` whether or not they are encoded,
+/// and encoded ones an `ETag` of their own.
+pub(crate) fn compress(request: &Request, response: Response) -> Response {
+    if !compressible(&response) {
+        return response;
+    }
+    let response = response.with_header("Vary", "Accept-Encoding");
+    // Ranges of a file stay ranges of the file rather than of its encoding.
+    if is_streamed(&response) && request.header("range").is_some() {
+        return response;
+    }
+    let Some(encoding) = request.header("accept-encoding").and_then(negotiate) else {
+        return response;
+    };
+    let encoded = match response.precompressed() {
+        Some(precompressed) => precompressed.get(encoding).map(<[u8]>::to_vec),
+        None => {
+            let Ok(body) = whole_body(&response) else {
+                return response;
+            };
+            let encoded = encoding.encode(&body);
+            (encoded.len() < body.len()).then_some(encoded)
+        }
+    };
+    let Some(encoded) = encoded else {
+        return response;
+    };
+    let etag = response.header("etag").and_then(|etag| {
+        let opaque = etag.strip_suffix('"')?;
+        Some(format!("{}-{}\"", opaque, encoding.name()))
+    });
+    let mut response = response
+        .with_header("Content-Encoding", encoding.name())
+        .with_body(encoded);
+    if let Some(etag) = etag {
+        response = response.replace_header("ETag", etag);
+    }
+    response
+}
+
+/// The body of `response`, reading a streamed file into memory.
+fn whole_body(response: &Response) -> io::Result<Cow<'_, [u8]>> {
+    if !is_streamed(response) {
+        return Ok(Cow::Borrowed(response.body()));
+    }
+    let mut body = Vec::with_capacity(response.body_len() as usize);
+    response.write_body(u64::MAX, |chunk, _| {
+        body.extend_from_slice(chunk);
+        Ok::<_, io::Error>(())
+    })?;
+    Ok(Cow::Owned(body))
+}
+
+fn is_streamed(response: &Response) -> bool {
+    response.body_len() != response.body().len() as u64
+}
+
+/// Whether a response is one compression applies to: a successful body of a
+/// compressible type that is not encoded already, in memory or a file of at
+/// most [`MAX_FILE_SIZE`].
+fn compressible(response: &Response) -> bool {
+    response.status() == 200
+        && response.fault().is_none()
+        && response.body_len() >= MIN_SIZE as u64
+        && (!is_streamed(response) || response.body_len() <= MAX_FILE_SIZE)
+        && response.header("content-encoding").is_none()
+        && response.header("content-type").is_some_and(|content_type| {
+            let content_type = content_type.to_ascii_lowercase();
+            COMPRESSIBLE
+                .iter()
+                .any(|prefix| content_type.starts_with(prefix))
+        })
+}
+
+/// The coding to use for an `Accept-Encoding` header: the one with the
+/// highest quality, `gzip` on a tie. `None` when neither is acceptable.
+pub(crate) fn negotiate(accept: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, u16)> = None;
+    let mut wildcard = None;
+    let (mut gzip, mut deflate) = (None, None);
+    for coding in accept.split(',') {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .next()
+            .map_or(Some(1000), parse_quality);
+        let Some(quality) = quality else {
+            continue;
+        };
+        match name.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(quality),
+            "deflate" => deflate = Some(quality),
+            "*" => wildcard = Some(quality),
+            _ => {}
+        }
+    }
+    for (encoding, quality) in [(Encoding::Gzip, gzip), (Encoding::Deflate, deflate)] {
+        let quality = quality.or(wildcard).unwrap_or(0);
+        let better = match best {
+            Some((_, best)) => quality > best,
+            None => quality > 0,
+        };
+        if better {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// A `q` value in thousandths, `None` if it is malformed.
+fn parse_quality(q: &str) -> Option<u16> {
+    let q: f64 = q.trim().parse().ok()?;
+    (0.0..=1.0)
+        .contains(&q)
+        .then(|| (q * 1000.0).round() as u16)
+}
+
+/// `data` as a gzip member (RFC 1952).
+pub(crate) fn gzip(data: &[u8]) -> Vec<u8> {
+    // No name or modification time; the OS is "unknown".
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend(deflate(data));
+    out.extend(crc32(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+/// `data` as a zlib stream (RFC 1950), which is what HTTP calls `deflate`.
+pub(crate) fn zlib(data: &[u8]) -> Vec<u8> {
+    // 32 KiB window, default compression level, no dictionary.
+    let mut out = vec![0x78, 0x9c];
+    out.extend(deflate(data));
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash are tried per match.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Raw deflate data (RFC 1951): one final block with the fixed Huffman
+/// codes, and matches found through hash chains.
+pub(crate) fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    bits.write(1, 1); // BFINAL
+    bits.write(1, 2); // BTYPE: fixed Huffman codes
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+    let hash = |i: usize| {
+        let key = u32::from(data[i]) << 16 | u32::from(data[i + 1]) << 8 | u32::from(data[i + 2]);
+        (key.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+    };
+    let mut i = 0;
+    while i < data.len() {
+        let mut best = (0, 0);
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(i);
+            let mut candidate = head[h];
+            let mut chain = 0;
+            while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let max = (data.len() - i).min(MAX_MATCH);
+                let len = (0..max)
+                    .take_while(|&k| data[candidate + k] == data[i + k])
+                    .count();
+                if len > best.0 {
+                    best = (len, i - candidate);
+                    if len == max {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+            prev[i] = head[h];
+            head[h] = i;
+        }
+        let (len, distance) = best;
+        if len < MIN_MATCH {
+            write_literal(&mut bits, u16::from(data[i]));
+            i += 1;
+            continue;
+        }
+        write_match(&mut bits, len, distance);
+        // Index the skipped positions so later matches can refer to them.
+        let end = (i + len).min(data.len().saturating_sub(MIN_MATCH - 1));
+        for (j, link) in prev.iter_mut().enumerate().take(end).skip(i + 1) {
+            let h = hash(j);
+            *link = head[h];
+            head[h] = j;
+        }
+        i += len;
+    }
+    write_literal(&mut bits, 256); // end of block
+    bits.finish()
+}
+
+/// A literal byte or, from 256, a length symbol in the fixed literal/length
+/// code.
+fn write_literal(bits: &mut BitWriter, symbol: u16) {
+    let (code, len) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xc0 + symbol - 280, 8),
+    };
+    bits.write_code(code, len);
+}
+
+fn write_match(bits: &mut BitWriter, len: usize, distance: usize) {
+    let index = LENGTH_BASES
+        .iter()
+        .rposition(|&base| usize::from(base) <= len)
+        .unwrap_or(0);
+    write_literal(bits, 257 + index as u16);
+    bits.write(
+        len as u32 - u32::from(LENGTH_BASES[index]),
+        LENGTH_EXTRA[index],
+    );
+    let index = DISTANCE_BASES
+        .iter()
+        .rposition(|&base| usize::from(base) <= distance)
+        .unwrap_or(0);
+    // Distance codes are all 5 bits in the fixed code.
+    bits.write_code(index as u16, 5);
+    bits.write(
+        distance as u32 - u32::from(DISTANCE_BASES[index]),
+        DISTANCE_EXTRA[index],
+    );
+}
+
+/// Packs bits least significant first, as deflate does.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    len: u8,
+}
+
+impl BitWriter {
+    /// Writes the low `len` bits of `value`.
+    fn write(&mut self, value: u32, len: u8) {
+        self.buffer |= value << self.len;
+        self.len += len;
+        while self.len >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which goes most significant bit first.
+    fn write_code(&mut self, code: u16, len: u8) {
+        let reversed = code.reverse_bits() >> (16 - len);
+        self.write(u32::from(reversed), len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= 65_521;
+        b %= 65_521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        adler32, compress, crc32, deflate, gzip, negotiate, precompress, zlib, Encoding,
+        MAX_FILE_SIZE,
+    };
+    use crate::handler::{Request, Response};
+    use std::fs::{self, File};
+
+    /// Decodes raw deflate data made of fixed-Huffman and stored blocks,
+    /// enough to check the encoder.
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut position = 0;
+        let mut bit = |n: u32| {
+            let mut value = 0;
+            for i in 0..n {
+                let byte = data[position / 8];
+                value |= u32::from(byte >> (position % 8) & 1) << i;
+                position += 1;
+            }
+            value
+        };
+        let mut out: Vec<u8> = Vec::new();
+        assert_eq!(bit(1), 1, "single final block");
+        assert_eq!(bit(2), 1, "fixed Huffman codes");
+        let lengths = [
+            3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99,
+            115, 131, 163, 195, 227, 258,
+        ];
+        let distances = [
+            1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025,
+            1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+        ];
+        loop {
+            // Read the fixed code most significant bit first.
+            let mut code = 0;
+            let mut len = 0;
+            let symbol = loop {
+                code = code << 1 | bit(1);
+                len += 1;
+                match (len, code) {
+                    (7, 0..=0x17) => break code + 256,
+                    (8, 0x30..=0xbf) => break code - 0x30,
+                    (8, 0xc0..=0xc7) => break code - 0xc0 + 280,
+                    (9, 0x190..=0x1ff) => break code - 0x190 + 144,
+                    _ => assert!(len < 9, "invalid code"),
+                }
+            };
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => return out,
+                _ => {
+                    let index = (symbol - 257) as usize;
+                    let extra = if index < 8 || index == 28 {
+                        0
+                    } else {
+                        (index as u32 - 4) / 4
+                    };
+                    let len = lengths[index] + bit(extra) as usize;
+                    let mut code = 0;
+                    for _ in 0..5 {
+                        code = code << 1 | bit(1);
+                    }
+                    let code = code as usize;
+                    let extra = if code < 4 { 0 } else { (code as u32 - 2) / 2 };
+                    let distance = distances[code] + bit(extra) as usize;
+                    for _ in 0..len {
+                        out.push(out[out.len() - distance]);
+                    }
+                }
+            }
+        }
+    }
+
+    fn fixture() -> Vec<u8> {
+        (0..200)
+            .map(|i| format!("{{\"id\":{},\"name\":\"user {}\"}}\n", i, i % 7))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    #[test]
+    fn deflates_and_inflates_back() {
+        let repetitive = fixture();
+        let encoded = deflate(&repetitive);
+        assert!(encoded.len() < repetitive.len() / 4, "{}", encoded.len());
+        assert_eq!(inflate(&encoded), repetitive);
+        let bytes: Vec<u8> = (0..=255).cycle().take(70_000).collect();
+        assert_eq!(inflate(&deflate(&bytes)), bytes);
+        assert_eq!(inflate(&deflate(b"")), b"");
+        assert_eq!(inflate(&deflate(b"aaaaaaaaaa")), b"aaaaaaaaaa");
+    }
+
+    #[test]
+    fn wraps_deflate_data() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        let data = fixture();
+        let gzip = gzip(&data);
+        assert_eq!(gzip[..4], [0x1f, 0x8b, 8, 0]);
+        assert_eq!(inflate(&gzip[10..gzip.len() - 8]), data);
+        assert_eq!(gzip[gzip.len() - 4..], (data.len() as u32).to_le_bytes());
+        let zlib = zlib(&data);
+        assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0);
+        assert_eq!(inflate(&zlib[2..zlib.len() - 4]), data);
+    }
+
+    #[test]
+    fn negotiates_content_codings() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("gzip;q=0.5, deflate"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("gzip;q=0, *"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("*;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br, identity"), None);
+        assert_eq!(negotiate("gzip;q=0"), None);
+    }
+
+    #[test]
+    fn compresses_eligible_responses() {
+        let json = || {
+            Response::new(200)
+                .with_header("Content-Type", "application/json")
+                .with_header("ETag", "\"abc\"")
+                .with_body(fixture())
+        };
+        let request = Request::new("GET", "/").with_header("Accept-Encoding", "gzip");
+        let response = compress(&request, json());
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+        assert_eq!(response.header("etag"), Some("\"abc-gzip\""));
+        assert_eq!(response.body(), gzip(&fixture()));
+        assert_eq!(compress(&request, precompress(json())), response);
+
+        let identity = compress(&Request::new("GET", "/"), json());
+        assert_eq!(identity.header("content-encoding"), None);
+        assert_eq!(identity.header("vary"), Some("Accept-Encoding"));
+        let small = Response::text("OK");
+        assert_eq!(compress(&request, small.clone()), small);
+        let image = Response::new(200)
+            .with_header("Content-Type", "image/png")
+            .with_body(fixture());
+        assert_eq!(compress(&request, image.clone()), image);
+    }
+
+    #[test]
+    fn compresses_file_bodies_up_to_a_size_limit() {
+        let path = std::env::temp_dir().join(format!("ok-compress-{}.json", std::process::id()));
+        let file = |contents: &[u8]| {
+            fs::write(&path, contents).unwrap();
+            Response::new(200)
+                .with_header("Content-Type", "application/json")
+                .with_file(File::open(&path).unwrap(), contents.len() as u64)
+        };
+        let request = Request::new("GET", "/").with_header("Accept-Encoding", "gzip");
+        let response = compress(&request, file(&fixture()));
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        assert_eq!(response.body(), gzip(&fixture()));
+        let ranged = request.clone().with_header("Range", "bytes=0-9");
+        let response = compress(&ranged, file(&fixture()));
+        assert_eq!(response.header("content-encoding"), None);
+
+        let large = vec![b'{'; MAX_FILE_SIZE as usize + 1];
+        let response = compress(&request, file(&large));
+        assert_eq!(response.header("content-encoding"), None);
+        assert_eq!(response.body_len(), large.len() as u64);
+        fs::remove_file(&path).unwrap();
+    }
+}
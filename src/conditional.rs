@@ -0,0 +1,254 @@
+//! Validators and conditional requests: strong `ETag`s for fixed bodies, HTTP
+//! dates, `304 Not Modified` for `If-None-Match` and `If-Modified-Since`, and
+//! the finishing steps every response goes through before it is sent.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::compression::compress;
+use crate::handler::{Handler, Middleware, Request, Response};
+use crate::ranges::partial_content;
+
+/// Headers a `304` keeps from the response it replaces.
+const KEPT_HEADERS: &[&str] = &[
+    "cache-control",
+    "content-location",
+    "etag",
+    "expires",
+    "last-modified",
+    "vary",
+];
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A strong entity tag for `body`: its 64-bit FNV-1a hash, quoted.
+pub(crate) fn etag(body: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in body {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("\"{:016x}\"", hash)
+}
+
+/// Applies [`finish_response`] to the responses of everything inside it.
+/// It sits right inside the access log, capture and recorder, so that they
+/// see the response that is actually sent.
+pub(crate) struct FinishResponse {
+    compression: bool,
+}
+
+impl FinishResponse {
+    pub(crate) fn new(compression: bool) -> Self {
+        FinishResponse { compression }
+    }
+}
+
+impl Middleware for FinishResponse {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        finish_response(request, next.handle(request), self.compression)
+    }
+}
+
+/// Turns a handler's response into the representation the client asked for:
+/// compressed when `compression` is on, then `304 Not Modified` or the
+/// requested byte ranges.
+pub(crate) fn finish_response(
+    request: &Request,
+    response: Response,
+    compression: bool,
+) -> Response {
+    let response = if compression {
+        compress(request, response)
+    } else {
+        response
+    };
+    partial_content(request, not_modified(request, response))
+}
+
+/// Answers a `GET` or `HEAD` with `304 Not Modified` instead of `response`
+/// when the client's copy is still current. `If-None-Match` takes precedence
+/// over `If-Modified-Since`, which only applies without it.
+pub(crate) fn not_modified(request: &Request, response: Response) -> Response {
+    if !matches!(request.method(), "GET" | "HEAD")
+        || response.status() != 200
+        || response.fault().is_some()
+    {
+        return response;
+    }
+    let current = match request.header("if-none-match") {
+        Some(tags) => response
+            .header("etag")
+            .is_some_and(|etag| matches_etag(tags, etag)),
+        None => {
+            let since = request
+                .header("if-modified-since")
+                .and_then(parse_http_date);
+            let modified = response.header("last-modified").and_then(parse_http_date);
+            matches!((since, modified), (Some(since), Some(modified)) if modified <= since)
+        }
+    };
+    if !current {
+        return response;
+    }
+    let mut not_modified = Response::new(304);
+    for (name, value) in response.headers() {
+        if KEPT_HEADERS
+            .iter()
+            .any(|kept| name.eq_ignore_ascii_case(kept))
+        {
+            not_modified = not_modified.with_header(name.to_string(), value.to_string());
+        }
+    }
+    not_modified
+}
+
+/// Whether an `If-None-Match` list names `etag`, comparing weakly as GET
+/// requires.
+fn matches_etag(tags: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| {
+        let tag = tag.trim();
+        tag.strip_prefix("W/").unwrap_or(tag).to_string()
+    };
+    tags.trim() == "*" || tags.split(',').any(|tag| opaque(tag) == opaque(etag))
+}
+
+/// `time` as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub(crate) fn http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let days = secs / 86_400;
+    let secs_of_day = secs % 86_400;
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Parses an HTTP date in the preferred IMF-fixdate format; the obsolete
+/// formats are not accepted.
+pub(crate) fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let (_, rest) = date.trim().split_once(", ")?;
+    let mut fields = rest.split(' ');
+    let day: u64 = fields.next()?.parse().ok()?;
+    let month = fields.next()?;
+    let month = MONTHS.iter().position(|known| *known == month)? as u64 + 1;
+    let year: u64 = fields.next()?.parse().ok()?;
+    let mut time = fields
+        .next()?
+        .split(':')
+        .map(|field| field.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if fields.next() != Some("GMT") || fields.next().is_some() || time.next().is_some() {
+        return None;
+    }
+    if year < 1970 || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    // Days since the epoch from a civil date, the inverse of `http_date`.
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{etag, http_date, not_modified, parse_http_date};
+    use crate::handler::{Request, Response};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn page() -> Response {
+        Response::text("OK")
+            .with_header("ETag", etag(b"OK"))
+            .with_header("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .with_header("Cache-Control", "max-age=60")
+    }
+
+    #[test]
+    fn answers_304_for_current_copies() {
+        let tag = etag(b"OK");
+        let request = Request::new("GET", "/").with_header("If-None-Match", tag.clone());
+        let response = not_modified(&request, page());
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.header("etag"), Some(tag.as_str()));
+        assert_eq!(response.header("cache-control"), Some("max-age=60"));
+        assert_eq!(response.header("content-type"), None);
+        assert!(response.body().is_empty());
+
+        let weak = format!("\"other\", W/{}", tag);
+        let request = Request::new("HEAD", "/").with_header("If-None-Match", weak);
+        assert_eq!(not_modified(&request, page()).status(), 304);
+        let request = Request::new("GET", "/").with_header("If-None-Match", "*");
+        assert_eq!(not_modified(&request, page()).status(), 304);
+        let since = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let request = Request::new("GET", "/").with_header("If-Modified-Since", since);
+        assert_eq!(not_modified(&request, page()).status(), 304);
+    }
+
+    #[test]
+    fn sends_changed_or_unconditional_responses() {
+        let stale = Request::new("GET", "/").with_header("If-None-Match", "\"stale\"");
+        assert_eq!(not_modified(&stale, page()).status(), 200);
+        let earlier = Request::new("GET", "/")
+            .with_header("If-Modified-Since", "Sat, 05 Nov 1994 08:49:37 GMT");
+        assert_eq!(not_modified(&earlier, page()).status(), 200);
+        // If-None-Match wins over a matching If-Modified-Since.
+        let both = stale
+            .clone()
+            .with_header("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(not_modified(&both, page()).status(), 200);
+        let post = Request::new("POST", "/").with_header("If-None-Match", "*");
+        assert_eq!(not_modified(&post, page()).status(), 200);
+        let any = Request::new("GET", "/").with_header("If-None-Match", "*");
+        assert_eq!(not_modified(&any, Response::new(404)).status(), 404);
+    }
+
+    #[test]
+    fn formats_and_parses_http_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(http_date(leap_day), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(parse_http_date(&http_date(leap_day)), Some(leap_day));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 PST"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+    }
+
+    #[test]
+    fn hashes_bodies_into_strong_etags() {
+        assert_eq!(etag(b""), "\"cbf29ce484222325\"");
+        assert_ne!(etag(b"OK"), etag(b"KO"));
+    }
+}
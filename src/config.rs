@@ -0,0 +1,1040 @@
+//! The binary's configuration: every setting is read from a command-line flag,
+//! the environment or the `--config` file (in that order of precedence) and
+//! validated up front, recording where each value came from.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use ok::{
+    AccessLogFilter, Echo, Faults, Favicon, FileSink, Latency, LogLevel, Logger, RouteTable,
+    Sequence, SequenceTable, Server, ServerBuilder, StaticResponse, StderrSink, StdoutSink,
+};
+
+/// Where a setting's value came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Source {
+    Default,
+    File,
+    Env,
+    Flag,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::Default => "default",
+            Source::File => "file",
+            Source::Env => "env",
+            Source::Flag => "flag",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SinkKind {
+    Stdout,
+    Stderr,
+    File,
+    Syslog,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum LogSinkConfig {
+    Stdout,
+    Stderr,
+    File {
+        path: PathBuf,
+        max_bytes: Option<u64>,
+        max_age: Option<Duration>,
+        keep: usize,
+    },
+    Syslog(PathBuf),
+}
+
+/// The validated configuration of the binary.
+#[derive(Debug)]
+pub(crate) struct Config {
+    pub(crate) port: u16,
+    pub(crate) bind: IpAddr,
+    pub(crate) threads: usize,
+    /// From `SHOW_FAVICON`, `FAVICON_PATH` and `FAVICON_NO_CONTENT`, with the
+    /// icon file already read.
+    pub(crate) favicon: Favicon,
+    pub(crate) require_host: bool,
+    pub(crate) compression: bool,
+    pub(crate) diagnostics: bool,
+    /// Answer every request with the request itself instead of the routes.
+    pub(crate) echo: Option<Echo>,
+    pub(crate) capture_requests: usize,
+    pub(crate) record_path: Option<PathBuf>,
+    /// Directory served under `static_prefix`.
+    pub(crate) static_dir: Option<PathBuf>,
+    pub(crate) static_prefix: String,
+    pub(crate) startup_delay: Duration,
+    /// How long `/readyz` answers 503 before the listener closes on shutdown.
+    pub(crate) drain_period: Duration,
+    pub(crate) health_check_path: String,
+    pub(crate) admin: Option<SocketAddr>,
+    pub(crate) tls: Option<(PathBuf, PathBuf)>,
+    pub(crate) log_sink: LogSinkConfig,
+    pub(crate) log_level: LogLevel,
+    pub(crate) access_log_sample: u64,
+    pub(crate) access_log_errors_only: bool,
+    pub(crate) access_log_paths: Vec<String>,
+    pub(crate) faults: Faults,
+    /// Static responses from `ROUTE <path>` keys in the config file.
+    pub(crate) routes: HashMap<String, StaticResponse>,
+    /// Response sequences from `SEQUENCE <path>` keys in the config file.
+    pub(crate) sequences: HashMap<String, Sequence>,
+    /// `Cache-Control` values from `CACHE_CONTROL <path>` keys in the config
+    /// file.
+    pub(crate) cache_control: HashMap<String, String>,
+    /// Every setting with its raw value and source, in load order.
+    entries: Vec<(String, String, Source)>,
+}
+
+/// Parses a config file: one `KEY=VALUE` per line, blank lines and lines
+/// starting with `#` ignored.
+pub(crate) fn parse_config(text: &str) -> io::Result<HashMap<String, String>> {
+    let mut values = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "config line {}: expected KEY=VALUE, got {:?}",
+                    number + 1,
+                    line
+                ),
+            ));
+        };
+        values.insert(key.trim().to_string(), value.trim().to_string());
+    }
+    Ok(values)
+}
+
+fn number<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("expected a non-negative number, got {:?}", value))
+}
+
+fn boolean(value: &str) -> Result<bool, String> {
+    if value.eq_ignore_ascii_case("true") {
+        Ok(true)
+    } else if value.eq_ignore_ascii_case("false") {
+        Ok(false)
+    } else {
+        Err(format!("expected true or false, got {:?}", value))
+    }
+}
+
+fn ip_addr(value: &str) -> Result<IpAddr, String> {
+    value
+        .parse()
+        .map_err(|_| format!("expected an IP address, got {:?}", value))
+}
+
+fn optional_path(value: &str) -> Result<Option<PathBuf>, String> {
+    Ok((!value.is_empty()).then(|| PathBuf::from(value)))
+}
+
+fn url_path(value: &str) -> Result<String, String> {
+    if value.starts_with('/') && !value.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Ok(value.to_string())
+    } else {
+        Err(format!(
+            "expected a path starting with '/', got {:?}",
+            value
+        ))
+    }
+}
+
+fn optional_number<T: FromStr>(value: &str) -> Result<Option<T>, String> {
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        number(value).map(Some)
+    }
+}
+
+fn probability(value: &str) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|p| (0.0..=1.0).contains(p))
+        .ok_or_else(|| format!("expected a probability between 0 and 1, got {:?}", value))
+}
+
+/// Parses a latency in milliseconds: `fixed:MS`, `uniform:MIN:MAX` or
+/// `normal:MEAN:STDDEV`, or empty for none.
+fn latency(value: &str) -> Result<Option<Latency>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let invalid = || {
+        format!(
+            "expected fixed:MS, uniform:MIN:MAX or normal:MEAN:STDDEV, got {:?}",
+            value
+        )
+    };
+    let mut parts = value.split(':');
+    let kind = parts.next().unwrap_or("");
+    let millis = parts
+        .map(|ms| ms.parse().map(Duration::from_millis))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+    match (kind, millis.as_slice()) {
+        ("fixed", [delay]) => Ok(Some(Latency::Fixed(*delay))),
+        ("uniform", [min, max]) if min <= max => Ok(Some(Latency::Uniform {
+            min: *min,
+            max: *max,
+        })),
+        ("normal", [mean, std_dev]) => Ok(Some(Latency::Normal {
+            mean: *mean,
+            std_dev: *std_dev,
+        })),
+        _ => Err(invalid()),
+    }
+}
+
+fn error_statuses(value: &str) -> Result<Vec<u16>, String> {
+    value
+        .split(',')
+        .map(|status| {
+            status
+                .trim()
+                .parse()
+                .ok()
+                .filter(|status| (400..=599).contains(status))
+                .ok_or_else(|| format!("expected statuses from 400 to 599, got {:?}", value))
+        })
+        .collect()
+}
+
+/// Parses a route value: `STATUS CONTENT_TYPE [BODY]`, where the body is the
+/// rest of the line.
+fn route(value: &str) -> Result<StaticResponse, String> {
+    let mut parts = value.splitn(3, ' ');
+    let status = parts.next().unwrap_or("");
+    let status = status
+        .parse()
+        .map_err(|_| format!("expected STATUS CONTENT_TYPE [BODY], got {:?}", value))?;
+    let content_type = parts
+        .next()
+        .ok_or_else(|| format!("expected STATUS CONTENT_TYPE [BODY], got {:?}", value))?;
+    let body = parts.next().unwrap_or("");
+    Ok(StaticResponse::new(status, content_type, body))
+}
+
+/// Parses a sequence value: routes separated by `|`, each optionally
+/// prefixed with a repeat count, as in `3x 503 text/plain down | 200 text/plain`.
+fn sequence(value: &str) -> Result<Vec<StaticResponse>, String> {
+    let mut responses = Vec::new();
+    for entry in value.split('|').map(str::trim) {
+        let (count, entry) = match entry.split_once(' ') {
+            Some((count, rest)) if count.ends_with('x') => {
+                let count = count[..count.len() - 1]
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| format!("expected a repeat count like 3x, got {:?}", count))?;
+                (count, rest)
+            }
+            _ => (1, entry),
+        };
+        let response = route(entry)?;
+        responses.resize(responses.len() + count, response);
+    }
+    Ok(responses)
+}
+
+fn echo_mode(value: &str) -> Result<Option<Echo>, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "off" => Ok(None),
+        "text" => Ok(Some(Echo::Text)),
+        "json" => Ok(Some(Echo::Json)),
+        _ => Err(format!("expected off, text or json, got {:?}", value)),
+    }
+}
+
+fn sink_kind(value: &str) -> Result<SinkKind, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "stdout" => Ok(SinkKind::Stdout),
+        "stderr" => Ok(SinkKind::Stderr),
+        "file" => Ok(SinkKind::File),
+        "syslog" if cfg!(unix) => Ok(SinkKind::Syslog),
+        "syslog" => Err("syslog is only supported on Unix".into()),
+        _ => Err(format!(
+            "expected stdout, stderr, file or syslog, got {:?}",
+            value
+        )),
+    }
+}
+
+/// Reads settings from the sources in precedence order, collecting every
+/// error instead of stopping at the first.
+struct Loader<'a> {
+    flags: &'a [(&'static str, String)],
+    file: &'a HashMap<String, String>,
+    env: &'a dyn Fn(&str) -> Option<String>,
+    entries: Vec<(String, String, Source)>,
+    errors: Vec<String>,
+}
+
+impl Loader<'_> {
+    fn raw(&self, name: &str) -> Option<(String, Source)> {
+        self.flags
+            .iter()
+            .rev()
+            .find(|(flag, _)| *flag == name)
+            .map(|(_, value)| (value.clone(), Source::Flag))
+            .or_else(|| (self.env)(name).map(|value| (value, Source::Env)))
+            .or_else(|| {
+                self.file
+                    .get(name)
+                    .map(|value| (value.clone(), Source::File))
+            })
+    }
+
+    /// Reads `name`, falling back to `default`; an invalid value is reported
+    /// and replaced by the default so loading can continue.
+    fn get<T>(
+        &mut self,
+        name: &'static str,
+        default: &str,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> T {
+        let (raw, source) = self
+            .raw(name)
+            .unwrap_or_else(|| (default.to_string(), Source::Default));
+        let value = match parse(&raw) {
+            Ok(value) => value,
+            Err(e) => {
+                self.errors.push(format!("{} ({}): {}", name, source, e));
+                parse(default).expect("defaults are valid")
+            }
+        };
+        self.entries.push((name.to_string(), raw, source));
+        value
+    }
+
+    fn source(&self, name: &str) -> Source {
+        self.entries
+            .iter()
+            .find(|(entry, _, _)| *entry == name)
+            .map_or(Source::Default, |(_, _, source)| *source)
+    }
+}
+
+fn default_pool_size(available_parallelism: Option<usize>) -> usize {
+    available_parallelism.filter(|n| *n > 0).unwrap_or(4)
+}
+
+impl Config {
+    /// Loads the configuration from `flags` (setting name and value pairs),
+    /// the process environment and the optional config file.
+    pub(crate) fn load(flags: &[(&'static str, String)], file: Option<&Path>) -> io::Result<Self> {
+        let values = match file {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+                parse_config(&text)?
+            }
+            None => HashMap::new(),
+        };
+        Config::from_sources(flags, &values, &|name| std::env::var(name).ok())
+    }
+
+    fn from_sources(
+        flags: &[(&'static str, String)],
+        file: &HashMap<String, String>,
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> io::Result<Self> {
+        let mut loader = Loader {
+            flags,
+            file,
+            env,
+            entries: Vec::new(),
+            errors: Vec::new(),
+        };
+        let default_threads =
+            default_pool_size(thread::available_parallelism().map(|n| n.get()).ok()).to_string();
+
+        let port = loader.get("PORT", "8080", number::<u16>);
+        let bind = loader.get("BIND_ADDRESS", "0.0.0.0", ip_addr);
+        let threads = loader.get("THREAD_POOL_SIZE", &default_threads, |value| {
+            number::<usize>(value).map(|n| n.max(1))
+        });
+        let show_favicon = loader.get("SHOW_FAVICON", "true", boolean);
+        let favicon_path = loader.get("FAVICON_PATH", "", optional_path);
+        let favicon_no_content = loader.get("FAVICON_NO_CONTENT", "false", boolean);
+        let require_host = loader.get("REQUIRE_HOST", "false", boolean);
+        let compression = loader.get("COMPRESSION", "true", boolean);
+        let diagnostics = loader.get("DIAGNOSTICS", "false", boolean);
+        let echo = loader.get("ECHO", "off", echo_mode);
+        let capture_requests = loader.get("CAPTURE_REQUESTS", "0", number::<usize>);
+        let record_path = loader.get("RECORD_PATH", "", optional_path);
+        let static_dir = loader.get("STATIC_DIR", "", optional_path);
+        let static_prefix = loader.get("STATIC_PREFIX", "/", url_path);
+        let startup_delay = loader.get("READY_DELAY_SECS", "0", |value| {
+            number(value).map(Duration::from_secs)
+        });
+        let drain_period = loader.get("DRAIN_SECS", "0", |value| {
+            number(value).map(Duration::from_secs)
+        });
+        let health_check_path = loader.get("HEALTH_CHECK_PATH", "/readyz", url_path);
+        let admin_port = loader.get("ADMIN_PORT", "", optional_number::<u16>);
+        let admin_bind = loader.get("ADMIN_BIND", "127.0.0.1", ip_addr);
+        let tls_cert = loader.get("TLS_CERT_PATH", "", optional_path);
+        let tls_key = loader.get("TLS_KEY_PATH", "", optional_path);
+        let sink = loader.get("LOG_SINK", "stdout", sink_kind);
+        let log_file = loader.get("LOG_FILE_PATH", "", optional_path);
+        let max_bytes = loader.get("LOG_FILE_MAX_BYTES", "", optional_number::<u64>);
+        let max_age = loader.get("LOG_FILE_MAX_AGE_SECS", "", optional_number::<u64>);
+        let keep = loader.get("LOG_FILE_KEEP", "5", number::<usize>);
+        let syslog_socket = loader.get("SYSLOG_SOCKET", "/dev/log", |v| Ok(PathBuf::from(v)));
+        let log_level = loader.get("LOG_LEVEL", "info", |value| {
+            value.parse::<LogLevel>().map_err(|e| e.to_string())
+        });
+        let access_log_sample = loader.get("ACCESS_LOG_SAMPLE", "1", number::<u64>);
+        let access_log_errors_only = loader.get("ACCESS_LOG_ERRORS_ONLY", "false", boolean);
+        let access_log_paths = loader.get("ACCESS_LOG_PATHS", "", |value| {
+            Ok(value
+                .split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>())
+        });
+        let fault_latency = loader.get("FAULT_LATENCY", "", latency);
+        let fault_error_rate = loader.get("FAULT_ERROR_RATE", "0", probability);
+        let fault_error_statuses = loader.get("FAULT_ERROR_STATUSES", "500", error_statuses);
+        let fault_reset_rate = loader.get("FAULT_RESET_RATE", "0", probability);
+        let fault_truncate_rate = loader.get("FAULT_TRUNCATE_RATE", "0", probability);
+        let fault_seed = loader.get("FAULT_SEED", "", optional_number::<u64>);
+        let sequence_per_client = loader.get("SEQUENCE_PER_CLIENT", "false", boolean);
+        let sequence_wrap = loader.get("SEQUENCE_WRAP", "false", boolean);
+
+        let tls = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => {
+                if !cfg!(feature = "tls") {
+                    loader.errors.push(
+                        "TLS_CERT_PATH/TLS_KEY_PATH are set but this build lacks the `tls` \
+                         feature"
+                            .into(),
+                    );
+                }
+                for (name, path) in [("TLS_CERT_PATH", &cert), ("TLS_KEY_PATH", &key)] {
+                    if !path.is_file() {
+                        let source = loader.source(name);
+                        loader.errors.push(format!(
+                            "{} ({}): {} is not a readable file",
+                            name,
+                            source,
+                            path.display()
+                        ));
+                    }
+                }
+                Some((cert, key))
+            }
+            (None, None) => None,
+            _ => {
+                loader
+                    .errors
+                    .push("TLS_CERT_PATH and TLS_KEY_PATH must be set together".into());
+                None
+            }
+        };
+        if let Some(dir) = &static_dir {
+            if !dir.is_dir() {
+                let source = loader.source("STATIC_DIR");
+                loader.errors.push(format!(
+                    "STATIC_DIR ({}): {} is not a directory",
+                    source,
+                    dir.display()
+                ));
+            }
+        }
+        let favicon = match (favicon_path, favicon_no_content) {
+            (Some(_), true) => {
+                loader
+                    .errors
+                    .push("FAVICON_PATH and FAVICON_NO_CONTENT cannot both be set".into());
+                Favicon::NoContent
+            }
+            (Some(_), false) if !show_favicon => {
+                loader
+                    .errors
+                    .push("FAVICON_PATH is set but SHOW_FAVICON is false".into());
+                Favicon::NotFound
+            }
+            (Some(path), false) => Favicon::load(&path).unwrap_or_else(|e| {
+                let source = loader.source("FAVICON_PATH");
+                loader
+                    .errors
+                    .push(format!("FAVICON_PATH ({}): {}", source, e));
+                Favicon::Builtin
+            }),
+            (None, true) => Favicon::NoContent,
+            (None, false) if show_favicon => Favicon::Builtin,
+            (None, false) => Favicon::NotFound,
+        };
+        let log_sink = match sink {
+            SinkKind::Stdout => LogSinkConfig::Stdout,
+            SinkKind::Stderr => LogSinkConfig::Stderr,
+            SinkKind::Syslog => LogSinkConfig::Syslog(syslog_socket),
+            SinkKind::File => match log_file {
+                Some(path) => LogSinkConfig::File {
+                    path,
+                    max_bytes,
+                    max_age: max_age.map(Duration::from_secs),
+                    keep,
+                },
+                None => {
+                    loader
+                        .errors
+                        .push("LOG_SINK=file requires LOG_FILE_PATH".into());
+                    LogSinkConfig::Stdout
+                }
+            },
+        };
+        let mut faults = Faults::default()
+            .errors(fault_error_rate, fault_error_statuses)
+            .resets(fault_reset_rate)
+            .truncations(fault_truncate_rate);
+        if let Some(latency) = fault_latency {
+            faults = faults.latency(latency);
+        }
+        if let Some(seed) = fault_seed {
+            faults = faults.seed(seed);
+        }
+        let mut keys: Vec<&String> = file.keys().collect();
+        keys.sort();
+        let mut routes = HashMap::new();
+        let mut sequences = HashMap::new();
+        let mut cache_control = HashMap::new();
+        for key in keys {
+            if let Some(path) = key.strip_prefix("ROUTE ") {
+                let value = &file[key];
+                match route(value) {
+                    Ok(response) => {
+                        routes.insert(path.trim().to_string(), response);
+                    }
+                    Err(e) => loader.errors.push(format!("{} (file): {}", key, e)),
+                }
+                loader
+                    .entries
+                    .push((key.clone(), value.clone(), Source::File));
+            } else if let Some(path) = key.strip_prefix("SEQUENCE ") {
+                let value = &file[key];
+                let path = path.trim();
+                let validated = sequence(value).and_then(|responses| {
+                    for response in &responses {
+                        let route = HashMap::from([(path.to_string(), response.clone())]);
+                        RouteTable::new(route).map_err(|e| e.to_string())?;
+                    }
+                    Ok(responses)
+                });
+                match validated {
+                    Ok(responses) => {
+                        let sequence = Sequence::new(responses)
+                            .per_client(sequence_per_client)
+                            .wrap(sequence_wrap);
+                        sequences.insert(path.to_string(), sequence);
+                    }
+                    Err(e) => loader.errors.push(format!("{} (file): {}", key, e)),
+                }
+                loader
+                    .entries
+                    .push((key.clone(), value.clone(), Source::File));
+            } else if let Some(path) = key.strip_prefix("CACHE_CONTROL ") {
+                let value = &file[key];
+                let path = path.trim();
+                if !path.starts_with('/') {
+                    loader
+                        .errors
+                        .push(format!("{} (file): path must start with '/'", key));
+                } else if value.is_empty() || value.chars().any(|c| c.is_control()) {
+                    loader
+                        .errors
+                        .push(format!("{} (file): expected a Cache-Control value", key));
+                } else {
+                    cache_control.insert(path.to_string(), value.clone());
+                }
+                loader
+                    .entries
+                    .push((key.clone(), value.clone(), Source::File));
+            } else if !loader.entries.iter().any(|(name, _, _)| name == key) {
+                loader
+                    .errors
+                    .push(format!("unknown setting {:?} in config file", key));
+            }
+        }
+        if let Err(e) = RouteTable::new(routes.clone()) {
+            loader.errors.push(e.to_string());
+        }
+
+        if !loader.errors.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid configuration:\n  {}", loader.errors.join("\n  ")),
+            ));
+        }
+        Ok(Config {
+            port,
+            bind,
+            threads,
+            favicon,
+            require_host,
+            compression,
+            diagnostics,
+            echo,
+            capture_requests,
+            record_path,
+            static_dir,
+            static_prefix,
+            startup_delay,
+            drain_period,
+            health_check_path,
+            admin: admin_port.map(|port| SocketAddr::new(admin_bind, port)),
+            tls,
+            log_sink,
+            log_level,
+            access_log_sample,
+            access_log_errors_only,
+            access_log_paths,
+            faults,
+            routes,
+            sequences,
+            cache_control,
+            entries: loader.entries,
+        })
+    }
+
+    /// A server builder with every setting applied, opening the log sink.
+    pub(crate) fn server_builder(&self) -> io::Result<ServerBuilder> {
+        let mut filter = AccessLogFilter::default()
+            .sample(self.access_log_sample)
+            .errors_only(self.access_log_errors_only);
+        for pattern in &self.access_log_paths {
+            filter = filter.path_pattern(pattern.clone());
+        }
+        let mut builder = Server::builder()
+            .bind((self.bind, self.port).into())
+            .pool_size(self.threads)
+            .favicon(self.favicon.clone())
+            .require_host(self.require_host)
+            .compression(self.compression)
+            .diagnostics(self.diagnostics)
+            .capture_requests(self.capture_requests)
+            .log_level(self.log_level)
+            .access_log(filter)
+            .startup_delay(self.startup_delay)
+            .drain_period(self.drain_period)
+            .faults(self.faults.clone());
+        if let Some(path) = &self.record_path {
+            builder = builder.record(FileSink::open(path)?);
+        }
+        if let Some(dir) = &self.static_dir {
+            builder = builder.static_dir(self.static_prefix.clone(), dir);
+        }
+        if let Some(echo) = self.echo {
+            builder = builder.handler(echo);
+        }
+        if let Some(admin) = self.admin {
+            builder = builder.admin(admin);
+        }
+        for (path, response) in &self.routes {
+            builder = builder.route(path.clone(), response.clone());
+        }
+        for (path, sequence) in &self.sequences {
+            builder = builder.sequence(path.clone(), sequence.clone());
+        }
+        for (path, value) in &self.cache_control {
+            builder = builder.cache_control(path.clone(), value.clone());
+        }
+        #[cfg(feature = "tls")]
+        let builder = match &self.tls {
+            Some((cert, key)) => builder.tls(cert, key),
+            None => builder,
+        };
+        Ok(match &self.log_sink {
+            LogSinkConfig::Stdout => builder.log_sink(StdoutSink),
+            LogSinkConfig::Stderr => builder.log_sink(StderrSink),
+            LogSinkConfig::File {
+                path,
+                max_bytes,
+                max_age,
+                keep,
+            } => {
+                let mut sink = FileSink::open(path)?.keep(*keep);
+                if let Some(max_bytes) = max_bytes {
+                    sink = sink.max_bytes(*max_bytes);
+                }
+                if let Some(max_age) = max_age {
+                    sink = sink.max_age(*max_age);
+                }
+                builder.log_sink(sink)
+            }
+            #[cfg(unix)]
+            LogSinkConfig::Syslog(socket) => builder.log_sink(ok::SyslogSink::connect(socket)?),
+            #[cfg(not(unix))]
+            LogSinkConfig::Syslog(_) => unreachable!("rejected while loading"),
+        })
+    }
+}
+
+/// How often [`watch_routes`] checks the config file for changes.
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reloads the routes, their `Cache-Control` values and the sequences
+/// whenever the config file's modification time changes. The whole file is
+/// validated again; an invalid file is reported and the current routes and
+/// sequences stay in place. The routes and the sequences live in separate
+/// tables and are swapped one after the other, so a request arriving in
+/// between can see the new routes with the old sequences; the file is fully
+/// validated first, so the second swap cannot fail. Other settings only
+/// apply after a restart. The outcome of each reload is logged through
+/// `logger`.
+pub(crate) fn watch_routes(
+    path: PathBuf,
+    flags: Vec<(&'static str, String)>,
+    table: RouteTable,
+    sequences: SequenceTable,
+    logger: Logger,
+) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(&path);
+    loop {
+        thread::sleep(RELOAD_POLL_INTERVAL);
+        let current = modified(&path);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        let reload = Config::load(&flags, Some(&path)).and_then(|config| {
+            table.replace(config.routes, config.cache_control)?;
+            sequences.replace(config.sequences)
+        });
+        match reload {
+            Ok(()) => logger.log(
+                LogLevel::Info,
+                format!("Reloaded routes from {}", path.display()),
+            ),
+            Err(e) => logger.log(
+                LogLevel::Error,
+                format!("Keeping previous routes, reload failed: {}", e),
+            ),
+        }
+    }
+}
+
+/// One `SOURCE  KEY=VALUE` line per setting, as printed by `--print-config`.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value, source) in &self.entries {
+            writeln!(f, "{:<8} {}={}", source.to_string(), name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_pool_size, parse_config, Config, LogSinkConfig, Source};
+    use ok::{Echo, Faults, Favicon, Latency, Sequence, StaticResponse};
+    use std::collections::HashMap;
+    use std::io;
+    use std::time::Duration;
+
+    fn load(
+        flags: &[(&'static str, &str)],
+        file: &[(&str, &str)],
+        env: &[(&str, &str)],
+    ) -> io::Result<Config> {
+        let flags: Vec<_> = flags.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        let file: HashMap<_, _> = file
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let env: HashMap<_, _> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::from_sources(&flags, &file, &|name| env.get(name).cloned())
+    }
+
+    fn source_of(config: &Config, name: &str) -> Source {
+        config
+            .entries
+            .iter()
+            .find(|(entry, _, _)| *entry == name)
+            .unwrap()
+            .2
+    }
+
+    #[test]
+    fn pool_size_falls_back_to_available_parallelism() {
+        assert_eq!(default_pool_size(Some(6)), 6);
+    }
+
+    #[test]
+    fn pool_size_uses_default_when_unavailable() {
+        assert_eq!(default_pool_size(None), 4);
+        assert_eq!(default_pool_size(Some(0)), 4);
+    }
+
+    #[test]
+    fn pool_size_clamps_zero_to_one() {
+        let config = load(&[], &[], &[("THREAD_POOL_SIZE", "0")]).unwrap();
+        assert_eq!(config.threads, 1);
+    }
+
+    #[test]
+    fn flags_override_env_which_overrides_file() {
+        let config = load(
+            &[("PORT", "1")],
+            &[
+                ("PORT", "3"),
+                ("THREAD_POOL_SIZE", "3"),
+                ("LOG_LEVEL", "warn"),
+            ],
+            &[("PORT", "2"), ("THREAD_POOL_SIZE", "2")],
+        )
+        .unwrap();
+        assert_eq!((config.port, config.threads), (1, 2));
+        assert_eq!(source_of(&config, "PORT"), Source::Flag);
+        assert_eq!(source_of(&config, "THREAD_POOL_SIZE"), Source::Env);
+        assert_eq!(source_of(&config, "LOG_LEVEL"), Source::File);
+        assert_eq!(source_of(&config, "SHOW_FAVICON"), Source::Default);
+        assert_eq!(config.log_sink, LogSinkConfig::Stdout);
+        assert!(!config.require_host);
+    }
+
+    #[test]
+    fn reports_every_invalid_value() {
+        let err = load(
+            &[],
+            &[("SHOWFAVICON", "false")],
+            &[("THREAD_POOL_SIZE", "abc"), ("SHOW_FAVICON", "flase")],
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("THREAD_POOL_SIZE (env): expected"), "{}", err);
+        assert!(
+            err.contains("SHOW_FAVICON (env): expected true or false"),
+            "{}",
+            err
+        );
+        assert!(err.contains("unknown setting \"SHOWFAVICON\""), "{}", err);
+    }
+
+    #[test]
+    fn rejects_inconsistent_settings() {
+        assert!(load(&[], &[], &[("TLS_CERT_PATH", "cert.pem")]).is_err());
+        assert!(load(&[], &[], &[("LOG_SINK", "file")]).is_err());
+        let config = load(
+            &[],
+            &[],
+            &[("LOG_SINK", "file"), ("LOG_FILE_PATH", "ok.log")],
+        )
+        .unwrap();
+        assert!(matches!(
+            config.log_sink,
+            LogSinkConfig::File { keep: 5, .. }
+        ));
+    }
+
+    #[test]
+    fn reads_routes_from_the_config_file() {
+        let config = load(
+            &[],
+            &[
+                ("ROUTE /ready", "503 application/json {\"ready\": false}"),
+                ("ROUTE /empty", "204 text/plain"),
+            ],
+            &[],
+        )
+        .unwrap();
+        let ready = &config.routes["/ready"];
+        assert_eq!(ready.status(), 503);
+        assert_eq!(ready.content_type(), "application/json");
+        assert_eq!(ready.body(), b"{\"ready\": false}");
+        assert_eq!(config.routes["/empty"].body(), b"");
+        assert_eq!(source_of(&config, "ROUTE /ready"), Source::File);
+
+        for invalid in ["ok text/plain", "200", "999 text/plain x"] {
+            let err = load(&[], &[("ROUTE /x", invalid)], &[]).unwrap_err();
+            assert!(err.to_string().contains("route") || err.to_string().contains("ROUTE"));
+        }
+        assert!(load(&[], &[("ROUTE x", "200 text/plain x")], &[]).is_err());
+    }
+
+    #[test]
+    fn reads_cache_control_from_the_config_file() {
+        let config = load(&[], &[("CACHE_CONTROL /", "public, max-age=60")], &[]).unwrap();
+        assert_eq!(config.cache_control["/"], "public, max-age=60");
+        assert_eq!(source_of(&config, "CACHE_CONTROL /"), Source::File);
+        assert!(load(&[], &[("CACHE_CONTROL x", "no-cache")], &[]).is_err());
+        assert!(load(&[], &[("CACHE_CONTROL /", "")], &[]).is_err());
+    }
+
+    #[test]
+    fn reads_fault_injection_settings() {
+        let config = load(
+            &[],
+            &[],
+            &[
+                ("FAULT_LATENCY", "uniform:10:20"),
+                ("FAULT_ERROR_RATE", "0.1"),
+                ("FAULT_ERROR_STATUSES", "502, 503"),
+                ("FAULT_RESET_RATE", "0.01"),
+                ("FAULT_SEED", "42"),
+            ],
+        )
+        .unwrap();
+        let expected = Faults::default()
+            .latency(Latency::Uniform {
+                min: Duration::from_millis(10),
+                max: Duration::from_millis(20),
+            })
+            .errors(0.1, [502, 503])
+            .resets(0.01)
+            .seed(42);
+        assert_eq!(config.faults, expected);
+        assert_eq!(
+            load(&[], &[], &[]).unwrap().faults,
+            Faults::default().errors(0.0, [500])
+        );
+
+        for (name, invalid) in [
+            ("FAULT_LATENCY", "uniform:20:10"),
+            ("FAULT_LATENCY", "normal:5"),
+            ("FAULT_LATENCY", "fixed:-1"),
+            ("FAULT_ERROR_RATE", "1.5"),
+            ("FAULT_ERROR_STATUSES", "200"),
+            ("FAULT_TRUNCATE_RATE", "often"),
+        ] {
+            assert!(load(&[], &[], &[(name, invalid)]).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn reads_echo_mode() {
+        assert_eq!(load(&[], &[], &[]).unwrap().echo, None);
+        let config = load(&[], &[], &[("ECHO", "JSON")]).unwrap();
+        assert_eq!(config.echo, Some(Echo::Json));
+        assert!(load(&[], &[], &[("ECHO", "yes")]).is_err());
+    }
+
+    #[test]
+    fn reads_favicon_settings() {
+        assert_eq!(load(&[], &[], &[]).unwrap().favicon, Favicon::Builtin);
+        let hidden = load(&[("SHOW_FAVICON", "false")], &[], &[]).unwrap();
+        assert_eq!(hidden.favicon, Favicon::NotFound);
+        let no_content = load(
+            &[("SHOW_FAVICON", "false")],
+            &[],
+            &[("FAVICON_NO_CONTENT", "true")],
+        );
+        assert_eq!(no_content.unwrap().favicon, Favicon::NoContent);
+
+        let path = std::env::temp_dir().join(format!("ok-config-{}.ico", std::process::id()));
+        std::fs::write(&path, [0, 0, 1, 0]).unwrap();
+        let path_str = path.to_string_lossy().into_owned();
+        let custom = load(&[], &[], &[("FAVICON_PATH", &path_str)]);
+        let conflicting = load(
+            &[],
+            &[],
+            &[("FAVICON_PATH", &path_str), ("FAVICON_NO_CONTENT", "true")],
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            custom.unwrap().favicon,
+            Favicon::Custom {
+                content_type: "image/x-icon".into(),
+                body: vec![0, 0, 1, 0],
+            }
+        );
+        assert!(conflicting.is_err());
+        let err = load(&[], &[], &[("FAVICON_PATH", "/no/such/favicon.png")]).unwrap_err();
+        assert!(err.to_string().contains("FAVICON_PATH (env): "), "{}", err);
+    }
+
+    #[test]
+    fn reads_the_static_directory() {
+        let dir = std::env::temp_dir().to_string_lossy().into_owned();
+        let config = load(
+            &[],
+            &[],
+            &[("STATIC_DIR", &dir), ("STATIC_PREFIX", "/files")],
+        )
+        .unwrap();
+        assert_eq!(config.static_dir, Some(dir.into()));
+        assert_eq!(config.static_prefix, "/files");
+        assert_eq!(load(&[], &[], &[]).unwrap().static_prefix, "/");
+        assert!(load(&[], &[], &[("STATIC_DIR", "/no/such/dir")]).is_err());
+        assert!(load(&[], &[], &[("STATIC_PREFIX", "files")]).is_err());
+    }
+
+    #[test]
+    fn reads_sequences_from_the_config_file() {
+        let config = load(
+            &[],
+            &[(
+                "SEQUENCE /flaky",
+                "2x 503 text/plain down | 200 text/plain OK",
+            )],
+            &[("SEQUENCE_WRAP", "true")],
+        )
+        .unwrap();
+        let down = StaticResponse::new(503, "text/plain", "down");
+        let expected = Sequence::new([
+            down.clone(),
+            down,
+            StaticResponse::new(200, "text/plain", "OK"),
+        ])
+        .wrap(true);
+        assert_eq!(config.sequences["/flaky"], expected);
+
+        for invalid in ["0x 503 text/plain", "503 text/plain |", "99 text/plain"] {
+            assert!(
+                load(&[], &[("SEQUENCE /x", invalid)], &[]).is_err(),
+                "{}",
+                invalid
+            );
+        }
+        assert!(load(&[], &[("SEQUENCE x", "200 text/plain")], &[]).is_err());
+    }
+
+    #[test]
+    fn prints_each_value_with_its_source() {
+        let config = load(&[("PORT", "9000")], &[], &[]).unwrap();
+        let printed = config.to_string();
+        assert!(printed.starts_with("flag     PORT=9000\n"), "{}", printed);
+        assert!(printed.contains("default  TLS_CERT_PATH=\n"), "{}", printed);
+    }
+
+    #[test]
+    fn parses_config_files() {
+        let values = parse_config("# ok\n\nPORT = 9000\nACCESS_LOG_PATHS=/a,/b\n").unwrap();
+        assert_eq!(values.get("PORT").map(String::as_str), Some("9000"));
+        assert_eq!(
+            values.get("ACCESS_LOG_PATHS").map(String::as_str),
+            Some("/a,/b")
+        );
+        assert!(parse_config("PORT 9000").is_err());
+    }
+}
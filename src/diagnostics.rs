@@ -0,0 +1,166 @@
+//! Opt-in httpbin-style endpoints for debugging clients and proxies:
+//! `/status/{code}`, `/delay/{ms}`, `/bytes/{n}`, `/headers`, `/ip` and
+//! `/anything`. Sizes and delays are bounded so they cannot tie up a worker.
+
+use std::thread;
+use std::time::Duration;
+
+use crate::handler::{Handler, Middleware, Request, Response};
+use crate::{json_string, MAX_BODY_SIZE};
+
+/// Longest delay `/delay/{ms}` will wait.
+const MAX_DELAY_MS: u64 = 10_000;
+
+/// Answers the diagnostic paths; other requests go to the handler.
+pub(crate) struct Diagnostics;
+
+impl Middleware for Diagnostics {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let path = request.path();
+        if let Some(code) = path.strip_prefix("/status/") {
+            return match code.parse::<u16>() {
+                Ok(status) if (200..=599).contains(&status) => Response::new(status),
+                _ => bad_request("expected a status from 200 to 599"),
+            };
+        }
+        if let Some(ms) = path.strip_prefix("/delay/") {
+            return match ms.parse::<u64>() {
+                Ok(ms) => {
+                    let ms = ms.min(MAX_DELAY_MS);
+                    thread::sleep(Duration::from_millis(ms));
+                    json(format!("{{\"delay_ms\":{}}}\n", ms))
+                }
+                Err(_) => bad_request("expected a delay in milliseconds"),
+            };
+        }
+        if let Some(n) = path.strip_prefix("/bytes/") {
+            return match n.parse::<usize>() {
+                Ok(n) if n <= MAX_BODY_SIZE => Response::new(200)
+                    .with_header("Content-Type", "application/octet-stream")
+                    .with_header("Accept-Ranges", "bytes")
+                    .with_body((0..n).map(|i| i as u8).collect::<Vec<_>>()),
+                _ => bad_request(&format!("expected at most {} bytes", MAX_BODY_SIZE)),
+            };
+        }
+        match path {
+            "/headers" => json(format!("{{\"headers\":{}}}\n", headers_json(request))),
+            "/ip" => json(format!("{{\"origin\":{}}}\n", json_string(request.peer()))),
+            "/anything" => json(anything_json(request)),
+            _ if path.starts_with("/anything/") => json(anything_json(request)),
+            _ => next.handle(request),
+        }
+    }
+}
+
+fn json(body: String) -> Response {
+    Response::new(200)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_body(body.into_bytes())
+}
+
+fn bad_request(reason: &str) -> Response {
+    Response::new(400)
+        .with_header("Content-Type", "text/plain; charset=utf-8")
+        .with_body(format!("{}\n", reason).into_bytes())
+}
+
+/// The request headers as a JSON object; repeated headers are joined with
+/// `, `.
+fn headers_json(request: &Request) -> String {
+    let mut headers: Vec<(&str, String)> = Vec::new();
+    for (name, value) in request.headers() {
+        match headers
+            .iter_mut()
+            .find(|(seen, _)| seen.eq_ignore_ascii_case(name))
+        {
+            Some((_, joined)) => {
+                joined.push_str(", ");
+                joined.push_str(value);
+            }
+            None => headers.push((name, value.to_string())),
+        }
+    }
+    let fields: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}", json_string(name), json_string(value)))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn anything_json(request: &Request) -> String {
+    format!(
+        "{{\"method\":{},\"path\":{},\"query\":{},\"headers\":{},\"origin\":{},\"body\":{}}}\n",
+        json_string(request.method()),
+        json_string(request.path()),
+        request.query().map_or("null".into(), json_string),
+        headers_json(request),
+        json_string(request.peer()),
+        json_string(&String::from_utf8_lossy(request.body())),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Diagnostics;
+    use crate::handler::{Chain, Handler, Request, Response};
+    use std::time::{Duration, Instant};
+
+    fn chain() -> Chain {
+        Chain::new(
+            vec![Box::new(Diagnostics)],
+            Box::new(|_: &Request| Response::new(404)),
+        )
+    }
+
+    fn body(response: &Response) -> String {
+        String::from_utf8_lossy(response.body()).into_owned()
+    }
+
+    #[test]
+    fn answers_with_requested_status_and_size() {
+        let chain = chain();
+        let status = |target: &str| chain.handle(&Request::new("GET", target)).status();
+        assert_eq!(status("/status/418"), 418);
+        assert_eq!(status("/status/99"), 400);
+        assert_eq!(status("/status/x"), 400);
+        assert_eq!(status("/bytes/2000000"), 400);
+        assert_eq!(status("/other"), 404);
+
+        let bytes = chain.handle(&Request::new("GET", "/bytes/300"));
+        assert_eq!(bytes.body().len(), 300);
+        assert_eq!(
+            bytes.header("content-type"),
+            Some("application/octet-stream")
+        );
+    }
+
+    #[test]
+    fn delays_responses() {
+        let started = Instant::now();
+        let response = chain().handle(&Request::new("GET", "/delay/50"));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(body(&response), "{\"delay_ms\":50}\n");
+    }
+
+    #[test]
+    fn echoes_requests_as_json() {
+        let chain = chain();
+        let request = Request::new("POST", "/anything/x?a=1")
+            .with_header("X-Test", "a\"b")
+            .with_header("x-test", "c")
+            .with_body("line\n");
+        assert_eq!(
+            body(&chain.handle(&request)),
+            concat!(
+                "{\"method\":\"POST\",\"path\":\"/anything/x\",\"query\":\"a=1\",",
+                "\"headers\":{\"X-Test\":\"a\\\"b, c\"},\"origin\":\"unknown\",",
+                "\"body\":\"line\\n\"}\n"
+            )
+        );
+        let headers = chain.handle(&Request::new("GET", "/headers").with_header("Host", "x"));
+        assert_eq!(body(&headers), "{\"headers\":{\"Host\":\"x\"}}\n");
+        let ip = chain.handle(&Request::new("GET", "/ip"));
+        assert_eq!(body(&ip), "{\"origin\":\"unknown\"}\n");
+    }
+}
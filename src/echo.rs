@@ -0,0 +1,104 @@
+//! A handler that reflects each request back to the client, for checking what
+//! a gateway or proxy actually forwards.
+
+use crate::handler::{Handler, Request, Response};
+use crate::{json_body, json_pairs, json_string};
+
+/// Answers every request, whatever its method, with the request itself: its
+/// request line, headers in the order received, and body.
+///
+/// ```no_run
+/// use ok::{Echo, Server};
+///
+/// let server = Server::builder().handler(Echo::Json).start().unwrap();
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Echo {
+    /// The request as received on the wire for HTTP/1, in `text/plain`.
+    Text,
+    /// A JSON object with `request_line`, `method`, `path`, `query`,
+    /// `version`, `headers` (as `[name, value]` pairs) and `body`, or
+    /// `body_base64` when the body is not UTF-8.
+    Json,
+}
+
+impl Handler for Echo {
+    fn handle(&self, request: &Request) -> Response {
+        match self {
+            Echo::Text => Response::new(200)
+                .with_header("Content-Type", "text/plain; charset=utf-8")
+                .with_body(echo_text(request)),
+            Echo::Json => Response::new(200)
+                .with_header("Content-Type", "application/json")
+                .with_body(echo_json(request).into_bytes()),
+        }
+        .with_header("Cache-Control", "no-store")
+    }
+}
+
+fn echo_text(request: &Request) -> Vec<u8> {
+    let mut text = format!("{}\r\n", request.request_line);
+    for (name, value) in request.headers() {
+        text.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    text.push_str("\r\n");
+    let mut text = text.into_bytes();
+    text.extend_from_slice(request.body());
+    text
+}
+
+fn echo_json(request: &Request) -> String {
+    format!(
+        "{{\"request_line\":{},\"method\":{},\"path\":{},\"query\":{},\"version\":{},\
+         \"headers\":{},{}}}\n",
+        json_string(&request.request_line),
+        json_string(request.method()),
+        json_string(request.path()),
+        request.query().map_or("null".into(), json_string),
+        json_string(request.version()),
+        json_pairs(request.headers()),
+        json_body(request.body()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Echo;
+    use crate::handler::{Handler, Request};
+
+    fn request() -> Request {
+        Request::new("PATCH", "/items/7?force=1")
+            .with_header("Host", "example.com")
+            .with_header("X-Forwarded-For", "10.0.0.1")
+            .with_body("{\"a\":1}")
+    }
+
+    #[test]
+    fn echoes_requests_as_text() {
+        let response = Echo::Text.handle(&request());
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.body(),
+            b"PATCH /items/7?force=1 HTTP/1.1\r\nHost: example.com\r\n\
+              X-Forwarded-For: 10.0.0.1\r\n\r\n{\"a\":1}"
+        );
+    }
+
+    #[test]
+    fn echoes_requests_as_json() {
+        let response = Echo::Json.handle(&request());
+        assert_eq!(response.header("content-type"), Some("application/json"));
+        assert_eq!(
+            String::from_utf8_lossy(response.body()),
+            concat!(
+                "{\"request_line\":\"PATCH /items/7?force=1 HTTP/1.1\",\"method\":\"PATCH\",",
+                "\"path\":\"/items/7\",\"query\":\"force=1\",\"version\":\"HTTP/1.1\",",
+                "\"headers\":[[\"Host\",\"example.com\"],[\"X-Forwarded-For\",\"10.0.0.1\"]],",
+                "\"body\":\"{\\\"a\\\":1}\"}\n"
+            )
+        );
+        let binary = Request::new("POST", "/").with_body(vec![0xff, 0x00]);
+        let body = Echo::Json.handle(&binary).body().to_vec();
+        assert!(body.ends_with(b"\"body_base64\":\"/wA=\"}\n"));
+    }
+}
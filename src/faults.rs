@@ -0,0 +1,310 @@
+//! Fault injection for chaos tests: added latency, error responses and broken
+//! connections, drawn from a small std-only PRNG.
+
+use std::collections::hash_map::RandomState;
+use std::f64::consts::PI;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::handler::{ConnectionFault, Handler, Middleware, Request, Response};
+use crate::logging::{LogLevel, LogSender};
+
+/// Latency added before each response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    /// Uniformly distributed between `min` and `max`.
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+    /// Normally distributed, clamped at zero.
+    Normal {
+        mean: Duration,
+        std_dev: Duration,
+    },
+}
+
+/// Which faults to inject, each drawn independently per request.
+///
+/// A broken connection is closed without a response (reset) or after half of
+/// the body (truncated); over HTTP/2 only the stream is reset.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Faults {
+    latency: Option<Latency>,
+    error_rate: f64,
+    error_statuses: Vec<u16>,
+    reset_rate: f64,
+    truncate_rate: f64,
+    seed: Option<u64>,
+}
+
+impl Faults {
+    pub fn latency(mut self, latency: Latency) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Answers with one of `statuses`, chosen uniformly, with the given
+    /// probability instead of calling the handler.
+    pub fn errors(mut self, probability: f64, statuses: impl Into<Vec<u16>>) -> Self {
+        self.error_rate = probability;
+        self.error_statuses = statuses.into();
+        self
+    }
+
+    /// Closes the connection without a response with the given probability.
+    pub fn resets(mut self, probability: f64) -> Self {
+        self.reset_rate = probability;
+        self
+    }
+
+    /// Closes the connection halfway through the body with the given
+    /// probability.
+    pub fn truncations(mut self, probability: f64) -> Self {
+        self.truncate_rate = probability;
+        self
+    }
+
+    /// Seeds the random number generator, for reproducible runs. Defaults to
+    /// a random seed.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.latency.is_none()
+            && self.error_rate == 0.0
+            && self.reset_rate == 0.0
+            && self.truncate_rate == 0.0
+    }
+
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidInput, reason);
+        for (name, rate) in [
+            ("error", self.error_rate),
+            ("reset", self.reset_rate),
+            ("truncate", self.truncate_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(invalid(format!(
+                    "fault {} rate must be between 0 and 1, got {}",
+                    name, rate
+                )));
+            }
+        }
+        if self.error_rate > 0.0 && self.error_statuses.is_empty() {
+            return Err(invalid("fault error rate needs at least one status".into()));
+        }
+        if let Some(status) = self
+            .error_statuses
+            .iter()
+            .find(|status| !(400..=599).contains(*status))
+        {
+            return Err(invalid(format!(
+                "fault error status must be between 400 and 599, got {}",
+                status
+            )));
+        }
+        if let Some(Latency::Uniform { min, max }) = self.latency {
+            if min > max {
+                return Err(invalid("uniform fault latency needs min <= max".into()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// SplitMix64 over an atomic counter, so workers can share it without a lock.
+#[derive(Debug)]
+struct Rng {
+    state: AtomicU64,
+}
+
+impl Rng {
+    fn new(seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| RandomState::new().build_hasher().finish());
+        Rng {
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    fn next_u64(&self) -> u64 {
+        const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut z = self
+            .state
+            .fetch_add(GAMMA, Ordering::Relaxed)
+            .wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A float in `[0, 1)`.
+    fn next_f64(&self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+
+    fn below(&self, n: usize) -> usize {
+        (self.next_f64() * n as f64) as usize
+    }
+
+    fn latency(&self, latency: Latency) -> Duration {
+        let secs = match latency {
+            Latency::Fixed(delay) => return delay,
+            Latency::Uniform { min, max } => {
+                min.as_secs_f64() + (max - min).as_secs_f64() * self.next_f64()
+            }
+            Latency::Normal { mean, std_dev } => {
+                // Box-Muller; `1 - u` keeps the logarithm's argument in (0, 1].
+                let u1 = 1.0 - self.next_f64();
+                let u2 = self.next_f64();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+                mean.as_secs_f64() + z * std_dev.as_secs_f64()
+            }
+        };
+        Duration::try_from_secs_f64(secs.max(0.0)).unwrap_or(Duration::ZERO)
+    }
+}
+
+/// Injects the configured faults into public requests and logs each one.
+pub(crate) struct FaultInjector {
+    faults: Faults,
+    rng: Rng,
+    log_tx: LogSender,
+}
+
+impl FaultInjector {
+    pub(crate) fn new(faults: Faults, log_tx: LogSender) -> Self {
+        FaultInjector {
+            rng: Rng::new(faults.seed),
+            faults,
+            log_tx,
+        }
+    }
+
+    fn report(&self, level: LogLevel, fault: &str, request: &Request) {
+        self.log_tx.event(
+            level,
+            format!(
+                "Injected {} for {} {}",
+                fault,
+                request.method(),
+                crate::sanitize(request.path())
+            ),
+        );
+    }
+}
+
+impl Middleware for FaultInjector {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        if let Some(latency) = self.faults.latency {
+            let delay = self.rng.latency(latency);
+            if !delay.is_zero() {
+                self.report(LogLevel::Debug, &format!("{:?} latency", delay), request);
+                thread::sleep(delay);
+            }
+        }
+        let response = if self.rng.chance(self.faults.error_rate) {
+            let statuses = &self.faults.error_statuses;
+            let status = statuses[self.rng.below(statuses.len())];
+            self.report(LogLevel::Info, &format!("{} response", status), request);
+            Response::new(status)
+        } else {
+            next.handle(request)
+        };
+        if self.rng.chance(self.faults.reset_rate) {
+            self.report(LogLevel::Info, "connection reset", request);
+            response.with_fault(ConnectionFault::Reset)
+        } else if self.rng.chance(self.faults.truncate_rate) {
+            self.report(LogLevel::Info, "truncated response", request);
+            response.with_fault(ConnectionFault::Truncate)
+        } else {
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FaultInjector, Faults, Latency, Rng};
+    use crate::handler::{Chain, ConnectionFault, Handler, Request, Response};
+    use crate::logging::{log_channel, LogLevel};
+    use std::time::Duration;
+
+    fn chain(faults: Faults) -> Chain {
+        let (log_tx, _log_rx) = log_channel(100, LogLevel::Off);
+        Chain::new(
+            vec![Box::new(FaultInjector::new(faults, log_tx))],
+            Box::new(|_: &Request| Response::text("OK")),
+        )
+    }
+
+    #[test]
+    fn seeded_rng_is_reproducible() {
+        let a = Rng::new(Some(7));
+        let b = Rng::new(Some(7));
+        for _ in 0..10 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+        assert!((0..1000).all(|_| (0.0..1.0).contains(&a.next_f64())));
+    }
+
+    #[test]
+    fn samples_latency_distributions() {
+        let rng = Rng::new(Some(1));
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let uniform = Latency::Uniform {
+            min: Duration::from_millis(10),
+            max: Duration::from_millis(20),
+        };
+        assert!((0..1000).all(|_| (10.0..=20.0).contains(&ms(rng.latency(uniform)))));
+
+        let normal = Latency::Normal {
+            mean: Duration::from_millis(100),
+            std_dev: Duration::from_millis(10),
+        };
+        let mean = (0..2000).map(|_| ms(rng.latency(normal))).sum::<f64>() / 2000.0;
+        assert!((95.0..105.0).contains(&mean), "{}", mean);
+    }
+
+    #[test]
+    fn injects_errors_at_the_configured_rate() {
+        let chain = chain(Faults::default().errors(0.25, [500, 503]).seed(3));
+        let statuses: Vec<u16> = (0..2000)
+            .map(|_| chain.handle(&Request::new("GET", "/")).status())
+            .collect();
+        let errors = statuses.iter().filter(|s| **s != 200).count();
+        assert!((400..600).contains(&errors), "{}", errors);
+        assert!(statuses.contains(&500) && statuses.contains(&503));
+    }
+
+    #[test]
+    fn marks_broken_connections() {
+        let reset = chain(Faults::default().resets(1.0)).handle(&Request::new("GET", "/"));
+        assert_eq!(reset.fault(), Some(ConnectionFault::Reset));
+        let truncated = chain(Faults::default().truncations(1.0)).handle(&Request::new("GET", "/"));
+        assert_eq!(truncated.fault(), Some(ConnectionFault::Truncate));
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(Faults::default().errors(1.5, [500]).validate().is_err());
+        assert!(Faults::default().errors(0.5, []).validate().is_err());
+        assert!(Faults::default().errors(0.5, [200]).validate().is_err());
+        let backwards = Latency::Uniform {
+            min: Duration::from_millis(2),
+            max: Duration::from_millis(1),
+        };
+        assert!(Faults::default().latency(backwards).validate().is_err());
+        assert!(Faults::default().resets(0.1).validate().is_ok());
+    }
+}
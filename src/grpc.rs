@@ -0,0 +1,248 @@
+//! gRPC Health Checking Protocol (`grpc.health.v1.Health`), answered by a
+//! middleware with hand-encoded protobuf messages. The HTTP/2 layer sends the
+//! `grpc-status` and `grpc-message` headers of its responses as trailers.
+
+use std::sync::Arc;
+
+use crate::handler::{Handler, Middleware, Request, Response};
+use crate::probes::Probes;
+
+pub(crate) const HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+
+pub(crate) const STATUS_OK: u32 = 0;
+const STATUS_NOT_FOUND: u32 = 5;
+const STATUS_UNIMPLEMENTED: u32 = 12;
+const STATUS_INTERNAL: u32 = 13;
+
+/// `HealthCheckResponse.ServingStatus` values.
+const SERVING: u8 = 1;
+const NOT_SERVING: u8 = 2;
+
+/// Length-prefix of a gRPC message: compressed flag plus big-endian length.
+const MESSAGE_PREFIX_LEN: usize = 5;
+
+/// Outcome of a gRPC call: the `grpc-status` trailer, an optional
+/// `grpc-message`, and the framed response message when the call succeeded.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Reply {
+    pub(crate) status: u32,
+    pub(crate) message: Option<&'static str>,
+    pub(crate) body: Option<Vec<u8>>,
+}
+
+impl Reply {
+    fn error(status: u32, message: &'static str) -> Self {
+        Reply {
+            status,
+            message: Some(message),
+            body: None,
+        }
+    }
+}
+
+pub(crate) fn is_grpc_content_type(content_type: &str) -> bool {
+    content_type == "application/grpc"
+        || content_type.starts_with("application/grpc+")
+        || content_type.starts_with("application/grpc;")
+}
+
+/// Answers a gRPC call to `path` with request body `body`. Only the empty
+/// service name (the server as a whole) is known to the health service.
+pub(crate) fn handle(path: &str, body: &[u8], serving: bool) -> Reply {
+    if path != HEALTH_CHECK_PATH {
+        return Reply::error(STATUS_UNIMPLEMENTED, "Method not implemented");
+    }
+    let message = match unframe(body) {
+        Ok(message) => message,
+        Err(reply) => return reply,
+    };
+    let service = match health_check_service(message) {
+        Some(service) => service,
+        None => return Reply::error(STATUS_INTERNAL, "Failed to parse HealthCheckRequest"),
+    };
+    if !service.is_empty() {
+        return Reply::error(STATUS_NOT_FOUND, "Unknown service");
+    }
+    let status = if serving { SERVING } else { NOT_SERVING };
+    Reply {
+        status: STATUS_OK,
+        message: None,
+        // Field 1 (status), wire type 0 (varint).
+        body: Some(frame(&[0x08, status])),
+    }
+}
+
+/// Answers gRPC calls over HTTP/2; other requests go to the handler. Being
+/// part of the chain, calls are logged, captured and counted like any other
+/// request.
+pub(crate) struct GrpcHealth {
+    probes: Arc<Probes>,
+}
+
+impl GrpcHealth {
+    pub(crate) fn new(probes: Arc<Probes>) -> Self {
+        GrpcHealth { probes }
+    }
+}
+
+impl Middleware for GrpcHealth {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        if request.version() != "HTTP/2.0"
+            || request.method() != "POST"
+            || !request
+                .header("content-type")
+                .is_some_and(is_grpc_content_type)
+        {
+            return next.handle(request);
+        }
+        let reply = handle(request.path(), request.body(), self.probes.is_ready());
+        let mut response = Response::new(200)
+            .with_header("Content-Type", "application/grpc")
+            .with_header("grpc-status", reply.status.to_string());
+        if let Some(message) = reply.message {
+            response = response.with_header("grpc-message", message);
+        }
+        match reply.body {
+            Some(body) => response.with_body(body),
+            None => response,
+        }
+    }
+}
+
+fn unframe(body: &[u8]) -> Result<&[u8], Reply> {
+    if body.len() < MESSAGE_PREFIX_LEN {
+        return Err(Reply::error(STATUS_INTERNAL, "Missing request message"));
+    }
+    if body[0] != 0 {
+        return Err(Reply::error(
+            STATUS_UNIMPLEMENTED,
+            "Compressed messages are not supported",
+        ));
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    if body.len() - MESSAGE_PREFIX_LEN != len {
+        return Err(Reply::error(STATUS_INTERNAL, "Malformed request message"));
+    }
+    Ok(&body[MESSAGE_PREFIX_LEN..])
+}
+
+fn frame(message: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(MESSAGE_PREFIX_LEN + message.len());
+    out.push(0);
+    out.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    out.extend_from_slice(message);
+    out
+}
+
+/// Extracts `HealthCheckRequest.service` (field 1), skipping unknown fields.
+/// Returns `None` when the message is not valid protobuf.
+fn health_check_service(mut message: &[u8]) -> Option<String> {
+    let mut service = String::new();
+    while !message.is_empty() {
+        let key = read_varint(&mut message)?;
+        let (field, wire_type) = (key >> 3, key & 0x7);
+        match wire_type {
+            0 => {
+                read_varint(&mut message)?;
+            }
+            1 => message = message.get(8..)?,
+            2 => {
+                let len = usize::try_from(read_varint(&mut message)?).ok()?;
+                let value = message.get(..len)?;
+                if field == 1 {
+                    service = String::from_utf8(value.to_vec()).ok()?;
+                }
+                message = &message[len..];
+            }
+            5 => message = message.get(4..)?,
+            _ => return None,
+        }
+    }
+    Some(service)
+}
+
+fn read_varint(input: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        frame, handle, health_check_service, is_grpc_content_type, Reply, HEALTH_CHECK_PATH,
+        STATUS_INTERNAL, STATUS_NOT_FOUND, STATUS_OK, STATUS_UNIMPLEMENTED,
+    };
+
+    #[test]
+    fn reports_serving_for_empty_service() {
+        let reply = handle(HEALTH_CHECK_PATH, &frame(&[]), true);
+        assert_eq!(
+            reply,
+            Reply {
+                status: STATUS_OK,
+                message: None,
+                body: Some(vec![0, 0, 0, 0, 2, 0x08, 1]),
+            }
+        );
+    }
+
+    #[test]
+    fn reports_not_serving_while_draining() {
+        let reply = handle(HEALTH_CHECK_PATH, &frame(&[]), false);
+        assert_eq!(reply.body, Some(vec![0, 0, 0, 0, 2, 0x08, 2]));
+    }
+
+    #[test]
+    fn rejects_unknown_service_with_not_found() {
+        let reply = handle(HEALTH_CHECK_PATH, &frame(b"\x0a\x03foo"), true);
+        assert_eq!(reply.status, STATUS_NOT_FOUND);
+        assert_eq!(reply.body, None);
+    }
+
+    #[test]
+    fn rejects_other_methods_as_unimplemented() {
+        let reply = handle("/grpc.health.v1.Health/Watch", &frame(&[]), true);
+        assert_eq!(reply.status, STATUS_UNIMPLEMENTED);
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert_eq!(
+            handle(HEALTH_CHECK_PATH, &[0, 0], true).status,
+            STATUS_INTERNAL
+        );
+        assert_eq!(
+            handle(HEALTH_CHECK_PATH, &[0, 0, 0, 0, 3, 0x0a], true).status,
+            STATUS_INTERNAL
+        );
+        assert_eq!(
+            handle(HEALTH_CHECK_PATH, &[1, 0, 0, 0, 0], true).status,
+            STATUS_UNIMPLEMENTED
+        );
+    }
+
+    #[test]
+    fn parses_service_and_skips_unknown_fields() {
+        assert_eq!(
+            health_check_service(b"\x10\x96\x01\x0a\x02ok\x1d\x01\x02\x03\x04"),
+            Some("ok".into())
+        );
+        assert_eq!(health_check_service(b"\x0a\x05ok"), None);
+    }
+
+    #[test]
+    fn recognizes_grpc_content_types() {
+        assert!(is_grpc_content_type("application/grpc"));
+        assert!(is_grpc_content_type("application/grpc+proto"));
+        assert!(!is_grpc_content_type("application/grpc-web"));
+        assert!(!is_grpc_content_type("text/plain"));
+    }
+}
@@ -0,0 +1,595 @@
+//! Pluggable request handling: a [`Handler`] turns a parsed [`Request`] into a
+//! [`Response`], and [`Middleware`] wraps handlers to add behavior around them.
+//!
+//! Protocol details stay in the HTTP/1 and HTTP/2 layers: they reject
+//! malformed or oversized requests before a handler runs, and always add
+//! `Content-Length` and the security headers to what it returns.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::compression::Precompressed;
+use crate::logging::{AccessLogFilter, LogSender};
+use crate::sanitize;
+
+/// Response headers set by the protocol layers; handlers cannot override them.
+const MANAGED_HEADERS: &[&str] = &[
+    "connection",
+    "content-length",
+    "keep-alive",
+    "transfer-encoding",
+    "upgrade",
+    "x-content-type-options",
+    "x-frame-options",
+];
+
+/// A parsed request, with its body already read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) query: Option<String>,
+    pub(crate) version: &'static str,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+    pub(crate) peer: String,
+    /// Raw request line; sanitized only when an access-log line is written.
+    pub(crate) request_line: String,
+    /// Size of the request on the wire: header block plus body.
+    pub(crate) received_bytes: usize,
+}
+
+impl Request {
+    /// Builds a request for `target` (a path with an optional `?query`), for
+    /// exercising handlers directly.
+    pub fn new(method: impl Into<String>, target: &str) -> Self {
+        let method = method.into();
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (target, None),
+        };
+        Request {
+            request_line: format!("{} {} HTTP/1.1", method, target),
+            method,
+            path: path.to_string(),
+            query,
+            version: "HTTP/1.1",
+            headers: Vec::new(),
+            body: Vec::new(),
+            peer: "unknown".into(),
+            received_bytes: 0,
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// The request path, without the query string.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The raw query string, without the leading `?`.
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    /// `HTTP/1.0`, `HTTP/1.1` or `HTTP/2.0`.
+    pub fn version(&self) -> &str {
+        self.version
+    }
+
+    /// Request headers in the order received. HTTP/2 pseudo-headers are not
+    /// included.
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// The value of the first header named `name`, ignoring ASCII case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The client address: the first `X-Forwarded-For` entry when present,
+    /// otherwise the socket peer address.
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+}
+
+/// A connection-level failure sent in place of a clean response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ConnectionFault {
+    /// Close the connection without responding.
+    Reset,
+    /// Close the connection after half of the body.
+    Truncate,
+}
+
+/// An open file sent as the body, read in chunks while the response is
+/// written instead of being held in memory.
+#[derive(Clone, Debug)]
+pub(crate) struct FileBody {
+    file: Arc<Mutex<File>>,
+    /// The body in order; ranges are of the file.
+    parts: Vec<BodyPart>,
+}
+
+impl PartialEq for FileBody {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.file, &other.file) && self.parts == other.parts
+    }
+}
+
+impl Eq for FileBody {}
+
+/// A piece of a body rebuilt by [`Response::with_body_parts`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum BodyPart {
+    Bytes(Vec<u8>),
+    /// `len` bytes of the original body from `start`.
+    Range {
+        start: u64,
+        len: u64,
+    },
+}
+
+impl BodyPart {
+    fn len(&self) -> u64 {
+        match self {
+            BodyPart::Bytes(bytes) => bytes.len() as u64,
+            BodyPart::Range { len, .. } => *len,
+        }
+    }
+}
+
+/// Size of the chunks a file body is read in.
+const FILE_CHUNK_SIZE: usize = 16 * 1024;
+
+/// A response to send. For `HEAD` requests the body is dropped but its length
+/// is still reported in `Content-Length`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    status: u16,
+    headers: Vec<(Cow<'static, str>, Cow<'static, str>)>,
+    body: Cow<'static, [u8]>,
+    file: Option<FileBody>,
+    /// Compressed copies of `body`, prepared when the response was built.
+    precompressed: Option<Arc<Precompressed>>,
+    fault: Option<ConnectionFault>,
+}
+
+impl Response {
+    /// A response with an empty body.
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Cow::Borrowed(&[]),
+            file: None,
+            precompressed: None,
+            fault: None,
+        }
+    }
+
+    /// A `200` response with a `text/plain` body.
+    pub fn text(body: impl Into<Cow<'static, str>>) -> Self {
+        let body = match body.into() {
+            Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+            Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+        };
+        Response::new(200)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body)
+    }
+
+    pub fn with_header(
+        mut self,
+        name: impl Into<Cow<'static, str>>,
+        value: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets header `name`, dropping any earlier values for it.
+    pub(crate) fn replace_header(
+        mut self,
+        name: &'static str,
+        value: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        self.headers
+            .retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.with_header(name, value)
+    }
+
+    pub fn with_body(mut self, body: impl Into<Cow<'static, [u8]>>) -> Self {
+        self.body = body.into();
+        self.file = None;
+        self.precompressed = None;
+        self
+    }
+
+    /// Streams the first `len` bytes of `file` as the body.
+    pub(crate) fn with_file(mut self, file: File, len: u64) -> Self {
+        self.body = Cow::Borrowed(&[]);
+        self.precompressed = None;
+        self.file = Some(FileBody {
+            file: Arc::new(Mutex::new(file)),
+            parts: vec![BodyPart::Range { start: 0, len }],
+        });
+        self
+    }
+
+    /// Replaces the body with `parts`, whose ranges are of the current body,
+    /// or of the whole file for a streamed one. Ranges are clamped to it.
+    pub(crate) fn with_body_parts(mut self, parts: Vec<BodyPart>) -> Self {
+        if let Some(file) = &mut self.file {
+            file.parts = parts;
+            return self;
+        }
+        let mut body = Vec::new();
+        for part in parts {
+            match part {
+                BodyPart::Bytes(bytes) => body.extend_from_slice(&bytes),
+                BodyPart::Range { start, len } => {
+                    let start = (start as usize).min(self.body.len());
+                    let end = start.saturating_add(len as usize).min(self.body.len());
+                    body.extend_from_slice(&self.body[start..end]);
+                }
+            }
+        }
+        self.with_body(body)
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| (name.as_ref(), value.as_ref()))
+    }
+
+    /// The value of the first header named `name`, ignoring ASCII case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// The body held in memory; empty when a file is streamed instead.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The length of the body, including a streamed file.
+    pub(crate) fn body_len(&self) -> u64 {
+        match &self.file {
+            Some(file) => file.parts.iter().map(BodyPart::len).sum(),
+            None => self.body.len() as u64,
+        }
+    }
+
+    /// Passes the first `len` bytes of the body to `write`, a file body in
+    /// chunks as it is read. The second argument marks the last chunk.
+    pub(crate) fn write_body<E: From<io::Error>>(
+        &self,
+        len: u64,
+        mut write: impl FnMut(&[u8], bool) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let Some(body) = &self.file else {
+            let len = (len as usize).min(self.body.len());
+            return if len == 0 {
+                Ok(())
+            } else {
+                write(&self.body[..len], true)
+            };
+        };
+        let mut file = body.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut remaining = len.min(self.body_len());
+        let mut chunk = vec![0; FILE_CHUNK_SIZE];
+        for part in &body.parts {
+            if remaining == 0 {
+                break;
+            }
+            let (start, len) = match part {
+                BodyPart::Bytes(bytes) => {
+                    let n = (remaining as usize).min(bytes.len());
+                    remaining -= n as u64;
+                    write(&bytes[..n], remaining == 0)?;
+                    continue;
+                }
+                BodyPart::Range { start, len } => (*start, *len),
+            };
+            file.seek(SeekFrom::Start(start))?;
+            let mut left = len.min(remaining);
+            while left > 0 {
+                let want = (left as usize).min(chunk.len());
+                let n = file.read(&mut chunk[..want])?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file shrank while it was sent",
+                    )
+                    .into());
+                }
+                left -= n as u64;
+                remaining -= n as u64;
+                write(&chunk[..n], remaining == 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn with_precompressed(mut self, precompressed: Arc<Precompressed>) -> Self {
+        self.precompressed = Some(precompressed);
+        self
+    }
+
+    pub(crate) fn precompressed(&self) -> Option<&Precompressed> {
+        self.precompressed.as_deref()
+    }
+
+    pub(crate) fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub(crate) fn with_fault(mut self, fault: ConnectionFault) -> Self {
+        self.fault = Some(fault);
+        self
+    }
+
+    pub(crate) fn fault(&self) -> Option<ConnectionFault> {
+        self.fault
+    }
+
+    /// The headers to put on the wire: managed headers and any name or value
+    /// that could break the framing are dropped.
+    pub(crate) fn headers_to_send(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers().filter(|(name, value)| {
+            !name.is_empty()
+                && name.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+                && !value.bytes().any(|b| b.is_ascii_control() && b != b'\t')
+                && !MANAGED_HEADERS
+                    .iter()
+                    .any(|managed| name.eq_ignore_ascii_case(managed))
+        })
+    }
+}
+
+/// Answers requests. Implemented for closures taking a [`Request`].
+pub trait Handler {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+/// Wraps a handler: inspect or short-circuit the request, call `next`, or
+/// adjust its response. Implemented for closures taking the request and `next`.
+pub trait Middleware {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&Request, &dyn Handler) -> Response,
+{
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        self(request, next)
+    }
+}
+
+/// Rejects requests whose body exceeds a limit with `413 Payload Too Large`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BodyLimit {
+    max_bytes: usize,
+}
+
+impl BodyLimit {
+    pub fn new(max_bytes: usize) -> Self {
+        BodyLimit { max_bytes }
+    }
+}
+
+impl Middleware for BodyLimit {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        if request.body.len() > self.max_bytes {
+            return Response::new(413);
+        }
+        next.handle(request)
+    }
+}
+
+/// Writes an access-log line for each request the filter selects.
+pub(crate) struct AccessLog {
+    log_tx: LogSender,
+    filter: AccessLogFilter,
+}
+
+impl AccessLog {
+    pub(crate) fn new(log_tx: LogSender, filter: AccessLogFilter) -> Self {
+        AccessLog { log_tx, filter }
+    }
+}
+
+impl Middleware for AccessLog {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let response = next.handle(request);
+        if self.filter.matches(&request.path, response.status()) {
+            self.log_tx.send(format!(
+                "{} \"{}\" {} bytes",
+                request.peer,
+                sanitize(&request.request_line),
+                request.received_bytes
+            ));
+        }
+        response
+    }
+}
+
+pub(crate) type BoxedHandler = Box<dyn Handler + Send + Sync>;
+pub(crate) type BoxedMiddleware = Box<dyn Middleware + Send + Sync>;
+
+/// A handler wrapped in middleware; the first middleware is the outermost.
+pub(crate) struct Chain {
+    middleware: Vec<BoxedMiddleware>,
+    handler: BoxedHandler,
+}
+
+impl Chain {
+    pub(crate) fn new(middleware: Vec<BoxedMiddleware>, handler: BoxedHandler) -> Self {
+        Chain {
+            middleware,
+            handler,
+        }
+    }
+}
+
+impl Handler for Chain {
+    fn handle(&self, request: &Request) -> Response {
+        Next {
+            middleware: &self.middleware,
+            handler: &*self.handler,
+        }
+        .handle(request)
+    }
+}
+
+struct Next<'a> {
+    middleware: &'a [BoxedMiddleware],
+    handler: &'a (dyn Handler + Send + Sync),
+}
+
+impl Handler for Next<'_> {
+    fn handle(&self, request: &Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(
+                request,
+                &Next {
+                    middleware: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.handle(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessLog, BodyLimit, Chain, Handler, Request, Response};
+    use crate::logging::{log_channel, AccessLogFilter, LogLevel};
+
+    fn echo_path(request: &Request) -> Response {
+        Response::text(request.path().to_string())
+    }
+
+    #[test]
+    fn splits_query_from_target() {
+        let request = Request::new("GET", "/search?q=ok&page=2");
+        assert_eq!(request.path(), "/search");
+        assert_eq!(request.query(), Some("q=ok&page=2"));
+        assert_eq!(Request::new("GET", "/").query(), None);
+    }
+
+    #[test]
+    fn looks_up_headers_ignoring_case() {
+        let request = Request::new("GET", "/").with_header("X-Token", "abc");
+        assert_eq!(request.header("x-token"), Some("abc"));
+        assert_eq!(request.header("x-other"), None);
+    }
+
+    #[test]
+    fn runs_middleware_outermost_first() {
+        let tag = |label: &'static str| {
+            move |request: &Request, next: &dyn Handler| {
+                let response = next.handle(request);
+                let body = [response.body(), label.as_bytes()].concat();
+                response.with_body(body)
+            }
+        };
+        let chain = Chain::new(
+            vec![Box::new(tag("-outer")), Box::new(tag("-inner"))],
+            Box::new(echo_path),
+        );
+        let response = chain.handle(&Request::new("GET", "/x"));
+        assert_eq!(response.body(), b"/x-inner-outer");
+    }
+
+    #[test]
+    fn body_limit_short_circuits_large_bodies() {
+        let chain = Chain::new(vec![Box::new(BodyLimit::new(4))], Box::new(echo_path));
+        let small = Request::new("POST", "/").with_body("1234");
+        let large = Request::new("POST", "/").with_body("12345");
+        assert_eq!(chain.handle(&small).status(), 200);
+        assert_eq!(chain.handle(&large).status(), 413);
+    }
+
+    #[test]
+    fn access_log_writes_one_line_per_request() {
+        let (log_tx, log_rx) = log_channel(10, LogLevel::Info);
+        let access_log = AccessLog::new(log_tx, AccessLogFilter::default());
+        let chain = Chain::new(vec![Box::new(access_log)], Box::new(echo_path));
+        chain.handle(&Request::new("GET", "/a?b"));
+        let lines: Vec<String> = log_rx.try_iter().collect();
+        assert_eq!(lines, ["unknown \"GET /a?b HTTP/1.1\" 0 bytes"]);
+    }
+
+    #[test]
+    fn access_log_skips_requests_outside_the_filter() {
+        let (log_tx, log_rx) = log_channel(10, LogLevel::Info);
+        let filter = AccessLogFilter::default().errors_only(true);
+        let not_found = |request: &Request| {
+            let status = if request.path() == "/" { 200 } else { 404 };
+            Response::new(status)
+        };
+        let access_log = AccessLog::new(log_tx, filter);
+        let chain = Chain::new(vec![Box::new(access_log)], Box::new(not_found));
+        chain.handle(&Request::new("GET", "/"));
+        chain.handle(&Request::new("GET", "/missing\x1b"));
+        let lines: Vec<String> = log_rx.try_iter().collect();
+        assert_eq!(lines, ["unknown \"GET /missing? HTTP/1.1\" 0 bytes"]);
+    }
+
+    #[test]
+    fn drops_managed_and_malformed_headers() {
+        let response = Response::new(200)
+            .with_header("Content-Length", "99")
+            .with_header("X-Ok", "yes")
+            .with_header("Bad Name", "x")
+            .with_header("X-Split", "a\r\nInjected: 1");
+        let sent: Vec<_> = response.headers_to_send().collect();
+        assert_eq!(sent, [("X-Ok", "yes")]);
+    }
+}
@@ -0,0 +1,795 @@
+//! HPACK (RFC 7541) header compression for the HTTP/2 frame layer.
+//!
+//! Decoding supports the full format: static and dynamic tables, table size
+//! updates and Huffman-coded strings. Encoding only emits literals without
+//! indexing, which keeps the peer's view of our dynamic table permanently empty.
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+/// Size of the dynamic table both peers start with (SETTINGS_HEADER_TABLE_SIZE).
+pub(crate) const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// Per-entry overhead added to `name.len() + value.len()` when sizing the dynamic table.
+const ENTRY_OVERHEAD: usize = 32;
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Huffman code and bit length for every octet plus EOS (index 256), RFC 7541 Appendix B.
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DecodeError {
+    Truncated,
+    IntegerOverflow,
+    InvalidIndex,
+    InvalidHuffman,
+    TableSizeExceeded,
+}
+
+/// Stateful decoder for the header blocks of one connection.
+pub(crate) struct Decoder {
+    dynamic: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    /// Upper bound for table size updates, as advertised in our SETTINGS.
+    limit: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new(limit: usize) -> Self {
+        Decoder {
+            dynamic: VecDeque::new(),
+            size: 0,
+            max_size: limit,
+            limit,
+        }
+    }
+
+    /// Decodes a header block, returning the headers and the size of the
+    /// whole header list as counted for `SETTINGS_MAX_HEADER_LIST_SIZE`.
+    /// Headers past `max_list_size` are decoded but not kept, so a small block
+    /// that repeats a large table entry cannot expand into a large list.
+    pub(crate) fn decode(
+        &mut self,
+        block: &[u8],
+        max_list_size: usize,
+    ) -> Result<(Vec<(String, String)>, usize), DecodeError> {
+        let mut headers = Vec::new();
+        let mut list_size = 0usize;
+        let mut keep = |name: &str, value: &str| {
+            list_size = list_size.saturating_add(name.len() + value.len() + ENTRY_OVERHEAD);
+            list_size <= max_list_size
+        };
+        let mut pos = 0;
+        while pos < block.len() {
+            let first = block[pos];
+            if first & 0x80 != 0 {
+                let index = decode_integer(block, &mut pos, 7)?;
+                let (name, value) = self.entry(index)?;
+                if keep(name, value) {
+                    headers.push((name.to_string(), value.to_string()));
+                }
+            } else if first & 0x40 != 0 {
+                let (name, value) = self.decode_literal(block, &mut pos, 6)?;
+                if keep(&name, &value) {
+                    headers.push((name.clone(), value.clone()));
+                }
+                self.insert(name, value);
+            } else if first & 0x20 != 0 {
+                let size = decode_integer(block, &mut pos, 5)?;
+                if size > self.limit {
+                    return Err(DecodeError::TableSizeExceeded);
+                }
+                self.max_size = size;
+                self.evict();
+            } else {
+                let (name, value) = self.decode_literal(block, &mut pos, 4)?;
+                if keep(&name, &value) {
+                    headers.push((name, value));
+                }
+            }
+        }
+        Ok((headers, list_size))
+    }
+
+    fn decode_literal(
+        &self,
+        block: &[u8],
+        pos: &mut usize,
+        prefix: u8,
+    ) -> Result<(String, String), DecodeError> {
+        let index = decode_integer(block, pos, prefix)?;
+        let name = if index == 0 {
+            decode_string(block, pos)?
+        } else {
+            self.entry(index)?.0.to_string()
+        };
+        let value = decode_string(block, pos)?;
+        Ok((name, value))
+    }
+
+    fn entry(&self, index: usize) -> Result<(&str, &str), DecodeError> {
+        if index == 0 {
+            return Err(DecodeError::InvalidIndex);
+        }
+        if let Some((name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name, value));
+        }
+        self.dynamic
+            .get(index - STATIC_TABLE.len() - 1)
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .ok_or(DecodeError::InvalidIndex)
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        let entry_size = name.len() + value.len() + ENTRY_OVERHEAD;
+        if entry_size > self.max_size {
+            // An entry larger than the table empties it without being added.
+            self.dynamic.clear();
+            self.size = 0;
+            return;
+        }
+        self.size += entry_size;
+        self.dynamic.push_front((name, value));
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let Some((name, value)) = self.dynamic.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+fn decode_integer(block: &[u8], pos: &mut usize, prefix: u8) -> Result<usize, DecodeError> {
+    let mask = (1u16 << prefix) as u8 - 1;
+    let first = *block.get(*pos).ok_or(DecodeError::Truncated)?;
+    *pos += 1;
+    let mut value = (first & mask) as usize;
+    if value < mask as usize {
+        return Ok(value);
+    }
+    let mut shift = 0u32;
+    loop {
+        let byte = *block.get(*pos).ok_or(DecodeError::Truncated)?;
+        *pos += 1;
+        if shift > 28 {
+            return Err(DecodeError::IntegerOverflow);
+        }
+        value = value
+            .checked_add(((byte & 0x7f) as usize) << shift)
+            .ok_or(DecodeError::IntegerOverflow)?;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+fn decode_string(block: &[u8], pos: &mut usize) -> Result<String, DecodeError> {
+    let huffman = block.get(*pos).ok_or(DecodeError::Truncated)? & 0x80 != 0;
+    let len = decode_integer(block, pos, 7)?;
+    let end = pos.checked_add(len).ok_or(DecodeError::Truncated)?;
+    let raw = block.get(*pos..end).ok_or(DecodeError::Truncated)?;
+    *pos = end;
+    let bytes = if huffman {
+        huffman_decode(raw)?
+    } else {
+        raw.to_vec()
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Binary decoding tree built from `HUFFMAN_CODES`; each node holds its two
+/// children, where values at or above `LEAF` encode the symbol `value - LEAF`.
+fn huffman_tree() -> &'static [[u16; 2]] {
+    static TREE: OnceLock<Vec<[u16; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut nodes = vec![[0u16; 2]];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for bit_index in (0..len).rev() {
+                let bit = ((code >> bit_index) & 1) as usize;
+                if bit_index == 0 {
+                    nodes[node][bit] = LEAF + symbol as u16;
+                } else {
+                    if nodes[node][bit] == 0 {
+                        nodes.push([0, 0]);
+                        nodes[node][bit] = (nodes.len() - 1) as u16;
+                    }
+                    node = nodes[node][bit] as usize;
+                }
+            }
+        }
+        nodes
+    })
+}
+
+const LEAF: u16 = 0x8000;
+const EOS: u16 = 256;
+
+fn huffman_decode(input: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let tree = huffman_tree();
+    let mut out = Vec::with_capacity(input.len() * 8 / 5);
+    let mut node = 0usize;
+    // Bits consumed since the last emitted symbol, and whether they were all ones.
+    let mut pending_bits = 0u32;
+    let mut pending_ones = true;
+    for &byte in input {
+        for bit_index in (0..8).rev() {
+            let bit = (byte >> bit_index) & 1;
+            pending_bits += 1;
+            pending_ones &= bit == 1;
+            let next = tree[node][bit as usize];
+            if next >= LEAF {
+                if next - LEAF == EOS {
+                    return Err(DecodeError::InvalidHuffman);
+                }
+                out.push((next - LEAF) as u8);
+                node = 0;
+                pending_bits = 0;
+                pending_ones = true;
+            } else if next == 0 {
+                return Err(DecodeError::InvalidHuffman);
+            } else {
+                node = next as usize;
+            }
+        }
+    }
+    // Padding must be a (strict) prefix of EOS, i.e. at most seven one bits.
+    if pending_bits > 7 || !pending_ones {
+        return Err(DecodeError::InvalidHuffman);
+    }
+    Ok(out)
+}
+
+pub(crate) fn encode_integer(value: usize, prefix: u8, flags: u8, out: &mut Vec<u8>) {
+    let mask = ((1u16 << prefix) - 1) as usize;
+    if value < mask {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | mask as u8);
+    let mut rest = value - mask;
+    while rest >= 0x80 {
+        out.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.push(rest as u8);
+}
+
+fn encode_string(value: &[u8], out: &mut Vec<u8>) {
+    encode_integer(value.len(), 7, 0, out);
+    out.extend_from_slice(value);
+}
+
+/// Appends `:status`, using the static table when the code has an entry.
+pub(crate) fn encode_status(status: u16, out: &mut Vec<u8>) {
+    let value = status.to_string();
+    match STATIC_TABLE[7..14].iter().position(|(_, v)| *v == value) {
+        Some(offset) => encode_integer(offset + 8, 7, 0x80, out),
+        None => {
+            encode_integer(8, 4, 0x00, out);
+            encode_string(value.as_bytes(), out);
+        }
+    }
+}
+
+/// Appends a header as a literal without indexing; `name` must be lowercase.
+pub(crate) fn encode_header(name: &str, value: &str, out: &mut Vec<u8>) {
+    match STATIC_TABLE.iter().position(|(n, _)| *n == name) {
+        Some(index) => encode_integer(index + 1, 4, 0x00, out),
+        None => {
+            out.push(0x00);
+            encode_string(name.as_bytes(), out);
+        }
+    }
+    encode_string(value.as_bytes(), out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_header, encode_integer, encode_status, DecodeError, Decoder};
+
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(|b| b.is_ascii_hexdigit()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn pairs(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn encodes_integers_with_prefix() {
+        let mut out = Vec::new();
+        encode_integer(10, 5, 0, &mut out);
+        assert_eq!(out, [0x0a]);
+        out.clear();
+        encode_integer(1337, 5, 0, &mut out);
+        assert_eq!(out, [0x1f, 0x9a, 0x0a]);
+    }
+
+    #[test]
+    fn decodes_request_sequence_without_huffman() {
+        // RFC 7541 C.3
+        let mut decoder = Decoder::new(4096);
+        let first = decoder
+            .decode(
+                &hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d"),
+                usize::MAX,
+            )
+            .unwrap()
+            .0;
+        assert_eq!(
+            first,
+            pairs(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        let second = decoder
+            .decode(&hex("8286 84be 5808 6e6f 2d63 6163 6865"), usize::MAX)
+            .unwrap()
+            .0;
+        assert_eq!(
+            second,
+            pairs(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+    }
+
+    #[test]
+    fn decodes_request_sequence_with_huffman() {
+        // RFC 7541 C.4
+        let mut decoder = Decoder::new(4096);
+        decoder
+            .decode(
+                &hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"),
+                usize::MAX,
+            )
+            .unwrap();
+        decoder
+            .decode(&hex("8286 84be 5886 a8eb 1064 9cbf"), usize::MAX)
+            .unwrap();
+        let third = decoder
+            .decode(
+                &hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"),
+                usize::MAX,
+            )
+            .unwrap()
+            .0;
+        assert_eq!(
+            third,
+            pairs(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+    }
+
+    #[test]
+    fn evicts_entries_beyond_table_size() {
+        let mut decoder = Decoder::new(4096);
+        // Shrink the table to 64 bytes, then insert two 42-byte entries.
+        let mut block = Vec::new();
+        encode_integer(64, 5, 0x20, &mut block);
+        block.extend_from_slice(&hex("4003 6162 6305 3132 3334 35"));
+        block.extend_from_slice(&hex("4003 7879 7a05 3637 3839 30"));
+        decoder.decode(&block, usize::MAX).unwrap();
+        assert_eq!(
+            decoder.decode(&[0x80 | 62], usize::MAX).unwrap().0,
+            pairs(&[("xyz", "67890")])
+        );
+        assert_eq!(
+            decoder.decode(&[0x80 | 63], usize::MAX),
+            Err(DecodeError::InvalidIndex)
+        );
+    }
+
+    #[test]
+    fn rejects_table_size_update_above_limit() {
+        let mut decoder = Decoder::new(4096);
+        let mut block = Vec::new();
+        encode_integer(8192, 5, 0x20, &mut block);
+        assert_eq!(
+            decoder.decode(&block, usize::MAX),
+            Err(DecodeError::TableSizeExceeded)
+        );
+    }
+
+    #[test]
+    fn stops_keeping_headers_past_the_list_size_limit() {
+        let mut decoder = Decoder::new(4096);
+        // One literal with a 4000-byte value added to the table, then 1000
+        // one-byte references to it: about 4 MB if every copy were kept.
+        let mut block = vec![0x40, 0x01, b'x'];
+        encode_integer(4000, 7, 0x00, &mut block);
+        block.extend_from_slice(&[b'v'; 4000]);
+        block.extend_from_slice(&[0x80 | 62; 1000]);
+        let (headers, size) = decoder.decode(&block, 8192).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(size, 1001 * (1 + 4000 + 32));
+        // The table is still in sync for the next block.
+        let (headers, _) = decoder.decode(&[0x80 | 62], 8192).unwrap();
+        assert_eq!(headers[0].1.len(), 4000);
+    }
+
+    #[test]
+    fn rejects_invalid_huffman_padding() {
+        let mut decoder = Decoder::new(4096);
+        // Literal name "a" (Huffman 00011) padded with zero bits instead of ones.
+        assert_eq!(
+            decoder.decode(&hex("0081 1800"), usize::MAX),
+            Err(DecodeError::InvalidHuffman)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_blocks() {
+        let mut decoder = Decoder::new(4096);
+        assert_eq!(
+            decoder.decode(&hex("400a 6162"), usize::MAX),
+            Err(DecodeError::Truncated)
+        );
+    }
+
+    #[test]
+    fn round_trips_encoded_responses() {
+        let mut block = Vec::new();
+        encode_status(200, &mut block);
+        encode_status(501, &mut block);
+        encode_header("content-type", "text/plain", &mut block);
+        encode_header("x-frame-options", "DENY", &mut block);
+        let headers = Decoder::new(4096).decode(&block, usize::MAX).unwrap().0;
+        assert_eq!(
+            headers,
+            pairs(&[
+                (":status", "200"),
+                (":status", "501"),
+                ("content-type", "text/plain"),
+                ("x-frame-options", "DENY"),
+            ])
+        );
+    }
+}
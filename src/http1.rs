@@ -0,0 +1,794 @@
+//! HTTP/1.0 and HTTP/1.1: one request per connection. Protocol errors are
+//! answered from prebuilt responses; everything else goes to the handler.
+
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::time::{Duration, Instant};
+
+use crate::handler::{ConnectionFault, Handler, Request, Response};
+use crate::server::ServerState;
+use crate::{http2, sanitize, Transport, MAX_BODY_SIZE, MAX_HEADER_SIZE};
+
+const RESPONSE_400: &[u8] = b"HTTP/1.1 400 Bad Request\r\n\
+Connection: close\r\n\
+X-Content-Type-Options: nosniff\r\n\
+X-Frame-Options: DENY\r\n\
+Content-Length: 0\r\n\r\n";
+const RESPONSE_408: &[u8] = b"HTTP/1.1 408 Request Timeout\r\n\
+Connection: close\r\n\
+X-Content-Type-Options: nosniff\r\n\
+X-Frame-Options: DENY\r\n\
+Content-Length: 0\r\n\r\n";
+const RESPONSE_431: &[u8] = b"HTTP/1.1 431 Request Header Fields Too Large\r\n\
+Connection: close\r\n\
+X-Content-Type-Options: nosniff\r\n\
+X-Frame-Options: DENY\r\n\
+Content-Length: 0\r\n\r\n";
+const RESPONSE_413: &[u8] = b"HTTP/1.1 413 Payload Too Large\r\n\
+Connection: close\r\n\
+X-Content-Type-Options: nosniff\r\n\
+X-Frame-Options: DENY\r\n\
+Content-Length: 0\r\n\r\n";
+const RESPONSE_501: &[u8] = b"HTTP/1.1 501 Not Implemented\r\n\
+Connection: close\r\n\
+X-Content-Type-Options: nosniff\r\n\
+X-Frame-Options: DENY\r\n\
+Content-Length: 0\r\n\r\n";
+const RESPONSE_505: &[u8] = b"HTTP/1.1 505 HTTP Version Not Supported\r\n\
+Connection: close\r\n\
+X-Content-Type-Options: nosniff\r\n\
+X-Frame-Options: DENY\r\n\
+Content-Length: 0\r\n\r\n";
+
+/// Every response constant above begins with this status-line prefix, which is
+/// swapped for the request's protocol version when the response is written.
+const STATUS_LINE_VERSION: &[u8] = b"HTTP/1.1";
+
+#[cfg(test)]
+fn body_timeout_duration() -> Duration {
+    Duration::from_millis(50)
+}
+
+#[cfg(not(test))]
+fn body_timeout_duration() -> Duration {
+    Duration::from_secs(5)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum HttpVersion {
+    Http10,
+    Http11,
+}
+
+impl HttpVersion {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            HttpVersion::Http10 => b"HTTP/1.0",
+            HttpVersion::Http11 => b"HTTP/1.1",
+        }
+    }
+}
+
+/// Maps the version token of a request line to the version we answer with.
+///
+/// A missing token is treated as HTTP/1.0 and higher HTTP/1.x minor versions are
+/// answered as HTTP/1.1. On failure the response to send is returned instead:
+/// 505 for other major versions (e.g. HTTP/2.0 or HTTP/3) and 400 for anything
+/// that is not an HTTP version at all.
+pub(crate) fn parse_http_version(version: &str) -> Result<HttpVersion, &'static [u8]> {
+    if version.is_empty() {
+        return Ok(HttpVersion::Http10);
+    }
+    let Some((major, minor)) = version
+        .strip_prefix("HTTP/")
+        .and_then(|v| v.split_once('.').or(Some((v, "0"))))
+    else {
+        return Err(RESPONSE_400);
+    };
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_number(major) || !is_number(minor) {
+        return Err(RESPONSE_400);
+    }
+    match (major.parse::<u32>(), minor.parse::<u32>()) {
+        (Ok(1), Ok(0)) => Ok(HttpVersion::Http10),
+        (Ok(1), Ok(_)) => Ok(HttpVersion::Http11),
+        _ => Err(RESPONSE_505),
+    }
+}
+
+pub(crate) fn parse_request_line(request_line: &str) -> (&str, &str, &str) {
+    if let Some(method_end_index) = request_line.find(' ') {
+        let method = &request_line[..method_end_index];
+        let rest = request_line[method_end_index + 1..].trim_start();
+        if let Some(path_end_index) = rest.find(' ') {
+            let path = rest[..path_end_index]
+                .split_once('?')
+                .map_or(&rest[..path_end_index], |(p, _)| p);
+            let version = rest[path_end_index + 1..].trim();
+            return (method, path, version);
+        }
+        let path = rest.split_once('?').map_or(rest, |(p, _)| p);
+        return (method, path, "");
+    }
+    ("", "", "")
+}
+
+/// Returns the trimmed value of the first header named `name`, skipping the
+/// request line.
+pub(crate) fn header_value<'a>(headers: &'a str, name: &str) -> Option<&'a str> {
+    headers
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+}
+
+/// The header fields of a request, skipping the request line.
+fn header_fields(headers: &str) -> Vec<(String, String)> {
+    headers
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            Some((key.to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// The query string of the request target, if any.
+fn request_query(request_line: &str) -> Option<&str> {
+    let target = request_line.split_whitespace().nth(1)?;
+    target.split_once('?').map(|(_, query)| query)
+}
+
+pub(crate) fn write_response(
+    stream: &mut impl Write,
+    version: HttpVersion,
+    response: &[u8],
+) -> std::io::Result<()> {
+    debug_assert!(response.starts_with(STATUS_LINE_VERSION));
+    stream.write_all(version.as_bytes())?;
+    stream.write_all(&response[STATUS_LINE_VERSION.len()..])
+}
+
+/// Writes a handler's response with the same framing and security headers as
+/// the prebuilt responses. The body is left out for `HEAD` requests.
+fn write_handler_response(
+    stream: &mut impl Write,
+    version: HttpVersion,
+    method: &str,
+    response: &Response,
+) -> std::io::Result<()> {
+    let status = response.status();
+    let mut head = Vec::with_capacity(256);
+    head.extend_from_slice(version.as_bytes());
+    write!(
+        head,
+        " {} {}\r\nConnection: close\r\n",
+        status,
+        reason_phrase(status)
+    )?;
+    for (name, value) in response.headers_to_send() {
+        write!(head, "{}: {}\r\n", name, value)?;
+    }
+    head.extend_from_slice(b"X-Content-Type-Options: nosniff\r\nX-Frame-Options: DENY\r\n");
+    // A 304 has no body, and its Content-Length would describe the one it
+    // stands in for.
+    if status != 304 {
+        write!(head, "Content-Length: {}\r\n", response.body_len())?;
+    }
+    head.extend_from_slice(b"\r\n");
+    stream.write_all(&head)?;
+    if method != "HEAD" {
+        let sent = match response.fault() {
+            Some(ConnectionFault::Truncate) => response.body_len() / 2,
+            _ => response.body_len(),
+        };
+        response.write_body(sent, |chunk, _| stream.write_all(chunk))?;
+    }
+    Ok(())
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        418 => "I'm a teapot",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+/// Reads up to the blank line ending the header block. Returns the header
+/// block and any bytes read past it, which belong to the body.
+fn read_headers(stream: &mut impl Read) -> std::io::Result<(String, Vec<u8>)> {
+    let mut buffer = [0u8; MAX_HEADER_SIZE];
+    let mut total_read = 0;
+    let mut temp = [0u8; 512];
+
+    let start_time = Instant::now();
+    let deadline = Duration::from_secs(5);
+
+    loop {
+        if start_time.elapsed() > deadline {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Header read timeout",
+            ));
+        }
+
+        let n = stream.read(&mut temp)?;
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Connection closed before full header was received",
+            ));
+        }
+        if total_read + n > MAX_HEADER_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Header too large",
+            ));
+        }
+        buffer[total_read..total_read + n].copy_from_slice(&temp[..n]);
+        total_read += n;
+
+        let start = total_read.saturating_sub(n + 3);
+        if let Some(pos) = buffer[start..total_read]
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+        {
+            let end = start + pos + 4;
+            let headers = String::from_utf8_lossy(&buffer[..end]).to_string();
+            return Ok((headers, buffer[end..total_read].to_vec()));
+        }
+    }
+}
+
+fn read_body(
+    stream: &mut impl Read,
+    mut remaining: usize,
+    deadline: Instant,
+) -> std::io::Result<Vec<u8>> {
+    if remaining > MAX_BODY_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Body too large",
+        ));
+    }
+    let mut body = Vec::with_capacity(remaining);
+    let mut buf = [0u8; 4096];
+    while remaining > 0 {
+        // The socket read timeout bounds a single blocking read; this deadline enforces a
+        // total time budget across the full body.
+        if Instant::now() >= deadline {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Body read timeout",
+            ));
+        }
+        let to_read = std::cmp::min(buf.len(), remaining);
+        let n = stream.read(&mut buf[..to_read])?;
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Connection closed before full body was received",
+            ));
+        }
+        body.extend_from_slice(&buf[..n]);
+        remaining -= n;
+    }
+    Ok(body)
+}
+
+/// Reads a response to a `GET` with the same limits as a request, returning
+/// its status, its head and its body. Without `Content-Length` the body runs
+/// to the end of the connection; chunked bodies are rejected.
+pub(crate) fn read_response(stream: &mut impl Read) -> std::io::Result<(u16, String, Vec<u8>)> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let (head, mut body) = read_headers(stream)?;
+    let mut status_line = head.lines().next().unwrap_or("").split(' ');
+    let version = status_line.next().unwrap_or("");
+    if version.is_empty() || parse_http_version(version).is_err() {
+        return Err(invalid("Malformed status line"));
+    }
+    let status = match status_line.next().map(str::parse::<u16>) {
+        Some(Ok(status)) if (100..=599).contains(&status) => status,
+        _ => return Err(invalid("Malformed status line")),
+    };
+    if header_value(&head, "transfer-encoding").is_some() {
+        return Err(invalid("Chunked responses are not supported"));
+    }
+    if status == 204 || status == 304 || (100..200).contains(&status) {
+        body.clear();
+        return Ok((status, head, body));
+    }
+    match header_value(&head, "content-length") {
+        Some(length) => {
+            let length: usize = length
+                .parse()
+                .map_err(|_| invalid("Invalid Content-Length"))?;
+            body.truncate(length);
+            let deadline = Instant::now() + body_timeout_duration();
+            body.extend(read_body(stream, length - body.len(), deadline)?);
+        }
+        None => {
+            stream
+                .take((MAX_BODY_SIZE - body.len().min(MAX_BODY_SIZE)) as u64 + 1)
+                .read_to_end(&mut body)?;
+            if body.len() > MAX_BODY_SIZE {
+                return Err(invalid("Body too large"));
+            }
+        }
+    }
+    Ok((status, head, body))
+}
+
+fn get_client_address(stream: &impl Transport, headers: &str) -> String {
+    for line in headers.lines() {
+        if let Some(val) = line
+            .strip_prefix("X-Forwarded-For:")
+            .or_else(|| line.strip_prefix("x-forwarded-for:"))
+        {
+            return sanitize(val.trim().split(',').next().unwrap_or(""));
+        }
+    }
+    stream
+        .socket()
+        .peer_addr()
+        .map(|a| sanitize(&a.to_string()))
+        .unwrap_or_else(|_| "unknown".into())
+}
+
+pub(crate) fn handle_connection(mut stream: impl Transport, state: &ServerState) {
+    let timeout = Duration::from_secs(5);
+    stream.socket().set_read_timeout(Some(timeout)).ok();
+    stream.socket().set_write_timeout(Some(timeout)).ok();
+
+    if stream.is_http2() {
+        http2::serve_connection(stream, state);
+        return;
+    }
+
+    let (headers, body_prefix) = match read_headers(&mut stream) {
+        Ok(h) => h,
+        Err(e) => match e.kind() {
+            std::io::ErrorKind::TimedOut => {
+                let _ = stream.write_all(RESPONSE_408);
+                return;
+            }
+            std::io::ErrorKind::InvalidData => {
+                let _ = stream.write_all(RESPONSE_431);
+                return;
+            }
+            _ => return,
+        },
+    };
+
+    let request_line = headers.lines().next().unwrap_or("");
+    let (method, path, version) = parse_request_line(request_line);
+    let version = match parse_http_version(version) {
+        Ok(v) => v,
+        Err(response) => {
+            let _ = stream.write_all(response);
+            return;
+        }
+    };
+    if state.require_host
+        && version == HttpVersion::Http11
+        && header_value(&headers, "host").is_none()
+    {
+        let _ = write_response(&mut stream, version, RESPONSE_400);
+        return;
+    }
+
+    for line in headers.lines() {
+        let lower = line.to_ascii_lowercase();
+        if lower.starts_with("transfer-encoding:") && lower.contains("chunked") {
+            let _ = write_response(&mut stream, version, RESPONSE_501);
+            return;
+        }
+    }
+
+    let mut content_length = 0;
+    for line in headers.lines() {
+        if line.len() >= 15 && line[..15].eq_ignore_ascii_case("content-length:") {
+            let val = &line[15..];
+            if content_length != 0 {
+                let _ = write_response(&mut stream, version, RESPONSE_431);
+                return;
+            }
+            match val.trim().parse::<usize>() {
+                Ok(len) => {
+                    if len > MAX_BODY_SIZE {
+                        let _ = write_response(&mut stream, version, RESPONSE_413);
+                        return;
+                    }
+                    content_length = len;
+                }
+                Err(_) => {
+                    let _ = write_response(&mut stream, version, RESPONSE_431);
+                    return;
+                }
+            }
+        }
+    }
+
+    let mut body = body_prefix;
+    body.truncate(content_length);
+    if body.len() < content_length {
+        let body_deadline = Instant::now() + body_timeout_duration();
+        match read_body(&mut stream, content_length - body.len(), body_deadline) {
+            Ok(rest) => body.extend_from_slice(&rest),
+            Err(e) => {
+                match e.kind() {
+                    std::io::ErrorKind::InvalidData => {
+                        let _ = write_response(&mut stream, version, RESPONSE_413);
+                    }
+                    std::io::ErrorKind::TimedOut => {
+                        let _ = write_response(&mut stream, version, RESPONSE_408);
+                    }
+                    _ => {}
+                }
+                return;
+            }
+        }
+    }
+
+    let request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: request_query(request_line).map(str::to_string),
+        version: match version {
+            HttpVersion::Http10 => "HTTP/1.0",
+            HttpVersion::Http11 => "HTTP/1.1",
+        },
+        headers: header_fields(&headers),
+        body,
+        peer: get_client_address(&stream, &headers),
+        request_line: request_line.to_string(),
+        received_bytes: headers.len().saturating_add(content_length),
+    };
+    let response = state.handler.handle(&request);
+    if response.fault() == Some(ConnectionFault::Reset) {
+        let _ = stream.socket().shutdown(Shutdown::Both);
+        return;
+    }
+    let _ = write_handler_response(&mut stream, version, method, &response);
+    let _ = stream.flush();
+    if response.fault() == Some(ConnectionFault::Truncate) {
+        let _ = stream.socket().shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn parses_request_line_without_query() {
+        let (method, path, version) = parse_request_line("GET / HTTP/1.1");
+        assert_eq!(method, "GET");
+        assert_eq!(path, "/");
+        assert_eq!(version, "HTTP/1.1");
+    }
+
+    #[test]
+    fn strips_query_from_path() {
+        let (method, path, _) = parse_request_line("GET /?foo=bar HTTP/1.1");
+        assert_eq!(method, "GET");
+        assert_eq!(path, "/");
+    }
+
+    #[test]
+    fn handles_root_without_http_version() {
+        let (method, path, version) = parse_request_line("GET /");
+        assert_eq!(method, "GET");
+        assert_eq!(path, "/");
+        assert_eq!(version, "");
+    }
+
+    #[test]
+    fn handles_missing_http_version() {
+        let (method, path, _) = parse_request_line("GET /foo");
+        assert_eq!(method, "GET");
+        assert_eq!(path, "/foo");
+    }
+
+    #[test]
+    fn strips_query_with_http_version_and_path() {
+        let (method, path, _) = parse_request_line("GET /foo?bar=baz HTTP/1.1");
+        assert_eq!(method, "GET");
+        assert_eq!(path, "/foo");
+    }
+
+    #[test]
+    fn strips_multiple_query_params() {
+        let (method, path, _) = parse_request_line("GET /?foo=bar&baz=qux HTTP/1.1");
+        assert_eq!(method, "GET");
+        assert_eq!(path, "/");
+    }
+
+    #[test]
+    fn handles_empty_query_string() {
+        let (method, path, _) = parse_request_line("GET /? HTTP/1.1");
+        assert_eq!(method, "GET");
+        assert_eq!(path, "/");
+    }
+
+    #[test]
+    fn preserves_fragment_in_path() {
+        let (method, path, _) = parse_request_line("GET /#section HTTP/1.1");
+        assert_eq!(method, "GET");
+        assert_eq!(path, "/#section");
+    }
+
+    #[test]
+    fn handles_empty_request_line() {
+        let (method, path, version) = parse_request_line("");
+        assert_eq!(method, "");
+        assert_eq!(path, "");
+        assert_eq!(version, "");
+    }
+
+    #[test]
+    fn parses_supported_http_versions() {
+        assert_eq!(parse_http_version("HTTP/1.0"), Ok(HttpVersion::Http10));
+        assert_eq!(parse_http_version("HTTP/1.1"), Ok(HttpVersion::Http11));
+        assert_eq!(parse_http_version("HTTP/1.2"), Ok(HttpVersion::Http11));
+        assert_eq!(parse_http_version(""), Ok(HttpVersion::Http10));
+    }
+
+    #[test]
+    fn rejects_unsupported_http_versions_with_505() {
+        assert_eq!(parse_http_version("HTTP/2.0"), Err(RESPONSE_505));
+        assert_eq!(parse_http_version("HTTP/3"), Err(RESPONSE_505));
+        assert_eq!(parse_http_version("HTTP/0.9"), Err(RESPONSE_505));
+    }
+
+    #[test]
+    fn rejects_malformed_http_versions_with_400() {
+        assert_eq!(parse_http_version("FTP/1.1"), Err(RESPONSE_400));
+        assert_eq!(parse_http_version("HTTP/1.x"), Err(RESPONSE_400));
+        assert_eq!(parse_http_version("HTTP/"), Err(RESPONSE_400));
+    }
+
+    #[test]
+    fn tolerates_extra_spaces_after_method() {
+        let (method, path, _) = parse_request_line("GET  /foo HTTP/1.1");
+        assert_eq!(method, "GET");
+        assert_eq!(path, "/foo");
+    }
+
+    #[test]
+    fn read_body_times_out_when_deadline_passed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .ok();
+            let deadline = Instant::now() - Duration::from_millis(1);
+            let err =
+                read_body(&mut stream, 1, deadline).expect_err("expected read_body to time out");
+            (err.kind(), err.to_string())
+        });
+        let _client = TcpStream::connect(addr).unwrap();
+        let (kind, msg) = server.join().unwrap();
+        assert_eq!(kind, std::io::ErrorKind::TimedOut);
+        assert!(
+            msg.contains("Body read timeout"),
+            "expected body timeout message, got: {}",
+            msg
+        );
+    }
+
+    #[test]
+    fn read_body_succeeds_before_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let deadline = Instant::now() + Duration::from_secs(1);
+            assert_eq!(read_body(&mut stream, 4, deadline).unwrap(), b"test");
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"test").unwrap();
+        server.join().unwrap();
+    }
+
+    fn run_request(raw: &str) -> Vec<u8> {
+        run_request_with_host_check(raw, true)
+    }
+
+    fn run_request_with_host_check(raw: &str, require_host: bool) -> Vec<u8> {
+        use std::net::Shutdown;
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind test listener");
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                let state = ServerState {
+                    require_host,
+                    ..ServerState::default()
+                };
+                handle_connection(stream, &state);
+            }
+        });
+
+        let mut client = TcpStream::connect(addr).expect("connect to test listener");
+        client
+            .write_all(raw.as_bytes())
+            .expect("write request to server");
+        let _ = client.shutdown(Shutdown::Write);
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).expect("read response");
+        let _ = server.join();
+        buf
+    }
+
+    #[test]
+    fn rejects_oversized_content_length_with_413() {
+        let request = format!(
+            "GET / HTTP/1.1\r\nHost: example\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        let response_bytes = run_request(&request);
+        let response = String::from_utf8_lossy(&response_bytes);
+        assert!(
+            response.starts_with("HTTP/1.1 413"),
+            "unexpected response: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn accepts_valid_content_length() {
+        let request = "GET / HTTP/1.1\r\nHost: example\r\nContent-Length: 5\r\n\r\nhello";
+        let response_bytes = run_request(request);
+        let response = String::from_utf8_lossy(&response_bytes);
+        assert!(
+            response.starts_with("HTTP/1.1 200"),
+            "unexpected response: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn rejects_chunked_requests_with_501() {
+        let request = "GET / HTTP/1.1\r\nHost: example\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let response_bytes = run_request(request);
+        let response = String::from_utf8_lossy(&response_bytes);
+        assert!(
+            response.starts_with("HTTP/1.1 501"),
+            "unexpected response: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn answers_http10_requests_with_http10_status_line() {
+        let response_bytes = run_request("GET / HTTP/1.0\r\n\r\n");
+        let response = String::from_utf8_lossy(&response_bytes);
+        assert!(
+            response.starts_with("HTTP/1.0 200 OK\r\n"),
+            "unexpected response: {}",
+            response
+        );
+        assert!(response.ends_with("\r\n\r\nOK"));
+    }
+
+    #[test]
+    fn rejects_http2_request_line_with_505() {
+        let response_bytes = run_request("GET / HTTP/2.0\r\nHost: example\r\n\r\n");
+        let response = String::from_utf8_lossy(&response_bytes);
+        assert!(
+            response.starts_with("HTTP/1.1 505"),
+            "unexpected response: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn rejects_http11_request_without_host_with_400() {
+        let response_bytes = run_request("GET / HTTP/1.1\r\n\r\n");
+        let response = String::from_utf8_lossy(&response_bytes);
+        assert!(
+            response.starts_with("HTTP/1.1 400"),
+            "unexpected response: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn accepts_http11_request_without_host_when_not_required() {
+        let response_bytes = run_request_with_host_check("GET / HTTP/1.1\r\n\r\n", false);
+        let response = String::from_utf8_lossy(&response_bytes);
+        assert!(
+            response.starts_with("HTTP/1.1 200"),
+            "unexpected response: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn writes_built_in_ok_response_unchanged() {
+        let response = run_request("GET / HTTP/1.1\r\nHost: example\r\n\r\n");
+        let expected: &[u8] = b"HTTP/1.1 200 OK\r\n\
+Connection: close\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+ETag: \"091d3d07b5b3076f\"\r\n\
+X-Content-Type-Options: nosniff\r\n\
+X-Frame-Options: DENY\r\n\
+Content-Length: 2\r\n\r\nOK";
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn answers_conditional_requests_with_304() {
+        let response = run_request(
+            "GET / HTTP/1.1\r\nHost: example\r\nIf-None-Match: \"091d3d07b5b3076f\"\r\n\r\n",
+        );
+        let expected: &[u8] = b"HTTP/1.1 304 Not Modified\r\n\
+Connection: close\r\n\
+ETag: \"091d3d07b5b3076f\"\r\n\
+X-Content-Type-Options: nosniff\r\n\
+X-Frame-Options: DENY\r\n\r\n";
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn head_responses_report_length_without_body() {
+        let mut out = Vec::new();
+        let response = Response::text("hello").with_header("Cache-Control", "no-store");
+        write_handler_response(&mut out, HttpVersion::Http10, "HEAD", &response).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.0 200 OK\r\n"), "{}", out);
+        assert!(out.contains("Cache-Control: no-store\r\n"));
+        assert!(out.ends_with("Content-Length: 5\r\n\r\n"));
+    }
+
+    #[test]
+    fn extracts_query_and_header_fields() {
+        assert_eq!(request_query("GET /a?b=c HTTP/1.1"), Some("b=c"));
+        assert_eq!(request_query("GET /a HTTP/1.1"), None);
+        let fields = header_fields("GET / HTTP/1.1\r\nHost: x\r\nX-A:  b \r\n\r\n");
+        assert_eq!(
+            fields,
+            [
+                ("Host".to_string(), "x".to_string()),
+                ("X-A".into(), "b".into())
+            ]
+        );
+    }
+}
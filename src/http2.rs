@@ -0,0 +1,1087 @@
+//! Minimal HTTP/2 (RFC 9113) support for cleartext connections that start with
+//! the client preface ("prior knowledge" h2c).
+//!
+//! Streams are answered one at a time, as soon as the client finishes sending
+//! them, using the same routes as the HTTP/1 path. There is no server push and
+//! no prioritisation; flow control is honoured in both directions.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::grpc;
+use crate::handler::{ConnectionFault, Handler, Request, Response};
+use crate::hpack::{self, Decoder};
+use crate::server::ServerState;
+use crate::{sanitize, Transport, MAX_BODY_SIZE, MAX_HEADER_SIZE};
+
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+/// Largest frame we accept; we never raise SETTINGS_MAX_FRAME_SIZE above the default.
+const MAX_FRAME_SIZE: usize = 16_384;
+const MAX_PEER_FRAME_SIZE: usize = (1 << 24) - 1;
+const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+const MAX_CONCURRENT_STREAMS: usize = 100;
+/// Upper bound on a compressed header block, including CONTINUATION frames.
+const MAX_HEADER_BLOCK_SIZE: usize = 4 * MAX_HEADER_SIZE;
+/// Frames buffered while a response waits for flow-control credit.
+const MAX_PENDING_FRAMES: usize = 256;
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Reports whether the client opened the connection with the HTTP/2 preface,
+/// without consuming any bytes.
+pub(crate) fn starts_with_preface(stream: &TcpStream) -> bool {
+    let mut buf = [0u8; PREFACE.len()];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let n = match stream.peek(&mut buf) {
+            Ok(n) => n,
+            Err(_) => return false,
+        };
+        if n == 0 || buf[..n] != PREFACE[..n] {
+            return false;
+        }
+        if n == PREFACE.len() {
+            return true;
+        }
+        // A partial match: wait for the rest of the preface to arrive.
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Serves an HTTP/2 connection until the client goes away, an error occurs or
+/// the socket read timeout expires, then sends GOAWAY.
+pub(crate) fn serve_connection<S: Transport>(stream: S, state: &ServerState) {
+    let mut conn = Connection::new(stream, state);
+    let code = match conn.run() {
+        Ok(()) | Err(Failure::Io) => NO_ERROR,
+        Err(Failure::Connection(code)) => code,
+    };
+    let _ = conn.write_goaway(code);
+    let _ = conn.stream.flush();
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+enum Failure {
+    /// The socket failed, timed out or was closed by the client.
+    Io,
+    /// A connection error; the code is sent in GOAWAY.
+    Connection(u32),
+}
+
+impl From<std::io::Error> for Failure {
+    fn from(_: std::io::Error) -> Self {
+        Failure::Io
+    }
+}
+
+#[derive(Default)]
+struct StreamState {
+    headers: Vec<(String, String)>,
+    header_bytes: usize,
+    header_list_size: usize,
+    body_len: usize,
+    /// Request body, kept while it stays within `MAX_BODY_SIZE`.
+    body: Vec<u8>,
+    grpc: bool,
+    send_window: i64,
+    /// Set once the client has sent END_STREAM and the response is being written.
+    responding: bool,
+}
+
+struct Connection<'a, S> {
+    stream: S,
+    state: &'a ServerState,
+    decoder: Decoder,
+    streams: HashMap<u32, StreamState>,
+    pending: VecDeque<Frame>,
+    last_stream_id: u32,
+    send_window: i64,
+    peer_initial_window: i64,
+    peer_max_frame_size: usize,
+}
+
+impl<'a, S: Transport> Connection<'a, S> {
+    fn new(stream: S, state: &'a ServerState) -> Self {
+        Connection {
+            stream,
+            state,
+            decoder: Decoder::new(hpack::DEFAULT_TABLE_SIZE),
+            streams: HashMap::new(),
+            pending: VecDeque::new(),
+            last_stream_id: 0,
+            send_window: DEFAULT_WINDOW_SIZE,
+            peer_initial_window: DEFAULT_WINDOW_SIZE,
+            peer_max_frame_size: MAX_FRAME_SIZE,
+        }
+    }
+
+    fn run(&mut self) -> Result<(), Failure> {
+        let mut preface = [0u8; PREFACE.len()];
+        self.stream.read_exact(&mut preface)?;
+        if preface != PREFACE {
+            return Err(Failure::Connection(PROTOCOL_ERROR));
+        }
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (SETTINGS_ENABLE_PUSH, 0),
+            (
+                SETTINGS_MAX_CONCURRENT_STREAMS,
+                MAX_CONCURRENT_STREAMS as u32,
+            ),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEADER_SIZE as u32),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        self.write_frame(SETTINGS, 0, 0, &settings)?;
+
+        let first = self.next_frame()?;
+        if first.kind != SETTINGS || first.flags & FLAG_ACK != 0 {
+            return Err(Failure::Connection(PROTOCOL_ERROR));
+        }
+        self.handle_frame(first)?;
+        loop {
+            let frame = self.next_frame()?;
+            if frame.kind == GOAWAY {
+                return Ok(());
+            }
+            self.handle_frame(frame)?;
+        }
+    }
+
+    fn next_frame(&mut self) -> Result<Frame, Failure> {
+        match self.pending.pop_front() {
+            Some(frame) => Ok(frame),
+            None => self.read_frame(),
+        }
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, Failure> {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        self.stream.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(Failure::Connection(FRAME_SIZE_ERROR));
+        }
+        let mut payload = vec![0u8; len];
+        self.stream.read_exact(&mut payload)?;
+        Ok(Frame {
+            kind: header[3],
+            flags: header[4],
+            stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]])
+                & 0x7fff_ffff,
+            payload,
+        })
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), Failure> {
+        match frame.kind {
+            DATA => self.handle_data(frame),
+            HEADERS => self.handle_headers(frame),
+            PRIORITY => Ok(()),
+            RST_STREAM => {
+                if frame.stream_id == 0 || frame.payload.len() != 4 {
+                    return Err(Failure::Connection(PROTOCOL_ERROR));
+                }
+                self.streams.remove(&frame.stream_id);
+                Ok(())
+            }
+            SETTINGS => self.handle_settings(frame),
+            PING => {
+                if frame.stream_id != 0 {
+                    return Err(Failure::Connection(PROTOCOL_ERROR));
+                }
+                if frame.payload.len() != 8 {
+                    return Err(Failure::Connection(FRAME_SIZE_ERROR));
+                }
+                if frame.flags & FLAG_ACK == 0 {
+                    self.write_frame(PING, FLAG_ACK, 0, &frame.payload)?;
+                }
+                Ok(())
+            }
+            WINDOW_UPDATE => self.handle_window_update(frame),
+            PUSH_PROMISE | CONTINUATION => Err(Failure::Connection(PROTOCOL_ERROR)),
+            // GOAWAY is handled by the caller; unknown frame types must be ignored.
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_settings(&mut self, frame: Frame) -> Result<(), Failure> {
+        if frame.stream_id != 0 {
+            return Err(Failure::Connection(PROTOCOL_ERROR));
+        }
+        if frame.flags & FLAG_ACK != 0 {
+            return if frame.payload.is_empty() {
+                Ok(())
+            } else {
+                Err(Failure::Connection(FRAME_SIZE_ERROR))
+            };
+        }
+        if !frame.payload.chunks_exact(6).remainder().is_empty() {
+            return Err(Failure::Connection(FRAME_SIZE_ERROR));
+        }
+        for setting in frame.payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(Failure::Connection(PROTOCOL_ERROR));
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW_SIZE {
+                        return Err(Failure::Connection(FLOW_CONTROL_ERROR));
+                    }
+                    let delta = value - self.peer_initial_window;
+                    for state in self.streams.values_mut() {
+                        state.send_window += delta;
+                        if state.send_window > MAX_WINDOW_SIZE {
+                            return Err(Failure::Connection(FLOW_CONTROL_ERROR));
+                        }
+                    }
+                    self.peer_initial_window = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    let value = value as usize;
+                    if !(MAX_FRAME_SIZE..=MAX_PEER_FRAME_SIZE).contains(&value) {
+                        return Err(Failure::Connection(PROTOCOL_ERROR));
+                    }
+                    self.peer_max_frame_size = value;
+                }
+                // We never index response headers, so the peer's table size is irrelevant.
+                SETTINGS_HEADER_TABLE_SIZE => {}
+                _ => {}
+            }
+        }
+        self.write_frame(SETTINGS, FLAG_ACK, 0, &[])?;
+        Ok(())
+    }
+
+    fn handle_window_update(&mut self, frame: Frame) -> Result<(), Failure> {
+        if frame.payload.len() != 4 {
+            return Err(Failure::Connection(FRAME_SIZE_ERROR));
+        }
+        let increment = (u32::from_be_bytes([
+            frame.payload[0],
+            frame.payload[1],
+            frame.payload[2],
+            frame.payload[3],
+        ]) & 0x7fff_ffff) as i64;
+        if frame.stream_id == 0 {
+            if increment == 0 {
+                return Err(Failure::Connection(PROTOCOL_ERROR));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW_SIZE {
+                return Err(Failure::Connection(FLOW_CONTROL_ERROR));
+            }
+            return Ok(());
+        }
+        let Some(state) = self.streams.get_mut(&frame.stream_id) else {
+            return Ok(());
+        };
+        if increment == 0 {
+            self.streams.remove(&frame.stream_id);
+            return self.write_rst_stream(frame.stream_id, PROTOCOL_ERROR);
+        }
+        state.send_window += increment;
+        if state.send_window > MAX_WINDOW_SIZE {
+            self.streams.remove(&frame.stream_id);
+            return self.write_rst_stream(frame.stream_id, FLOW_CONTROL_ERROR);
+        }
+        Ok(())
+    }
+
+    fn handle_data(&mut self, frame: Frame) -> Result<(), Failure> {
+        if frame.stream_id == 0 {
+            return Err(Failure::Connection(PROTOCOL_ERROR));
+        }
+        if frame.stream_id > self.last_stream_id {
+            return Err(Failure::Connection(PROTOCOL_ERROR));
+        }
+        let data = strip_padding(&frame)?;
+        // Flow control covers the whole payload, padding included; hand it straight back.
+        if !frame.payload.is_empty() {
+            let increment = (frame.payload.len() as u32).to_be_bytes();
+            self.write_frame(WINDOW_UPDATE, 0, 0, &increment)?;
+        }
+        let end_stream = frame.flags & FLAG_END_STREAM != 0;
+        match self.streams.get_mut(&frame.stream_id) {
+            Some(state) if !state.responding => {
+                state.body_len = state.body_len.saturating_add(data.len());
+                if state.body_len <= MAX_BODY_SIZE {
+                    state.body.extend_from_slice(data);
+                }
+                if !end_stream && !frame.payload.is_empty() {
+                    let increment = (frame.payload.len() as u32).to_be_bytes();
+                    self.write_frame(WINDOW_UPDATE, 0, frame.stream_id, &increment)?;
+                }
+            }
+            _ => return self.write_rst_stream(frame.stream_id, STREAM_CLOSED),
+        }
+        if end_stream {
+            self.respond(frame.stream_id)?;
+        }
+        Ok(())
+    }
+
+    fn handle_headers(&mut self, frame: Frame) -> Result<(), Failure> {
+        let stream_id = frame.stream_id;
+        if stream_id == 0 {
+            return Err(Failure::Connection(PROTOCOL_ERROR));
+        }
+        let end_stream = frame.flags & FLAG_END_STREAM != 0;
+        let mut fragment = strip_padding(&frame)?;
+        if frame.flags & FLAG_PRIORITY != 0 {
+            fragment = fragment
+                .get(5..)
+                .ok_or(Failure::Connection(PROTOCOL_ERROR))?;
+        }
+        let mut block = fragment.to_vec();
+        let mut end_headers = frame.flags & FLAG_END_HEADERS != 0;
+        while !end_headers {
+            let next = self.next_frame()?;
+            if next.kind != CONTINUATION || next.stream_id != stream_id {
+                return Err(Failure::Connection(PROTOCOL_ERROR));
+            }
+            if block.len() + next.payload.len() > MAX_HEADER_BLOCK_SIZE {
+                return Err(Failure::Connection(ENHANCE_YOUR_CALM));
+            }
+            block.extend_from_slice(&next.payload);
+            end_headers = next.flags & FLAG_END_HEADERS != 0;
+        }
+        // The block must be decoded even when the stream is refused, to keep
+        // the HPACK dynamic table in sync with the client.
+        let (headers, header_list_size) = self
+            .decoder
+            .decode(&block, MAX_HEADER_SIZE)
+            .map_err(|_| Failure::Connection(COMPRESSION_ERROR))?;
+
+        if let Some(state) = self.streams.get(&stream_id) {
+            // Trailers: they must end the stream and are otherwise ignored.
+            if state.responding || !end_stream {
+                return Err(Failure::Connection(PROTOCOL_ERROR));
+            }
+            return self.respond(stream_id);
+        }
+        if stream_id & 1 == 0 || stream_id <= self.last_stream_id {
+            return Err(Failure::Connection(PROTOCOL_ERROR));
+        }
+        self.last_stream_id = stream_id;
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS {
+            return self.write_rst_stream(stream_id, REFUSED_STREAM);
+        }
+        let grpc = headers
+            .iter()
+            .any(|(name, value)| name == "content-type" && grpc::is_grpc_content_type(value));
+        self.streams.insert(
+            stream_id,
+            StreamState {
+                headers,
+                grpc,
+                header_bytes: block.len(),
+                header_list_size,
+                send_window: self.peer_initial_window,
+                ..StreamState::default()
+            },
+        );
+        if end_stream {
+            self.respond(stream_id)?;
+        }
+        Ok(())
+    }
+
+    fn respond(&mut self, stream_id: u32) -> Result<(), Failure> {
+        let Some(state) = self.streams.get_mut(&stream_id) else {
+            return Ok(());
+        };
+        state.responding = true;
+        let pseudo = |name: &str| {
+            state
+                .headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        let (Some(method), Some(path)) = (pseudo(":method"), pseudo(":path")) else {
+            self.streams.remove(&stream_id);
+            return self.write_rst_stream(stream_id, PROTOCOL_ERROR);
+        };
+        let method = method.to_string();
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (path.to_string(), None),
+        };
+
+        let peer = state
+            .headers
+            .iter()
+            .find(|(n, _)| n == "x-forwarded-for")
+            .map(|(_, v)| sanitize(v.trim().split(',').next().unwrap_or("")))
+            .or_else(|| {
+                self.stream
+                    .socket()
+                    .peer_addr()
+                    .ok()
+                    .map(|a| sanitize(&a.to_string()))
+            })
+            .unwrap_or_else(|| "unknown".into());
+
+        let within_limits =
+            state.header_list_size <= MAX_HEADER_SIZE && state.body_len <= MAX_BODY_SIZE;
+        if !within_limits {
+            let status = if state.header_list_size > MAX_HEADER_SIZE {
+                431
+            } else {
+                413
+            };
+            let mut block = Vec::new();
+            hpack::encode_status(status, &mut block);
+            encode_security_headers(Some(0), &mut block);
+            self.write_headers(stream_id, &block, true)?;
+            self.streams.remove(&stream_id);
+            return Ok(());
+        }
+
+        let grpc = state.grpc;
+        let request = Request {
+            request_line: format!("{} {} HTTP/2.0", method, path),
+            received_bytes: state.header_bytes.saturating_add(state.body_len),
+            headers: std::mem::take(&mut state.headers)
+                .into_iter()
+                .filter(|(name, _)| !name.starts_with(':'))
+                .collect(),
+            body: std::mem::take(&mut state.body),
+            method,
+            path,
+            query,
+            version: "HTTP/2.0",
+            peer,
+        };
+        let response = self.state.handler.handle(&request);
+        if response.fault() == Some(ConnectionFault::Reset) {
+            self.streams.remove(&stream_id);
+            return self.write_rst_stream(stream_id, INTERNAL_ERROR);
+        }
+        if grpc && response.header("grpc-status").is_some() {
+            return self.write_grpc_response(stream_id, &response);
+        }
+
+        let mut block = Vec::new();
+        hpack::encode_status(response.status(), &mut block);
+        for (name, value) in response.headers_to_send() {
+            hpack::encode_header(&name.to_ascii_lowercase(), value, &mut block);
+        }
+        // A 304's Content-Length would describe the body it stands in for.
+        let content_length = (response.status() != 304).then(|| response.body_len());
+        encode_security_headers(content_length, &mut block);
+
+        let body_len = if request.method() == "HEAD" {
+            0
+        } else {
+            response.body_len()
+        };
+        if response.fault() == Some(ConnectionFault::Truncate) {
+            self.write_headers(stream_id, &block, false)?;
+            response.write_body(body_len / 2, |chunk, _| {
+                self.write_data(stream_id, chunk, false)
+            })?;
+            self.streams.remove(&stream_id);
+            return self.write_rst_stream(stream_id, INTERNAL_ERROR);
+        }
+        self.write_headers(stream_id, &block, body_len == 0)?;
+        response.write_body(body_len, |chunk, last| {
+            self.write_data(stream_id, chunk, last)
+        })?;
+        self.streams.remove(&stream_id);
+        Ok(())
+    }
+
+    /// Sends the answer to a gRPC call: its `grpc-*` headers as trailers after
+    /// the message, or in the only header block when there is no message.
+    fn write_grpc_response(&mut self, stream_id: u32, response: &Response) -> Result<(), Failure> {
+        let mut block = Vec::new();
+        hpack::encode_status(response.status(), &mut block);
+        let mut trailers = Vec::new();
+        for (name, value) in response.headers_to_send() {
+            let name = name.to_ascii_lowercase();
+            let out = if name.starts_with("grpc-") {
+                &mut trailers
+            } else {
+                &mut block
+            };
+            hpack::encode_header(&name, value, out);
+        }
+        if response.body().is_empty() {
+            block.extend_from_slice(&trailers);
+            self.write_headers(stream_id, &block, true)?;
+        } else {
+            self.write_headers(stream_id, &block, false)?;
+            self.write_data(stream_id, response.body(), false)?;
+            if self.streams.contains_key(&stream_id) {
+                self.write_headers(stream_id, &trailers, true)?;
+            }
+        }
+        self.streams.remove(&stream_id);
+        Ok(())
+    }
+
+    fn write_headers(
+        &mut self,
+        stream_id: u32,
+        block: &[u8],
+        end_stream: bool,
+    ) -> Result<(), Failure> {
+        let mut chunks = block.chunks(self.peer_max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_none() {
+                flags |= FLAG_END_HEADERS;
+            }
+            self.write_frame(kind, flags, stream_id, chunk)?;
+            kind = CONTINUATION;
+            flags = 0;
+        }
+        Ok(())
+    }
+
+    /// Sends `body` as DATA frames while staying within the peer's flow-control
+    /// windows, setting END_STREAM on the last frame if `end_stream` is set.
+    fn write_data(
+        &mut self,
+        stream_id: u32,
+        mut body: &[u8],
+        end_stream: bool,
+    ) -> Result<(), Failure> {
+        while !body.is_empty() {
+            let Some(stream_window) = self.streams.get(&stream_id).map(|s| s.send_window) else {
+                // Reset by the client while we were waiting for credit.
+                return Ok(());
+            };
+            let available = self.send_window.min(stream_window);
+            if available <= 0 {
+                self.wait_for_window()?;
+                continue;
+            }
+            let len = body
+                .len()
+                .min(available as usize)
+                .min(self.peer_max_frame_size);
+            let flags = if end_stream && len == body.len() {
+                FLAG_END_STREAM
+            } else {
+                0
+            };
+            self.write_frame(DATA, flags, stream_id, &body[..len])?;
+            self.send_window -= len as i64;
+            if let Some(state) = self.streams.get_mut(&stream_id) {
+                state.send_window -= len as i64;
+            }
+            body = &body[len..];
+        }
+        Ok(())
+    }
+
+    /// Reads one frame while a response is blocked on flow control. Frames
+    /// that could change the windows are handled now; the rest are queued.
+    fn wait_for_window(&mut self) -> Result<(), Failure> {
+        let frame = self.read_frame()?;
+        match frame.kind {
+            WINDOW_UPDATE | SETTINGS | PING | RST_STREAM | PRIORITY => self.handle_frame(frame),
+            GOAWAY => Err(Failure::Connection(NO_ERROR)),
+            _ => {
+                if self.pending.len() >= MAX_PENDING_FRAMES {
+                    return Err(Failure::Connection(ENHANCE_YOUR_CALM));
+                }
+                self.pending.push_back(frame);
+                Ok(())
+            }
+        }
+    }
+
+    fn write_rst_stream(&mut self, stream_id: u32, code: u32) -> Result<(), Failure> {
+        self.write_frame(RST_STREAM, 0, stream_id, &code.to_be_bytes())
+    }
+
+    fn write_goaway(&mut self, code: u32) -> Result<(), Failure> {
+        let mut payload = Vec::with_capacity(8);
+        payload.extend_from_slice(&self.last_stream_id.to_be_bytes());
+        payload.extend_from_slice(&code.to_be_bytes());
+        self.write_frame(GOAWAY, 0, 0, &payload)
+    }
+
+    fn write_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: &[u8],
+    ) -> Result<(), Failure> {
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame)?;
+        Ok(())
+    }
+}
+
+/// Headers sent on every non-gRPC response, matching the HTTP/1 responses.
+fn encode_security_headers(content_length: Option<u64>, block: &mut Vec<u8>) {
+    hpack::encode_header("x-content-type-options", "nosniff", block);
+    hpack::encode_header("x-frame-options", "DENY", block);
+    if let Some(content_length) = content_length {
+        hpack::encode_header("content-length", &content_length.to_string(), block);
+    }
+}
+
+/// Returns the payload of a DATA or HEADERS frame without its padding.
+fn strip_padding(frame: &Frame) -> Result<&[u8], Failure> {
+    if frame.flags & FLAG_PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let (&pad_len, rest) = frame
+        .payload
+        .split_first()
+        .ok_or(Failure::Connection(PROTOCOL_ERROR))?;
+    rest.len()
+        .checked_sub(pad_len as usize)
+        .map(|end| &rest[..end])
+        .ok_or(Failure::Connection(PROTOCOL_ERROR))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        FLAG_ACK, FLAG_END_HEADERS, FLAG_END_STREAM, GOAWAY, HEADERS, PING, PREFACE, SETTINGS,
+        WINDOW_UPDATE,
+    };
+    use crate::grpc::GrpcHealth;
+    use crate::handler::{Chain, Handler, Request, Response};
+    use crate::hpack::{self, Decoder};
+    use crate::http1::handle_connection;
+    use crate::probes::Probes;
+    use crate::server::ServerState;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        out.push(kind);
+        out.push(flags);
+        out.extend_from_slice(&stream_id.to_be_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn read_frame(stream: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+        let mut header = [0u8; 9];
+        stream.read_exact(&mut header).unwrap();
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).unwrap();
+        let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        (header[3], header[4], stream_id, payload)
+    }
+
+    /// Opens an h2c connection to a fresh `handle_connection` and completes the
+    /// SETTINGS exchange.
+    fn connect() -> TcpStream {
+        connect_with_serving(true)
+    }
+
+    fn connect_with_serving(serving: bool) -> TcpStream {
+        let probes = Arc::new(Probes::new(Duration::ZERO));
+        if !serving {
+            probes.start_draining();
+        }
+        connect_with_state(ServerState::with_probes(probes))
+    }
+
+    fn connect_with_state(state: ServerState) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                handle_connection(stream, &state);
+            }
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(PREFACE).unwrap();
+        client.write_all(&frame(SETTINGS, 0, 0, &[])).unwrap();
+        let (kind, flags, _, _) = read_frame(&mut client);
+        assert_eq!((kind, flags & FLAG_ACK), (SETTINGS, 0));
+        client
+    }
+
+    /// Sends a GET for `path` on stream 1 and collects the decoded response
+    /// headers and body.
+    fn get(path: &str) -> (Vec<(String, String)>, Vec<u8>) {
+        get_from(connect(), path)
+    }
+
+    fn get_from(mut client: TcpStream, path: &str) -> (Vec<(String, String)>, Vec<u8>) {
+        // :method GET, :scheme http, :path <path> (literal, indexed name), :authority example
+        let mut block = vec![0x82, 0x86, 0x04, path.len() as u8];
+        block.extend_from_slice(path.as_bytes());
+        block.extend_from_slice(b"\x41\x07example");
+        client
+            .write_all(&frame(
+                HEADERS,
+                FLAG_END_HEADERS | FLAG_END_STREAM,
+                1,
+                &block,
+            ))
+            .unwrap();
+
+        let mut decoder = Decoder::new(4096);
+        let mut headers = Vec::new();
+        let mut body = Vec::new();
+        loop {
+            let (kind, flags, stream_id, payload) = read_frame(&mut client);
+            match kind {
+                HEADERS => {
+                    assert_eq!(stream_id, 1);
+                    headers = decoder.decode(&payload, usize::MAX).unwrap().0;
+                }
+                0x0 => body.extend_from_slice(&payload),
+                _ => continue,
+            }
+            if flags & FLAG_END_STREAM != 0 {
+                break;
+            }
+        }
+        (headers, body)
+    }
+
+    /// Calls `path` with a gRPC request message on stream 1 and collects all
+    /// decoded header blocks and the response body.
+    fn grpc_call(
+        serving: bool,
+        path: &str,
+        message: &[u8],
+    ) -> (Vec<Vec<(String, String)>>, Vec<u8>) {
+        let mut client = connect_with_serving(serving);
+        grpc_call_on(&mut client, &mut Decoder::new(4096), 1, path, message)
+    }
+
+    /// Like `grpc_call`, on stream `stream_id` of an open connection.
+    fn grpc_call_on(
+        client: &mut TcpStream,
+        decoder: &mut Decoder,
+        stream_id: u32,
+        path: &str,
+        message: &[u8],
+    ) -> (Vec<Vec<(String, String)>>, Vec<u8>) {
+        let mut block = vec![0x83, 0x86];
+        hpack::encode_header(":path", path, &mut block);
+        hpack::encode_header("content-type", "application/grpc", &mut block);
+        hpack::encode_header("te", "trailers", &mut block);
+        client
+            .write_all(&frame(HEADERS, FLAG_END_HEADERS, stream_id, &block))
+            .unwrap();
+        let mut data = vec![0];
+        data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        data.extend_from_slice(message);
+        client
+            .write_all(&frame(0x0, FLAG_END_STREAM, stream_id, &data))
+            .unwrap();
+
+        let mut blocks = Vec::new();
+        let mut body = Vec::new();
+        loop {
+            let (kind, flags, id, payload) = read_frame(client);
+            match kind {
+                HEADERS => blocks.push(decoder.decode(&payload, usize::MAX).unwrap().0),
+                0x0 if id == stream_id => body.extend_from_slice(&payload),
+                _ => continue,
+            }
+            if flags & FLAG_END_STREAM != 0 {
+                break;
+            }
+        }
+        (blocks, body)
+    }
+
+    #[test]
+    fn answers_grpc_health_check_with_serving_status() {
+        let (blocks, body) = grpc_call(true, "/grpc.health.v1.Health/Check", &[]);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(header(&blocks[0], ":status"), Some("200"));
+        assert_eq!(header(&blocks[0], "content-type"), Some("application/grpc"));
+        assert_eq!(body, [0, 0, 0, 0, 2, 0x08, 1]);
+        assert_eq!(header(&blocks[1], "grpc-status"), Some("0"));
+    }
+
+    #[test]
+    fn answers_grpc_health_check_with_not_serving_status() {
+        let (_, body) = grpc_call(false, "/grpc.health.v1.Health/Check", &[]);
+        assert_eq!(body, [0, 0, 0, 0, 2, 0x08, 2]);
+    }
+
+    #[test]
+    fn reports_not_serving_once_the_server_drains() {
+        let probes = Arc::new(Probes::new(Duration::ZERO));
+        let mut client = connect_with_state(ServerState::with_probes(Arc::clone(&probes)));
+        let mut decoder = Decoder::new(4096);
+        let path = "/grpc.health.v1.Health/Check";
+        let (_, body) = grpc_call_on(&mut client, &mut decoder, 1, path, &[]);
+        assert_eq!(body, [0, 0, 0, 0, 2, 0x08, 1]);
+        probes.start_draining();
+        let (_, body) = grpc_call_on(&mut client, &mut decoder, 3, path, &[]);
+        assert_eq!(body, [0, 0, 0, 0, 2, 0x08, 2]);
+    }
+
+    #[test]
+    fn passes_grpc_calls_through_the_middleware() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let observe = move |request: &Request, next: &dyn Handler| {
+            let response = next.handle(request);
+            let status = response.header("grpc-status").map(str::to_string);
+            tx.lock()
+                .unwrap()
+                .send((request.path().to_string(), status))
+                .unwrap();
+            response
+        };
+        let probes = Arc::new(Probes::new(Duration::ZERO));
+        let state = ServerState {
+            handler: Chain::new(
+                vec![Box::new(observe), Box::new(GrpcHealth::new(probes))],
+                Box::new(|_: &Request| Response::new(404)),
+            ),
+            ..ServerState::default()
+        };
+        let mut client = connect_with_state(state);
+        let path = "/grpc.health.v1.Health/Check";
+        let (blocks, _) = grpc_call_on(&mut client, &mut Decoder::new(4096), 1, path, &[]);
+        assert_eq!(header(&blocks[1], "grpc-status"), Some("0"));
+        assert_eq!(
+            rx.recv().unwrap(),
+            (path.to_string(), Some("0".to_string()))
+        );
+    }
+
+    #[test]
+    fn answers_unknown_grpc_service_with_trailers_only_response() {
+        let (blocks, body) = grpc_call(true, "/grpc.health.v1.Health/Check", b"\x0a\x05other");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(header(&blocks[0], "grpc-status"), Some("5"));
+        assert!(body.is_empty());
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn passes_requests_to_the_handler() {
+        let handler = |request: &Request| {
+            let query = request.query().unwrap_or("").to_string();
+            let authority = request.header(":authority").is_some().to_string();
+            Response::new(201)
+                .with_header("X-Query", query)
+                .with_header("X-Pseudo", authority)
+                .with_body(request.version().as_bytes().to_vec())
+        };
+        let state = ServerState {
+            handler: Chain::new(Vec::new(), Box::new(handler)),
+            ..ServerState::default()
+        };
+        let (headers, body) = get_from(connect_with_state(state), "/a?b=c");
+        assert_eq!(header(&headers, ":status"), Some("201"));
+        assert_eq!(header(&headers, "x-query"), Some("b=c"));
+        assert_eq!(header(&headers, "x-pseudo"), Some("false"));
+        assert_eq!(body, b"HTTP/2.0");
+    }
+
+    #[test]
+    fn streams_file_bodies() {
+        let path = std::env::temp_dir().join(format!("ok-h2-file-{}", std::process::id()));
+        let contents: Vec<u8> = (0..40_000).map(|i| i as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let response = Response::new(200).with_file(file, contents.len() as u64);
+        let state = ServerState {
+            handler: Chain::new(Vec::new(), Box::new(move |_: &Request| response.clone())),
+            ..ServerState::default()
+        };
+        let (headers, body) = get_from(connect_with_state(state), "/file");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(header(&headers, "content-length"), Some("40000"));
+        assert!(body == contents, "got {} bytes", body.len());
+    }
+
+    #[test]
+    fn serves_root_over_h2c() {
+        let (headers, body) = get("/");
+        assert_eq!(header(&headers, ":status"), Some("200"));
+        assert_eq!(header(&headers, "content-length"), Some("2"));
+        assert_eq!(body, b"OK");
+    }
+
+    #[test]
+    fn serves_favicon_over_h2c() {
+        let (headers, body) = get("/favicon.ico");
+        assert_eq!(header(&headers, ":status"), Some("200"));
+        assert_eq!(header(&headers, "content-type"), Some("image/png"));
+        assert_eq!(body.len(), 130);
+    }
+
+    #[test]
+    fn answers_unknown_path_with_404_over_h2c() {
+        let (headers, body) = get("/missing?x=1");
+        assert_eq!(header(&headers, ":status"), Some("404"));
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn rejects_header_lists_expanded_from_the_dynamic_table() {
+        let mut client = connect();
+        // A 4000-byte header added to the table, then referenced 1000 times.
+        let mut block = vec![0x82, 0x86, 0x84, 0x41, 0x07];
+        block.extend_from_slice(b"example\x40\x01x");
+        hpack::encode_integer(4000, 7, 0x00, &mut block);
+        block.extend_from_slice(&[b'v'; 4000]);
+        block.extend_from_slice(&[0x80 | 62; 1000]);
+        client
+            .write_all(&frame(
+                HEADERS,
+                FLAG_END_HEADERS | FLAG_END_STREAM,
+                1,
+                &block,
+            ))
+            .unwrap();
+        loop {
+            let (kind, _, _, payload) = read_frame(&mut client);
+            if kind == HEADERS {
+                let (headers, _) = Decoder::new(4096).decode(&payload, usize::MAX).unwrap();
+                assert_eq!(header(&headers, ":status"), Some("431"));
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn acknowledges_ping() {
+        let mut client = connect();
+        client.write_all(&frame(PING, 0, 0, b"12345678")).unwrap();
+        loop {
+            let (kind, flags, _, payload) = read_frame(&mut client);
+            if kind == PING {
+                assert_eq!(flags, FLAG_ACK);
+                assert_eq!(payload, b"12345678");
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn sends_goaway_on_protocol_error() {
+        let mut client = connect();
+        // Even stream identifiers are reserved for the server.
+        client
+            .write_all(&frame(
+                HEADERS,
+                FLAG_END_HEADERS | FLAG_END_STREAM,
+                2,
+                &[0x82],
+            ))
+            .unwrap();
+        loop {
+            let (kind, _, _, payload) = read_frame(&mut client);
+            if kind == GOAWAY {
+                assert_eq!(&payload[4..], &1u32.to_be_bytes());
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn waits_for_window_update_before_sending_more_data() {
+        let mut client = connect();
+        // Shrink the initial stream window to a single byte.
+        let mut settings = 0x4u16.to_be_bytes().to_vec();
+        settings.extend_from_slice(&1u32.to_be_bytes());
+        client.write_all(&frame(SETTINGS, 0, 0, &settings)).unwrap();
+        client
+            .write_all(&frame(
+                HEADERS,
+                FLAG_END_HEADERS | FLAG_END_STREAM,
+                1,
+                &[0x82, 0x86, 0x84],
+            ))
+            .unwrap();
+        let mut body = Vec::new();
+        loop {
+            let (kind, flags, _, payload) = read_frame(&mut client);
+            if kind == 0x0 {
+                body.extend_from_slice(&payload);
+                if flags & FLAG_END_STREAM != 0 {
+                    break;
+                }
+                assert_eq!(body, b"O");
+                client
+                    .write_all(&frame(WINDOW_UPDATE, 0, 1, &1u32.to_be_bytes()))
+                    .unwrap();
+            }
+        }
+        assert_eq!(body, b"OK");
+    }
+}
@@ -0,0 +1,163 @@
+//! A minimal webserver that answers `OK`.
+//!
+//! The `ok` binary is a thin wrapper around this crate; embed the same server
+//! in-process with [`Server::builder`]:
+//!
+//! ```no_run
+//! use ok::{Server, StaticResponse};
+//!
+//! let server = Server::builder()
+//!     .bind("127.0.0.1:0".parse().unwrap())
+//!     .route("/ready", StaticResponse::text("ready"))
+//!     .start()
+//!     .expect("start server");
+//! println!("listening on {}", server.local_addr());
+//! server.shutdown();
+//! ```
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+mod admin;
+mod bench;
+mod capture;
+mod compression;
+mod conditional;
+mod diagnostics;
+mod echo;
+mod faults;
+mod grpc;
+mod handler;
+mod hpack;
+mod http1;
+mod http2;
+mod logging;
+mod probes;
+mod ranges;
+mod record;
+mod routes;
+mod sequences;
+mod server;
+mod static_files;
+#[cfg(feature = "tls")]
+mod tls;
+
+pub use bench::{Bench, BenchReport};
+pub use echo::Echo;
+pub use faults::{Faults, Latency};
+pub use handler::{BodyLimit, Handler, Middleware, Request, Response};
+#[cfg(unix)]
+pub use logging::SyslogSink;
+pub use logging::{AccessLogFilter, FileSink, LogLevel, LogSink, Logger, StderrSink, StdoutSink};
+pub use routes::{Favicon, RouteTable, StaticResponse};
+pub use sequences::{Sequence, SequenceTable};
+pub use server::{health_check, request_shutdown, Server, ServerBuilder};
+
+const MAX_HEADER_SIZE: usize = 8192;
+#[allow(clippy::identity_op)]
+const MAX_BODY_SIZE: usize = 1 * 1024 * 1024;
+
+fn sanitize(input: &str) -> String {
+    input
+        .chars()
+        .map(|c| if c.is_control() || c == '"' { '?' } else { c })
+        .collect()
+}
+
+/// `input` as a quoted JSON string.
+fn json_string(input: &str) -> String {
+    let mut quoted = String::with_capacity(input.len() + 2);
+    quoted.push('"');
+    for c in input.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Header pairs as a JSON array of `[name, value]` arrays.
+fn json_pairs<'a>(pairs: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+    let pairs: Vec<String> = pairs
+        .map(|(name, value)| format!("[{},{}]", json_string(name), json_string(value)))
+        .collect();
+    format!("[{}]", pairs.join(","))
+}
+
+/// A body as a JSON object member: `"body"` when it is UTF-8, otherwise
+/// `"body_base64"`.
+fn json_body(body: &[u8]) -> String {
+    match std::str::from_utf8(body) {
+        Ok(body) => format!("\"body\":{}", json_string(body)),
+        Err(_) => format!("\"body_base64\":\"{}\"", base64(body)),
+    }
+}
+
+/// Standard base64 with padding.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// A client connection: a plain TCP socket, or a TLS stream that reads and
+/// writes decrypted bytes.
+trait Transport: Read + Write {
+    /// The underlying socket, used for timeouts and the peer address.
+    fn socket(&self) -> &TcpStream;
+
+    /// Whether the client speaks HTTP/2 on this connection: the client preface
+    /// on cleartext connections, ALPN for TLS.
+    fn is_http2(&mut self) -> bool;
+}
+
+impl Transport for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+
+    fn is_http2(&mut self) -> bool {
+        http2::starts_with_preface(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64, json_string};
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn quotes_json_strings() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+}
@@ -0,0 +1,610 @@
+//! Log delivery: a bounded queue that drops entries when full, drained by a
+//! single thread into a [`LogSink`], and request records into a second sink.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+/// Destination for log lines. Implemented for closures taking a `&str`.
+pub trait LogSink: Send {
+    /// Writes one line; `line` has no trailing newline.
+    fn write_line(&mut self, line: &str) -> io::Result<()>;
+}
+
+impl<F> LogSink for F
+where
+    F: FnMut(&str) + Send,
+{
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self(line);
+        Ok(())
+    }
+}
+
+/// Writes each line to standard output. The default sink.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdoutSink;
+
+impl LogSink for StdoutSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(io::stdout().lock(), "{}", line)
+    }
+}
+
+/// Writes each line to standard error.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StderrSink;
+
+impl LogSink for StderrSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(io::stderr().lock(), "{}", line)
+    }
+}
+
+/// Appends lines to a file, rotating it by size and/or age.
+///
+/// On rotation `app.log` is renamed to `app.log.1`, older files shift up to
+/// `app.log.<keep>`, and the oldest is discarded.
+#[derive(Debug)]
+pub struct FileSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    keep: usize,
+}
+
+impl FileSink {
+    /// Opens `path` for appending, creating it if needed. Without
+    /// [`max_bytes`](Self::max_bytes) or [`max_age`](Self::max_age) the file is
+    /// never rotated.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(FileSink {
+            path,
+            file,
+            size,
+            opened_at: Instant::now(),
+            max_bytes: None,
+            max_age: None,
+            keep: 5,
+        })
+    }
+
+    /// Rotates before a line would grow the file past `max_bytes`.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Rotates once the current file has been written to for `max_age`.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Number of rotated files to keep. Defaults to 5; `0` discards the old
+    /// contents on rotation.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    fn needs_rotation(&self, incoming: u64) -> bool {
+        let too_big = self
+            .max_bytes
+            .is_some_and(|max| self.size > 0 && self.size + incoming > max);
+        let too_old = self
+            .max_age
+            .is_some_and(|max| self.opened_at.elapsed() >= max);
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match fs::rename(rotated_path(&self.path, n), rotated_path(&self.path, n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+}
+
+impl LogSink for FileSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.needs_rotation(len) {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Sends lines to the local syslog daemon over a Unix datagram socket
+/// (usually `/dev/log`), as facility `daemon`, severity `info`.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct SyslogSink {
+    path: PathBuf,
+    socket: UnixDatagram,
+    prefix: String,
+}
+
+#[cfg(unix)]
+impl SyslogSink {
+    pub fn connect(path: impl Into<PathBuf>) -> io::Result<Self> {
+        const FACILITY_DAEMON: u8 = 3;
+        const SEVERITY_INFO: u8 = 6;
+        let path = path.into();
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(&path)?;
+        Ok(SyslogSink {
+            path,
+            socket,
+            prefix: format!(
+                "<{}>ok[{}]: ",
+                FACILITY_DAEMON * 8 + SEVERITY_INFO,
+                std::process::id()
+            ),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl LogSink for SyslogSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let message = format!("{}{}", self.prefix, line);
+        if self.socket.send(message.as_bytes()).is_ok() {
+            return Ok(());
+        }
+        // The daemon may have restarted and recreated its socket.
+        self.socket = UnixDatagram::unbound()?;
+        self.socket.connect(&self.path)?;
+        self.socket.send(message.as_bytes()).map(|_| ())
+    }
+}
+
+/// Verbosity of server-event logging (accepts, dropped connections, worker
+/// failures, TLS). Access logs are controlled by [`AccessLogFilter`] instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid log level {:?}: expected off, error, warn, info or debug",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+/// Which requests get an access-log line. By default every request does.
+///
+/// The checks run before the line is formatted, so filtered-out requests cost
+/// no allocation. All configured conditions must hold; sampling counts only
+/// the requests that pass the other conditions.
+#[derive(Debug)]
+pub struct AccessLogFilter {
+    every: u64,
+    errors_only: bool,
+    path_patterns: Vec<String>,
+    seen: AtomicU64,
+}
+
+impl Default for AccessLogFilter {
+    fn default() -> Self {
+        AccessLogFilter {
+            every: 1,
+            errors_only: false,
+            path_patterns: Vec::new(),
+            seen: AtomicU64::new(0),
+        }
+    }
+}
+
+impl AccessLogFilter {
+    /// Logs one request in `n`; `0` disables the access log.
+    pub fn sample(mut self, n: u64) -> Self {
+        self.every = n;
+        self
+    }
+
+    /// Logs only responses with a status outside 200–299.
+    pub fn errors_only(mut self, errors_only: bool) -> Self {
+        self.errors_only = errors_only;
+        self
+    }
+
+    /// Logs only paths matching one of the added patterns, where `*` matches
+    /// any run of characters, e.g. `/api/*`.
+    pub fn path_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.path_patterns.push(pattern.into());
+        self
+    }
+
+    pub(crate) fn matches(&self, path: &str, status: u16) -> bool {
+        if self.every == 0 || (self.errors_only && (200..300).contains(&status)) {
+            return false;
+        }
+        if !self.path_patterns.is_empty()
+            && !self
+                .path_patterns
+                .iter()
+                .any(|pattern| glob_match(pattern.as_bytes(), path.as_bytes()))
+        {
+            return false;
+        }
+        if self.every == 1 {
+            return true;
+        }
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed);
+        seen.checked_rem(self.every) == Some(0)
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` matches any run of bytes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it currently absorbs up to.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// A queued entry: a log line, or a request record for the record sink.
+enum Entry {
+    Line(String),
+    Record(String),
+}
+
+/// Sending half of the log queue. Entries are dropped, and counted, when the
+/// queue is full so that logging never blocks a worker.
+#[derive(Clone)]
+pub(crate) struct LogSender {
+    tx: SyncSender<Entry>,
+    dropped: Arc<AtomicU64>,
+    level: LogLevel,
+}
+
+impl LogSender {
+    /// Queues a server event if `level` is enabled.
+    pub(crate) fn event(&self, level: LogLevel, message: String) {
+        if level <= self.level {
+            self.send(message);
+        }
+    }
+
+    /// Queues a line unconditionally; used for access logs, which are
+    /// filtered before they are formatted.
+    pub(crate) fn send(&self, message: String) {
+        self.queue(Entry::Line(message));
+    }
+
+    /// Queues a request record; see [`ServerBuilder::record`].
+    ///
+    /// [`ServerBuilder::record`]: crate::ServerBuilder::record
+    pub(crate) fn record(&self, record: String) {
+        self.queue(Entry::Record(record));
+    }
+
+    fn queue(&self, entry: Entry) {
+        match self.tx.try_send(entry) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                eprintln!("log channel disconnected – log entry lost");
+            }
+        }
+    }
+}
+
+/// A handle for logging server events through the server's sink, subject to
+/// its [`LogLevel`]. Created with [`Server::logger`]; events logged once the
+/// server has stopped are dropped.
+///
+/// [`Server::logger`]: crate::Server::logger
+#[derive(Clone)]
+pub struct Logger {
+    sender: Weak<LogSender>,
+}
+
+impl Logger {
+    /// Weak, so that a handle kept by another thread does not keep the log
+    /// thread running after the server stops.
+    pub(crate) fn new(sender: Weak<LogSender>) -> Self {
+        Logger { sender }
+    }
+
+    pub fn log(&self, level: LogLevel, message: impl Into<String>) {
+        if let Some(sender) = self.sender.upgrade() {
+            sender.event(level, message.into());
+        }
+    }
+}
+
+pub(crate) struct LogReceiver {
+    rx: Receiver<Entry>,
+    dropped: Arc<AtomicU64>,
+    level: LogLevel,
+}
+
+pub(crate) fn log_channel(capacity: usize, level: LogLevel) -> (LogSender, LogReceiver) {
+    let (tx, rx) = sync_channel(capacity);
+    let dropped = Arc::new(AtomicU64::new(0));
+    (
+        LogSender {
+            tx,
+            dropped: Arc::clone(&dropped),
+            level,
+        },
+        LogReceiver { rx, dropped, level },
+    )
+}
+
+impl LogReceiver {
+    /// Writes queued lines to `sink`, and records to `records` if given,
+    /// until every sender is gone, reporting how many entries were dropped
+    /// every `report_interval`.
+    pub(crate) fn run(
+        self,
+        sink: &mut dyn LogSink,
+        mut records: Option<&mut dyn LogSink>,
+        report_interval: Duration,
+    ) {
+        let mut next_report = Instant::now() + report_interval;
+        loop {
+            let timeout = next_report.saturating_duration_since(Instant::now());
+            match self.rx.recv_timeout(timeout) {
+                Ok(Entry::Line(line)) => write_line(sink, &line),
+                Ok(Entry::Record(record)) => {
+                    if let Some(records) = records.as_deref_mut() {
+                        write_line(records, &record);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if Instant::now() >= next_report {
+                self.report_dropped(sink);
+                next_report = Instant::now() + report_interval;
+            }
+        }
+        self.report_dropped(sink);
+    }
+
+    /// The queued lines and records, without waiting.
+    #[cfg(test)]
+    pub(crate) fn try_iter(&self) -> impl Iterator<Item = String> + '_ {
+        self.rx.try_iter().map(|entry| match entry {
+            Entry::Line(line) | Entry::Record(line) => line,
+        })
+    }
+
+    fn report_dropped(&self, sink: &mut dyn LogSink) {
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 && LogLevel::Warn <= self.level {
+            write_line(
+                sink,
+                &format!("Dropped {} log entries: log queue full", dropped),
+            );
+        }
+    }
+}
+
+fn write_line(sink: &mut dyn LogSink, line: &str) {
+    if let Err(e) = sink.write_line(line) {
+        eprintln!("log sink failed: {} – log entry lost", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        glob_match, log_channel, rotated_path, AccessLogFilter, FileSink, LogLevel, LogSink,
+    };
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("ok-log-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn read(path: &PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn reports_dropped_entries() {
+        let (log_tx, log_rx) = log_channel(1, LogLevel::Info);
+        log_tx.send("first".into());
+        log_tx.send("dropped".into());
+        log_tx.send("dropped".into());
+        drop(log_tx);
+        let (tx, rx) = mpsc::channel();
+        let mut sink = move |line: &str| tx.send(line.to_string()).unwrap();
+        log_rx.run(&mut sink, None, Duration::from_secs(60));
+        let lines: Vec<String> = rx.try_iter().collect();
+        assert_eq!(lines, ["first", "Dropped 2 log entries: log queue full"]);
+    }
+
+    #[test]
+    fn filters_events_by_level() {
+        let (log_tx, log_rx) = log_channel(10, LogLevel::Warn);
+        log_tx.event(LogLevel::Error, "error".into());
+        log_tx.event(LogLevel::Warn, "warn".into());
+        log_tx.event(LogLevel::Info, "info".into());
+        log_tx.send("access".into());
+        let lines: Vec<String> = log_rx.try_iter().collect();
+        assert_eq!(lines, ["error", "warn", "access"]);
+    }
+
+    #[test]
+    fn parses_log_levels() {
+        assert_eq!("OFF".parse::<LogLevel>().unwrap(), LogLevel::Off);
+        assert_eq!("debug".parse::<LogLevel>().unwrap(), LogLevel::Debug);
+        assert!("verbose".parse::<LogLevel>().is_err());
+    }
+
+    #[test]
+    fn samples_access_log() {
+        let filter = AccessLogFilter::default().sample(3);
+        let logged = (0..9).filter(|_| filter.matches("/", 200)).count();
+        assert_eq!(logged, 3);
+        assert!(!AccessLogFilter::default().sample(0).matches("/", 500));
+    }
+
+    #[test]
+    fn filters_access_log_by_status_and_path() {
+        let filter = AccessLogFilter::default()
+            .errors_only(true)
+            .path_pattern("/api/*");
+        assert!(filter.matches("/api/items", 404));
+        assert!(!filter.matches("/api/items", 204));
+        assert!(!filter.matches("/", 500));
+    }
+
+    #[test]
+    fn matches_glob_patterns() {
+        assert!(glob_match(b"/", b"/"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"/api/*", b"/api/"));
+        assert!(glob_match(b"/*/items/*", b"/v1/items/7"));
+        assert!(glob_match(b"*.png", b"/img/a.b.png"));
+        assert!(!glob_match(b"/api/*", b"/apix"));
+        assert!(!glob_match(b"/a*c", b"/abcd"));
+    }
+
+    #[test]
+    fn rotates_file_by_size() {
+        let dir = TempDir::new("size");
+        let path = dir.0.join("ok.log");
+        let mut sink = FileSink::open(&path).unwrap().max_bytes(9).keep(2);
+        for line in ["aaaa", "bbbb", "cccc", "dddd"] {
+            sink.write_line(line).unwrap();
+        }
+        assert_eq!(read(&path), "dddd\n");
+        assert_eq!(read(&rotated_path(&path, 1)), "cccc\n");
+        assert_eq!(read(&rotated_path(&path, 2)), "bbbb\n");
+        assert!(!rotated_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn rotates_file_by_age() {
+        let dir = TempDir::new("age");
+        let path = dir.0.join("ok.log");
+        let mut sink = FileSink::open(&path).unwrap().max_age(Duration::ZERO);
+        sink.write_line("old").unwrap();
+        sink.write_line("new").unwrap();
+        assert_eq!(read(&path), "new\n");
+        assert_eq!(read(&rotated_path(&path, 1)), "old\n");
+    }
+
+    #[test]
+    fn appends_to_existing_file() {
+        let dir = TempDir::new("append");
+        let path = dir.0.join("ok.log");
+        std::fs::write(&path, "before\n").unwrap();
+        FileSink::open(&path).unwrap().write_line("after").unwrap();
+        assert_eq!(read(&path), "before\nafter\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sends_lines_to_syslog_socket() {
+        use super::SyslogSink;
+        use std::os::unix::net::UnixDatagram;
+
+        let dir = TempDir::new("syslog");
+        let socket_path = dir.0.join("log.sock");
+        let daemon = UnixDatagram::bind(&socket_path).unwrap();
+        let mut sink = SyslogSink::connect(&socket_path).unwrap();
+        sink.write_line("hello").unwrap();
+        let mut buf = [0u8; 128];
+        let n = daemon.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..n]);
+        let expected = format!("<30>ok[{}]: hello", std::process::id());
+        assert_eq!(message, expected);
+    }
+}
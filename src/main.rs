@@ -1,708 +1,449 @@
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::panic;
-use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
-use std::time::{Duration, Instant};
-use std::{env, thread};
-
-const MAX_HEADER_SIZE: usize = 8192;
-const MAX_BODY_SIZE: usize = 1 * 1024 * 1024;
-const QUEUE_CAPACITY: usize = 100;
-const LOG_QUEUE_CAPACITY: usize = 100;
-
-const OK_HEADER: &[u8] = b"HTTP/1.1 200 OK\r\n\
-Connection: close\r\n\
-Content-Type: text/plain; charset=utf-8\r\n\
-X-Content-Type-Options: nosniff\r\n\
-X-Frame-Options: DENY\r\n\
-Content-Length: 2\r\n\r\n";
-const OK_BODY: &[u8] = b"OK";
-
-const FAVICON_HEADER: &[u8] = b"HTTP/1.1 200 OK\r\n\
-Connection: close\r\n\
-Content-Type: image/png\r\n\
-Cache-Control: public, max-age=86400\r\n\
-X-Content-Type-Options: nosniff\r\n\
-X-Frame-Options: DENY\r\n\
-Content-Length: 130\r\n\r\n";
-
-const RESPONSE_404: &[u8] = b"HTTP/1.1 404 Not Found\r\n\
-Connection: close\r\n\
-X-Content-Type-Options: nosniff\r\n\
-X-Frame-Options: DENY\r\n\
-Content-Length: 0\r\n\r\n";
-const RESPONSE_408: &[u8] = b"HTTP/1.1 408 Request Timeout\r\n\
-Connection: close\r\n\
-X-Content-Type-Options: nosniff\r\n\
-X-Frame-Options: DENY\r\n\
-Content-Length: 0\r\n\r\n";
-const RESPONSE_431: &[u8] = b"HTTP/1.1 431 Request Header Fields Too Large\r\n\
-Connection: close\r\n\
-X-Content-Type-Options: nosniff\r\n\
-X-Frame-Options: DENY\r\n\
-Content-Length: 0\r\n\r\n";
-const RESPONSE_413: &[u8] = b"HTTP/1.1 413 Payload Too Large\r\n\
-Connection: close\r\n\
-X-Content-Type-Options: nosniff\r\n\
-X-Frame-Options: DENY\r\n\
-Content-Length: 0\r\n\r\n";
-const RESPONSE_501: &[u8] = b"HTTP/1.1 501 Not Implemented\r\n\
-Connection: close\r\n\
-X-Content-Type-Options: nosniff\r\n\
-X-Frame-Options: DENY\r\n\
-Content-Length: 0\r\n\r\n";
-
-#[cfg(test)]
-fn body_timeout_duration() -> Duration {
-    Duration::from_millis(50)
+use std::env;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use ok::{health_check, request_shutdown, Bench};
+
+use crate::config::Config;
+use crate::replay::ReplayArgs;
+
+mod config;
+mod replay;
+
+const USAGE: &str = "\
+Usage: ok [OPTIONS]
+       ok replay [--fast] <FILE> <TARGET>
+       ok bench [BENCH OPTIONS] <TARGET>
+
+Options:
+  -p, --port <PORT>      TCP port to listen on [env: PORT] [default: 8080]
+  -b, --bind <ADDR>      IP address to listen on [env: BIND_ADDRESS] [default: 0.0.0.0]
+  -t, --threads <N>      Number of worker threads [env: THREAD_POOL_SIZE]
+      --no-favicon       Do not serve /favicon.ico [env: SHOW_FAVICON=false]
+  -c, --config <FILE>    Read KEY=VALUE settings (the environment variable names) from FILE
+      --check-config     Validate the configuration, report every error and exit
+      --print-config     Print each setting with its source (default, file, env, flag) and exit
+      --health-check     Probe HEALTH_CHECK_PATH on the bind address and exit 0 if it answers 200
+      --shutdown         Ask the running server to drain and stop through the admin API, and
+                         wait until it has stopped accepting
+  -h, --help             Print this help and exit
+  -V, --version          Print the version and exit
+
+Options take precedence over environment variables, which take precedence over
+the config file.
+
+`ok replay` re-issues the requests recorded in FILE (see RECORD_PATH) against
+TARGET (host:port), keeping their original spacing unless --fast is given.
+
+`ok bench` sends GET requests to TARGET (host:port[/path]) and reports
+throughput, latency percentiles and errors:
+  -c, --connections <N>  Concurrent connections [default: 1]
+  -n, --requests <N>     Stop after N requests
+  -d, --duration <SECS>  Stop after SECS seconds [default: 10, or none with -n]
+      --no-keep-alive    Open a new connection for every request
+";
+
+/// Command-line options; `None` leaves the setting to the environment or
+/// config file.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Args {
+    port: Option<u16>,
+    bind: Option<IpAddr>,
+    threads: Option<usize>,
+    no_favicon: bool,
+    config: Option<PathBuf>,
+    check_config: bool,
+    print_config: bool,
+    health_check: bool,
+    shutdown: bool,
+    help: bool,
+    version: bool,
+    replay: Option<ReplayArgs>,
+    bench: Option<Bench>,
 }
 
-#[cfg(not(test))]
-fn body_timeout_duration() -> Duration {
-    Duration::from_secs(5)
-}
-
-const FAVICON_PNG: &[u8] = &[
-    0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52,
-    0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x10, 0x08, 0x03, 0x00, 0x00, 0x00, 0x28, 0x2D, 0x0F,
-    0x53, 0x00, 0x00, 0x00, 0x06, 0x50, 0x4C, 0x54, 0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xA5,
-    0x67, 0xB9, 0xCF, 0x00, 0x00, 0x00, 0x01, 0x74, 0x52, 0x4E, 0x53, 0x00, 0x40, 0xE6, 0xD8, 0x66,
-    0x00, 0x00, 0x00, 0x2A, 0x49, 0x44, 0x41, 0x54, 0x18, 0xD3, 0x63, 0x60, 0xA0, 0x0E, 0x60, 0x64,
-    0x64, 0x04, 0x93, 0x10, 0x0C, 0x26, 0xA1, 0x1C, 0x08, 0x9F, 0x81, 0x11, 0x26, 0x08, 0xE3, 0x13,
-    0x14, 0x40, 0x68, 0x61, 0x40, 0x28, 0x41, 0x35, 0x14, 0xC3, 0x5A, 0x8A, 0x01, 0x00, 0x20, 0xDE,
-    0x00, 0x3D, 0xEB, 0xB1, 0x31, 0x2A, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42,
-    0x60, 0x82,
-];
-
-fn sanitize(input: &str) -> String {
-    input
-        .chars()
-        .map(|c| if c.is_control() || c == '"' { '?' } else { c })
-        .collect()
-}
-
-fn parse_request_line(request_line: &str) -> (&str, &str) {
-    if let Some(method_end_index) = request_line.find(' ') {
-        let method = &request_line[..method_end_index];
-        let rest = request_line[method_end_index + 1..].trim_start();
-        if let Some(path_end_index) = rest.find(' ') {
-            let path = rest[..path_end_index]
-                .split_once('?')
-                .map_or(&rest[..path_end_index], |(p, _)| p);
-            return (method, path);
+impl Args {
+    /// The settings given as flags, by their environment variable names.
+    fn flags(&self) -> Vec<(&'static str, String)> {
+        let mut flags = Vec::new();
+        if let Some(port) = self.port {
+            flags.push(("PORT", port.to_string()));
+        }
+        if let Some(bind) = self.bind {
+            flags.push(("BIND_ADDRESS", bind.to_string()));
         }
-        let path = rest
-            .split_once('?')
-            .map_or(rest, |(p, _)| p);
-        return (method, path);
+        if let Some(threads) = self.threads {
+            flags.push(("THREAD_POOL_SIZE", threads.to_string()));
+        }
+        if self.no_favicon {
+            flags.push(("SHOW_FAVICON", "false".into()));
+        }
+        flags
     }
-    ("", "")
 }
 
-fn compute_pool_size(env_value: Option<String>, available_parallelism: Option<usize>) -> usize {
-    env_value
-        .and_then(|v| v.parse::<usize>().ok())
-        .map(|n| if n == 0 { 1 } else { n })
-        .or_else(|| available_parallelism.filter(|n| *n > 0))
-        .unwrap_or(4)
+fn invalid_arg(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
 }
 
-fn dispatch_connection(
-    senders: &mut [Option<SyncSender<TcpStream>>],
-    mut stream: TcpStream,
-    log_tx: &SyncSender<String>,
-    next: &mut usize,
-) -> bool {
-    if senders.is_empty() {
-        let _ = log_tx.try_send("Connection dropped: no workers available".into());
-        return false;
-    }
-    let mut dispatched = false;
-    for i in 0..senders.len() {
-        let idx = (*next + i) % senders.len();
+fn parse_arg<T: FromStr>(flag: &str, value: &str) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid_arg(format!("invalid value {:?} for {}", value, flag)))
+}
 
-        let Some(tx) = senders[idx].as_ref() else {
-            continue;
+/// Parses the arguments after the program name. Values may follow the flag as
+/// the next argument or after `=`, as in `--port=9000`.
+fn parse_args(args: impl IntoIterator<Item = String>) -> io::Result<Args> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter().peekable();
+    if args.peek().is_some_and(|arg| arg == "replay") {
+        args.next();
+        parsed.replay = Some(parse_replay_args(args)?);
+        return Ok(parsed);
+    }
+    if args.peek().is_some_and(|arg| arg == "bench") {
+        args.next();
+        parsed.bench = Some(parse_bench_args(args)?);
+        return Ok(parsed);
+    }
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None),
         };
-
-        match tx.try_send(stream) {
-            Ok(_) => {
-                dispatched = true;
-                break;
+        let takes_value = matches!(
+            flag,
+            "-p" | "--port" | "-b" | "--bind" | "-t" | "--threads" | "-c" | "--config"
+        );
+        let value = if takes_value {
+            match inline.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(invalid_arg(format!("{} requires a value", flag))),
             }
-            Err(TrySendError::Full(returned)) => {
-                stream = returned;
-                continue;
+        } else if inline.is_some() {
+            return Err(invalid_arg(format!("{} does not take a value", flag)));
+        } else {
+            String::new()
+        };
+        match flag {
+            "-p" | "--port" => parsed.port = Some(parse_arg(flag, &value)?),
+            "-b" | "--bind" => parsed.bind = Some(parse_arg(flag, &value)?),
+            "-t" | "--threads" => {
+                let threads: usize = parse_arg(flag, &value)?;
+                if threads == 0 {
+                    return Err(invalid_arg(format!("{} must be at least 1", flag)));
+                }
+                parsed.threads = Some(threads);
             }
-            Err(TrySendError::Disconnected(returned)) => {
-                stream = returned;
-                let _ = log_tx.try_send(format!("Worker {} disconnected", idx));
-                senders[idx] = None;
-                continue;
+            "-c" | "--config" => parsed.config = Some(PathBuf::from(value)),
+            "--no-favicon" => parsed.no_favicon = true,
+            "--check-config" => parsed.check_config = true,
+            "--print-config" => parsed.print_config = true,
+            "--health-check" => parsed.health_check = true,
+            "--shutdown" => parsed.shutdown = true,
+            "-h" | "--help" => parsed.help = true,
+            "-V" | "--version" => parsed.version = true,
+            _ => {
+                return Err(invalid_arg(format!(
+                    "unknown argument {:?} (see --help)",
+                    arg
+                )))
             }
         }
     }
-    *next = (*next + 1) % senders.len();
-    if !dispatched {
-        let _ = log_tx
-            .try_send("Connection dropped: all workers unavailable or queues full".into());
-    }
-    dispatched
+    Ok(parsed)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{
-        compute_pool_size, dispatch_connection, handle_connection, parse_request_line, read_body,
-        MAX_BODY_SIZE,
-    };
-    use std::io::{Read, Write};
-    use std::net::{TcpListener, TcpStream};
-    use std::sync::mpsc::sync_channel;
-    use std::thread;
-    use std::time::{Duration, Instant};
-
-    #[test]
-    fn parses_request_line_without_query() {
-        let (method, path) = parse_request_line("GET / HTTP/1.1");
-        assert_eq!(method, "GET");
-        assert_eq!(path, "/");
-    }
-
-    #[test]
-    fn strips_query_from_path() {
-        let (method, path) = parse_request_line("GET /?foo=bar HTTP/1.1");
-        assert_eq!(method, "GET");
-        assert_eq!(path, "/");
-    }
-
-    #[test]
-    fn handles_root_without_http_version() {
-        let (method, path) = parse_request_line("GET /");
-        assert_eq!(method, "GET");
-        assert_eq!(path, "/");
-    }
-
-    #[test]
-    fn handles_missing_http_version() {
-        let (method, path) = parse_request_line("GET /foo");
-        assert_eq!(method, "GET");
-        assert_eq!(path, "/foo");
-    }
-
-    #[test]
-    fn strips_query_with_http_version_and_path() {
-        let (method, path) = parse_request_line("GET /foo?bar=baz HTTP/1.1");
-        assert_eq!(method, "GET");
-        assert_eq!(path, "/foo");
-    }
-
-    #[test]
-    fn strips_multiple_query_params() {
-        let (method, path) = parse_request_line("GET /?foo=bar&baz=qux HTTP/1.1");
-        assert_eq!(method, "GET");
-        assert_eq!(path, "/");
-    }
-
-    #[test]
-    fn handles_empty_query_string() {
-        let (method, path) = parse_request_line("GET /? HTTP/1.1");
-        assert_eq!(method, "GET");
-        assert_eq!(path, "/");
-    }
-
-    #[test]
-    fn preserves_fragment_in_path() {
-        let (method, path) = parse_request_line("GET /#section HTTP/1.1");
-        assert_eq!(method, "GET");
-        assert_eq!(path, "/#section");
-    }
-
-    #[test]
-    fn handles_empty_request_line() {
-        let (method, path) = parse_request_line("");
-        assert_eq!(method, "");
-        assert_eq!(path, "");
-    }
-
-    #[test]
-    fn tolerates_extra_spaces_after_method() {
-        let (method, path) = parse_request_line("GET  /foo HTTP/1.1");
-        assert_eq!(method, "GET");
-        assert_eq!(path, "/foo");
+/// Parses the arguments after `replay`.
+fn parse_replay_args(args: impl Iterator<Item = String>) -> io::Result<ReplayArgs> {
+    let mut fast = false;
+    let mut positional = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--fast" => fast = true,
+            _ if arg.starts_with('-') => {
+                return Err(invalid_arg(format!(
+                    "unknown replay argument {:?} (see --help)",
+                    arg
+                )))
+            }
+            _ => positional.push(arg),
+        }
     }
+    let [file, target] = <[String; 2]>::try_from(positional)
+        .map_err(|_| invalid_arg("replay expects <FILE> <TARGET>".into()))?;
+    Ok(ReplayArgs {
+        file: PathBuf::from(file),
+        target,
+        fast,
+    })
+}
 
-    #[test]
-    fn pool_size_clamps_zero_to_one() {
-        assert_eq!(compute_pool_size(Some("0".into()), Some(8)), 1);
+/// Parses the arguments after `bench`.
+fn parse_bench_args(args: impl Iterator<Item = String>) -> io::Result<Bench> {
+    let mut connections = 1;
+    let mut requests = None;
+    let mut duration = None;
+    let mut keep_alive = true;
+    let mut target = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| invalid_arg(format!("{} requires a value", flag)))
+        };
+        match flag {
+            "-c" | "--connections" => {
+                connections = parse_arg(flag, &value()?)?;
+                if connections == 0 {
+                    return Err(invalid_arg(format!("{} must be at least 1", flag)));
+                }
+            }
+            "-n" | "--requests" => requests = Some(parse_arg(flag, &value()?)?),
+            "-d" | "--duration" => {
+                let secs: f64 = parse_arg(flag, &value()?)?;
+                duration =
+                    Some(Duration::try_from_secs_f64(secs).map_err(|_| {
+                        invalid_arg(format!("invalid value {:?} for {}", secs, flag))
+                    })?);
+            }
+            "--no-keep-alive" if inline.is_none() => keep_alive = false,
+            _ if arg.starts_with('-') || target.is_some() => {
+                return Err(invalid_arg(format!(
+                    "unknown bench argument {:?} (see --help)",
+                    arg
+                )))
+            }
+            _ => target = Some(arg.clone()),
+        }
     }
-
-    #[test]
-    fn pool_size_uses_env_when_valid() {
-        assert_eq!(compute_pool_size(Some("5".into()), Some(8)), 5);
+    let target = target.ok_or_else(|| invalid_arg("bench expects <TARGET>".into()))?;
+    let mut bench = Bench::new(&target)?
+        .connections(connections)
+        .keep_alive(keep_alive);
+    if let Some(requests) = requests {
+        bench = bench.requests(requests);
     }
-
-    #[test]
-    fn pool_size_falls_back_to_available_parallelism() {
-        assert_eq!(compute_pool_size(None, Some(6)), 6);
-    }
-
-    #[test]
-    fn pool_size_uses_default_when_unset_and_unavailable() {
-        assert_eq!(compute_pool_size(None, None), 4);
+    if let Some(duration) = duration {
+        bench = bench.duration(duration);
     }
+    Ok(bench)
+}
 
-    #[test]
-    fn pool_size_uses_default_when_env_invalid_and_unavailable() {
-        assert_eq!(compute_pool_size(Some("abc".into()), None), 4);
+fn run(args: Args) -> io::Result<ExitCode> {
+    if args.help {
+        print!("{}", USAGE);
+        return Ok(ExitCode::SUCCESS);
     }
-
-    #[test]
-    fn read_body_times_out_when_deadline_passed() {
-        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
-        let addr = listener.local_addr().unwrap();
-        let server = thread::spawn(move || {
-            let (mut stream, _) = listener.accept().unwrap();
-            stream
-                .set_read_timeout(Some(Duration::from_millis(100)))
-                .ok();
-            let deadline = Instant::now() - Duration::from_millis(1);
-            let err = read_body(&mut stream, 1, deadline)
-                .expect_err("expected read_body to time out");
-            (err.kind(), err.to_string())
-        });
-        let _client = TcpStream::connect(addr).unwrap();
-        let (kind, msg) = server.join().unwrap();
-        assert_eq!(kind, std::io::ErrorKind::TimedOut);
-        assert!(
-            msg.contains("Body read timeout"),
-            "expected body timeout message, got: {}",
-            msg
-        );
+    if args.version {
+        println!("ok {}", env!("CARGO_PKG_VERSION"));
+        return Ok(ExitCode::SUCCESS);
     }
-
-    #[test]
-    fn read_body_succeeds_before_deadline() {
-        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
-        let addr = listener.local_addr().unwrap();
-        let server = thread::spawn(move || {
-            let (mut stream, _) = listener.accept().unwrap();
-            let deadline = Instant::now() + Duration::from_secs(1);
-            read_body(&mut stream, 4, deadline).unwrap();
+    if let Some(replay) = &args.replay {
+        return Ok(if replay::run(replay)? {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
         });
-        let mut client = TcpStream::connect(addr).unwrap();
-        client.write_all(b"test").unwrap();
-        server.join().unwrap();
     }
-
-    fn run_request(raw: &str) -> Vec<u8> {
-        use std::net::Shutdown;
-
-        let listener = TcpListener::bind("127.0.0.1:0").expect("bind test listener");
-        let addr = listener.local_addr().unwrap();
-        let (log_tx, _log_rx) = sync_channel::<String>(1);
-        let server = thread::spawn(move || {
-            if let Ok((stream, _)) = listener.accept() {
-                handle_connection(stream, log_tx, false);
-            }
+    if let Some(bench) = &args.bench {
+        println!("Benchmarking {}", bench.url());
+        let report = bench.run();
+        print!("{}", report);
+        return Ok(if report.errors().is_empty() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
         });
-
-        let mut client = TcpStream::connect(addr).expect("connect to test listener");
-        client
-            .write_all(raw.as_bytes())
-            .expect("write request to server");
-        let _ = client.shutdown(Shutdown::Write);
-        let mut buf = Vec::new();
-        client.read_to_end(&mut buf).expect("read response");
-        let _ = server.join();
-        buf
-    }
-
-    #[test]
-    fn rejects_oversized_content_length_with_413() {
-        let request = format!(
-            "GET / HTTP/1.1\r\nHost: example\r\nContent-Length: {}\r\n\r\n",
-            MAX_BODY_SIZE + 1
-        );
-        let response_bytes = run_request(&request);
-        let response = String::from_utf8_lossy(&response_bytes);
-        assert!(
-            response.starts_with("HTTP/1.1 413"),
-            "unexpected response: {}",
-            response
-        );
-    }
-
-    #[test]
-    fn accepts_valid_content_length() {
-        let request = "GET / HTTP/1.1\r\nHost: example\r\nContent-Length: 5\r\n\r\nhello";
-        let response_bytes = run_request(request);
-        let response = String::from_utf8_lossy(&response_bytes);
-        assert!(
-            response.starts_with("HTTP/1.1 200"),
-            "unexpected response: {}",
-            response
-        );
     }
-
-    #[test]
-    fn rejects_chunked_requests_with_501() {
-        let request = "GET / HTTP/1.1\r\nHost: example\r\nTransfer-Encoding: chunked\r\n\r\n";
-        let response_bytes = run_request(request);
-        let response = String::from_utf8_lossy(&response_bytes);
-        assert!(
-            response.starts_with("HTTP/1.1 501"),
-            "unexpected response: {}",
-            response
+    let flags = args.flags();
+    let config = Config::load(&flags, args.config.as_deref())?;
+    if args.print_config {
+        print!("{}", config);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.check_config {
+        println!("Configuration OK");
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.health_check {
+        let use_tls = config.tls.is_some();
+        return Ok(
+            if health_check(
+                (config.bind, config.port).into(),
+                use_tls,
+                &config.health_check_path,
+            ) {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            },
         );
     }
-
-    fn make_stream_pair() -> (TcpStream, TcpStream) {
-        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
-        let addr = listener.local_addr().unwrap();
-        let client = TcpStream::connect(addr).unwrap();
-        let (server, _) = listener.accept().unwrap();
-        (client, server)
-    }
-
-    #[test]
-    fn retries_across_workers_when_queue_full() {
-        let (tx1, rx1) = sync_channel::<TcpStream>(1);
-        let (tx2, rx2) = sync_channel::<TcpStream>(1);
-        let mut senders = vec![Some(tx1), Some(tx2)];
-        let (client1, _server1) = make_stream_pair();
-        // Fill first queue
-        senders[0].as_ref().unwrap().try_send(client1).unwrap();
-
-        let (client2, server2) = make_stream_pair();
-        let (log_tx, _log_rx) = sync_channel::<String>(10);
-        let mut next = 0;
-
-        let dispatched = dispatch_connection(&mut senders, client2, &log_tx, &mut next);
-        assert!(dispatched);
-        let recv2 = rx2.try_recv().expect("worker 2 should receive");
-        assert_eq!(recv2.peer_addr().unwrap(), server2.local_addr().unwrap());
-        assert!(rx1.try_recv().is_ok());
-    }
-
-    #[test]
-    fn advances_round_robin_counter_with_multiple_workers() {
-        let (tx1, _rx1) = sync_channel::<TcpStream>(1);
-        let (tx2, rx2) = sync_channel::<TcpStream>(1);
-        let mut senders = vec![Some(tx1), Some(tx2)];
-        let (client, _server) = make_stream_pair();
-        let (log_tx, _log_rx) = sync_channel::<String>(10);
-        let mut next = 0;
-        let dispatched = dispatch_connection(&mut senders, client, &log_tx, &mut next);
-        assert!(dispatched);
-        assert_eq!(next, 1);
-        let _ = rx2.try_recv();
-    }
-
-    #[test]
-    fn drops_after_all_workers_tried() {
-        let (tx1, rx1) = sync_channel::<TcpStream>(1);
-        let (tx2, rx2) = sync_channel::<TcpStream>(1);
-        let mut senders = vec![Some(tx1), Some(tx2)];
-        let (client1, _server1) = make_stream_pair();
-        let (client2, _server2) = make_stream_pair();
-        senders[0].as_ref().unwrap().try_send(client1).unwrap();
-        senders[1].as_ref().unwrap().try_send(client2).unwrap();
-
-        let (client3, _server3) = make_stream_pair();
-        let (log_tx, log_rx) = sync_channel::<String>(10);
-        let mut next = 0;
-
-        let dispatched = dispatch_connection(&mut senders, client3, &log_tx, &mut next);
-        assert!(!dispatched);
-        assert!(log_rx.try_iter().any(|m| m.contains("unavailable")));
-        drop(rx1);
-        drop(rx2);
-    }
-
-    #[test]
-    fn skips_disconnected_workers() {
-        let (tx1, _rx1) = sync_channel::<TcpStream>(1);
-        let (tx2, rx2) = sync_channel::<TcpStream>(1);
-        let mut senders = vec![Some(tx1), Some(tx2)];
-        // Drop receiver for worker 0 to force Disconnected
-        drop(_rx1);
-
-        let (client, _server) = make_stream_pair();
-        let (log_tx, log_rx) = sync_channel::<String>(10);
-        let mut next = 0;
-
-        let dispatched = dispatch_connection(&mut senders, client, &log_tx, &mut next);
-        assert!(dispatched);
-        assert!(rx2.try_recv().is_ok());
-        assert!(senders[0].is_none());
-        assert!(log_rx.try_iter().any(|m| m.contains("disconnected")));
-    }
-}
-
-fn read_headers(stream: &mut TcpStream) -> std::io::Result<String> {
-    let mut buffer = [0u8; MAX_HEADER_SIZE];
-    let mut total_read = 0;
-    let mut temp = [0u8; 512];
-
-    let start_time = Instant::now();
-    let deadline = Duration::from_secs(5);
-
-    loop {
-        if start_time.elapsed() > deadline {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::TimedOut,
-                "Header read timeout",
-            ));
-        }
-
-        let n = stream.read(&mut temp)?;
-        if n == 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "Connection closed before full header was received",
+    if args.shutdown {
+        let Some(admin) = config.admin else {
+            return Err(invalid_arg(
+                "--shutdown needs the admin API (ADMIN_PORT)".into(),
             ));
-        }
-        if total_read + n > MAX_HEADER_SIZE {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Header too large",
-            ));
-        }
-        buffer[total_read..total_read + n].copy_from_slice(&temp[..n]);
-        total_read += n;
-
-        let start = total_read.saturating_sub(n + 3);
-        if buffer[start..total_read]
-            .windows(4)
-            .any(|w| w == b"\r\n\r\n")
-        {
-            break;
-        }
+        };
+        return Ok(if request_shutdown(admin) {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
     }
 
-    Ok(String::from_utf8_lossy(&buffer[..total_read]).to_string())
-}
-
-fn read_body(
-    stream: &mut TcpStream,
-    mut remaining: usize,
-    deadline: Instant,
-) -> std::io::Result<()> {
-    if remaining > MAX_BODY_SIZE {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Body too large",
-        ));
+    let server = config.server_builder()?.start()?;
+    println!(
+        "Listening on {} with {} worker threads",
+        server.local_addr(),
+        server.pool_size()
+    );
+    if let Some(admin_addr) = server.admin_addr() {
+        println!("Admin API listening on {}", admin_addr);
     }
-    let mut buf = [0u8; 4096];
-    while remaining > 0 {
-        // The socket read timeout bounds a single blocking read; this deadline enforces a
-        // total time budget across the full body.
-        if Instant::now() >= deadline {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::TimedOut,
-                "Body read timeout",
-            ));
-        }
-        let to_read = std::cmp::min(buf.len(), remaining);
-        let n = stream.read(&mut buf[..to_read])?;
-        if n == 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "Connection closed before full body was received",
-            ));
-        }
-        remaining -= n;
+    if let (Some(path), Some(table)) = (args.config, server.route_table()) {
+        let (sequences, logger) = (server.sequence_table(), server.logger());
+        thread::spawn(move || config::watch_routes(path, flags, table, sequences, logger));
     }
-    Ok(())
+    server.wait();
+    Ok(ExitCode::SUCCESS)
 }
 
-fn get_client_address(stream: &mut TcpStream, headers: &str) -> String {
-    for line in headers.lines() {
-        if let Some(val) = line
-            .strip_prefix("X-Forwarded-For:")
-            .or_else(|| line.strip_prefix("x-forwarded-for:"))
-        {
-            return sanitize(val.trim().split(',').next().unwrap_or(""));
+fn main() -> ExitCode {
+    match parse_args(env::args().skip(1)).and_then(run) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("ok: {}", e);
+            ExitCode::from(2)
         }
     }
-    stream
-        .peer_addr()
-        .map(|a| sanitize(&a.to_string()))
-        .unwrap_or_else(|_| "unknown".into())
 }
 
-fn handle_connection(mut stream: TcpStream, log_tx: SyncSender<String>, show_favicon: bool) {
-    let timeout = Duration::from_secs(5);
-    stream.set_read_timeout(Some(timeout)).ok();
-    stream.set_write_timeout(Some(timeout)).ok();
-
-    let headers = match read_headers(&mut stream) {
-        Ok(h) => h,
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::TimedOut => {
-                let _ = stream.write_all(RESPONSE_408);
-                return;
-            }
-            std::io::ErrorKind::InvalidData => {
-                let _ = stream.write_all(RESPONSE_431);
-                return;
-            }
-            _ => return,
-        },
-    };
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, Args, Bench, ReplayArgs};
+    use std::net::IpAddr;
+    use std::path::PathBuf;
+    use std::time::Duration;
 
-    for line in headers.lines() {
-        let lower = line.to_ascii_lowercase();
-        if lower.starts_with("transfer-encoding:") && lower.contains("chunked") {
-            let _ = stream.write_all(RESPONSE_501);
-            return;
-        }
+    fn args(list: &[&str]) -> std::io::Result<Args> {
+        parse_args(list.iter().map(|s| s.to_string()))
     }
 
-    let mut content_length = 0;
-    for line in headers.lines() {
-        if line.len() >= 15 && line[..15].eq_ignore_ascii_case("content-length:") {
-            let val = &line[15..];
-            if content_length != 0 {
-                let _ = stream.write_all(RESPONSE_431);
-                return;
-            }
-            match val.trim().parse::<usize>() {
-                Ok(len) => {
-                    if len > MAX_BODY_SIZE {
-                        let _ = stream.write_all(RESPONSE_413);
-                        return;
-                    }
-                    content_length = len;
-                }
-                Err(_) => {
-                    let _ = stream.write_all(RESPONSE_431);
-                    return;
-                }
+    #[test]
+    fn parses_all_options() {
+        let parsed = args(&[
+            "--port",
+            "9000",
+            "--bind=127.0.0.1",
+            "-t",
+            "3",
+            "--no-favicon",
+            "--config",
+            "ok.conf",
+        ])
+        .unwrap();
+        assert_eq!(
+            parsed,
+            Args {
+                port: Some(9000),
+                bind: Some(IpAddr::from([127, 0, 0, 1])),
+                threads: Some(3),
+                no_favicon: true,
+                config: Some(PathBuf::from("ok.conf")),
+                ..Args::default()
             }
-        }
-    }
-
-    let peer = get_client_address(&mut stream, &headers);
-    let request_line = headers.lines().next().unwrap_or("");
-    let byte_count = headers.len().saturating_add(content_length);
-    let log_message = format!(
-        "{} \"{}\" {} bytes",
-        peer,
-        sanitize(request_line),
-        byte_count
-    );
-    match log_tx.try_send(log_message) {
-        Ok(_) => {}
-        Err(TrySendError::Full(_)) => {}
-        Err(TrySendError::Disconnected(_)) => {
-            eprintln!("log channel disconnected – log entry lost");
-        }
+        );
+        assert!(args(&["--health-check"]).unwrap().health_check);
+        assert!(args(&["--shutdown"]).unwrap().shutdown);
+        assert!(args(&["-h"]).unwrap().help);
+        assert!(args(&["--version"]).unwrap().version);
     }
 
-    let (method, path) = parse_request_line(request_line);
-
-    match (method, path) {
-        (m @ ("GET" | "HEAD"), "/") => {
-            let _ = stream.write_all(OK_HEADER);
-            if m == "GET" {
-                let _ = stream.write_all(OK_BODY);
-            }
-        }
-        (m @ ("GET" | "HEAD"), "/favicon.ico") if show_favicon => {
-            let _ = stream.write_all(FAVICON_HEADER);
-            if m == "GET" {
-                let _ = stream.write_all(FAVICON_PNG);
-            }
-        }
-        ("HEAD", _) => {
-            let _ = stream.write_all(RESPONSE_404);
-        }
-        (method, _) if method != "GET" && method != "HEAD" => {
-            let _ = stream.write_all(RESPONSE_501);
-        }
-        _ => {
-            if content_length > 0 {
-                let body_deadline = Instant::now() + body_timeout_duration();
-                if let Err(e) = read_body(&mut stream, content_length, body_deadline) {
-                    match e.kind() {
-                        std::io::ErrorKind::InvalidData => {
-                            let _ = stream.write_all(RESPONSE_413);
-                        }
-                        std::io::ErrorKind::TimedOut => {
-                            let _ = stream.write_all(RESPONSE_408);
-                        }
-                        _ => {}
-                    }
-                    return;
-                }
-            }
-            let _ = stream.write_all(RESPONSE_404);
+    #[test]
+    fn rejects_invalid_arguments() {
+        for invalid in [
+            &["--port", "99999"][..],
+            &["--port"],
+            &["--bind", "localhost"],
+            &["--threads", "0"],
+            &["--no-favicon=yes"],
+            &["--verbose"],
+        ] {
+            assert!(args(invalid).is_err(), "{:?}", invalid);
         }
     }
-    let _ = stream.flush();
-}
 
-fn main() -> std::io::Result<()> {
-    let mut args = std::env::args();
-    if args.len() == 2 && args.nth(1).as_deref() == Some("--health-check") {
-        std::process::exit(0);
+    #[test]
+    fn parses_the_replay_subcommand() {
+        let parsed = args(&["replay", "--fast", "requests.jsonl", "localhost:9000"]).unwrap();
+        assert_eq!(
+            parsed.replay,
+            Some(ReplayArgs {
+                file: PathBuf::from("requests.jsonl"),
+                target: "localhost:9000".into(),
+                fast: true,
+            })
+        );
+        assert!(args(&["replay", "requests.jsonl"]).is_err());
+        assert!(args(&["replay", "--port", "1", "a", "b"]).is_err());
     }
-    let port: u16 = env::var("PORT")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(8080);
-    let bind_addr = format!("0.0.0.0:{}", port);
-    let show_favicon = env::var("SHOW_FAVICON")
-        .map(|v| !v.eq_ignore_ascii_case("false"))
-        .unwrap_or(true);
-    let pool_size = compute_pool_size(
-        env::var("THREAD_POOL_SIZE").ok(),
-        thread::available_parallelism().map(|n| n.get()).ok(),
-    );
-
-    let listener = TcpListener::bind(&bind_addr)?;
-    println!(
-        "Listening on {} with {} worker threads",
-        bind_addr, pool_size
-    );
-
-    let (log_tx, log_rx) = sync_channel::<String>(LOG_QUEUE_CAPACITY);
-    thread::spawn(move || {
-        for msg in log_rx {
-            println!("{}", msg);
-        }
-    });
 
-    let mut senders: Vec<Option<SyncSender<TcpStream>>> = Vec::with_capacity(pool_size);
-    for _ in 0..pool_size {
-        let (tx, rx) = sync_channel::<TcpStream>(QUEUE_CAPACITY);
-        senders.push(Some(tx));
-        let log_tx_clone = log_tx.clone();
-        let show_favicon = show_favicon;
-        thread::spawn(move || {
-            for stream in rx {
-                if let Err(err) = panic::catch_unwind(|| {
-                    handle_connection(stream, log_tx_clone.clone(), show_favicon)
-                }) {
-                    eprintln!("Worker thread panicked: {:?}", err);
-                }
-            }
-        });
+    #[test]
+    fn parses_the_bench_subcommand() {
+        let parsed = args(&[
+            "bench",
+            "-c",
+            "8",
+            "--requests=100",
+            "-d",
+            "1.5",
+            "localhost:9000/a",
+        ]);
+        assert_eq!(
+            parsed.unwrap().bench,
+            Some(
+                Bench::new("localhost:9000/a")
+                    .unwrap()
+                    .connections(8)
+                    .requests(100)
+                    .duration(Duration::from_millis(1500))
+            )
+        );
+        let parsed = args(&["bench", "--no-keep-alive", "localhost:9000"]).unwrap();
+        assert_eq!(
+            parsed.bench,
+            Some(Bench::new("localhost:9000").unwrap().keep_alive(false))
+        );
+        assert!(args(&["bench"]).is_err());
+        assert!(args(&["bench", "-c", "0", "localhost:9000"]).is_err());
+        assert!(args(&["bench", "-d", "-1", "localhost:9000"]).is_err());
+        assert!(args(&["bench", "a:1", "b:2"]).is_err());
     }
 
-    let mut next = 0;
-    for incoming in listener.incoming() {
-        let stream = match incoming {
-            Ok(s) => s,
-            Err(e) => {
-                let _ = log_tx.try_send(format!("Accept error: {}", e));
-                continue;
-            }
-        };
-        dispatch_connection(&mut senders, stream, &log_tx, &mut next);
+    #[test]
+    fn maps_flags_to_setting_names() {
+        let parsed = args(&["--no-favicon", "-p", "9000", "--print-config"]).unwrap();
+        assert!(parsed.print_config);
+        assert_eq!(
+            parsed.flags(),
+            [
+                ("PORT", "9000".to_string()),
+                ("SHOW_FAVICON", "false".to_string())
+            ]
+        );
     }
-    Ok(())
 }
@@ -0,0 +1,102 @@
+//! Kubernetes-style probe endpoints served by the built-in handler:
+//! `/livez` answers while the process runs, `/startupz` once the startup delay
+//! has passed, and `/readyz` while the server is started, marked ready and
+//! not draining for shutdown.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::handler::Response;
+
+/// Probe state shared by the handler, the gRPC health service and [`Server`].
+///
+/// [`Server`]: crate::Server
+#[derive(Debug)]
+pub(crate) struct Probes {
+    started_at: Instant,
+    startup_delay: Duration,
+    ready: AtomicBool,
+    draining: AtomicBool,
+}
+
+impl Probes {
+    pub(crate) fn new(startup_delay: Duration) -> Self {
+        Probes {
+            started_at: Instant::now(),
+            startup_delay,
+            ready: AtomicBool::new(true),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn is_started(&self) -> bool {
+        self.started_at.elapsed() >= self.startup_delay
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        self.is_started()
+            && self.ready.load(Ordering::Relaxed)
+            && !self.draining.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    /// Marks the server as shutting down; readiness stays off from then on.
+    pub(crate) fn start_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// The probe response for `path`, or `None` if it is not a probe path.
+    pub(crate) fn respond(&self, path: &str) -> Option<Response> {
+        let (up, down_reason) = match path {
+            "/livez" => (true, ""),
+            "/startupz" => (self.is_started(), "starting"),
+            "/readyz" => (self.is_ready(), "not ready"),
+            _ => return None,
+        };
+        let response = if up {
+            Response::new(200).with_body(&b"OK"[..])
+        } else {
+            Response::new(503).with_body(down_reason.as_bytes().to_vec())
+        };
+        Some(
+            response
+                .with_header("Content-Type", "text/plain; charset=utf-8")
+                .with_header("Cache-Control", "no-store"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Probes;
+    use std::time::Duration;
+
+    fn status(probes: &Probes, path: &str) -> u16 {
+        probes.respond(path).unwrap().status()
+    }
+
+    #[test]
+    fn reports_not_started_during_startup_delay() {
+        let probes = Probes::new(Duration::from_secs(60));
+        assert_eq!(status(&probes, "/livez"), 200);
+        assert_eq!(status(&probes, "/startupz"), 503);
+        assert_eq!(status(&probes, "/readyz"), 503);
+        assert!(probes.respond("/").is_none());
+    }
+
+    #[test]
+    fn readiness_follows_toggle_and_draining() {
+        let probes = Probes::new(Duration::ZERO);
+        assert_eq!(status(&probes, "/readyz"), 200);
+        probes.set_ready(false);
+        assert_eq!(status(&probes, "/readyz"), 503);
+        probes.set_ready(true);
+        probes.start_draining();
+        assert_eq!(status(&probes, "/readyz"), 503);
+        assert_eq!(status(&probes, "/startupz"), 200);
+        assert_eq!(status(&probes, "/livez"), 200);
+    }
+}
@@ -0,0 +1,223 @@
+//! Byte range requests: `206 Partial Content` with one range or a
+//! `multipart/byteranges` body, and `416 Range Not Satisfiable`, for
+//! responses that advertise `Accept-Ranges: bytes`.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::conditional::parse_http_date;
+use crate::handler::{BodyPart, Request, Response};
+
+/// More ranges than this in one request are ignored and the whole body is
+/// sent, rather than building a response out of many tiny parts.
+const MAX_RANGES: usize = 32;
+
+/// Answers a `GET` with a `Range` header with the ranges it asks for instead
+/// of the whole of `response`, unless an `If-Range` validator no longer
+/// matches it.
+pub(crate) fn partial_content(request: &Request, response: Response) -> Response {
+    if request.method() != "GET"
+        || response.status() != 200
+        || response.fault().is_some()
+        || !response
+            .header("accept-ranges")
+            .is_some_and(|units| units.eq_ignore_ascii_case("bytes"))
+    {
+        return response;
+    }
+    let Some(range) = request.header("range") else {
+        return response;
+    };
+    if let Some(validator) = request.header("if-range") {
+        if !if_range_matches(validator.trim(), &response) {
+            return response;
+        }
+    }
+    let len = response.body_len();
+    let Some(ranges) = parse_ranges(range, len) else {
+        return response;
+    };
+    match ranges.as_slice() {
+        [] => Response::new(416).with_header("Content-Range", format!("bytes */{}", len)),
+        [(start, end)] => response
+            .with_status(206)
+            .with_header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+            .with_body_parts(vec![BodyPart::Range {
+                start: *start,
+                len: end - start + 1,
+            }]),
+        _ => multipart(response, &ranges, len),
+    }
+}
+
+/// Whether `If-Range` names the current representation: its strong `ETag`,
+/// or exactly its `Last-Modified` date.
+fn if_range_matches(validator: &str, response: &Response) -> bool {
+    if validator.starts_with('"') {
+        return response.header("etag") == Some(validator);
+    }
+    if validator.starts_with("W/") {
+        return false;
+    }
+    let modified = response.header("last-modified").and_then(parse_http_date);
+    modified.is_some() && modified == parse_http_date(validator)
+}
+
+/// The inclusive ranges a `bytes=` header selects from a body of `len`
+/// bytes, dropping the unsatisfiable ones. `None` when the header is
+/// malformed, uses another unit or asks for too many ranges, so it is
+/// ignored.
+fn parse_ranges(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+    let (unit, specs) = header.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let specs: Vec<&str> = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        let range = if first.is_empty() {
+            let suffix: u64 = last.parse().ok()?;
+            (suffix > 0 && len > 0).then(|| (len - suffix.min(len), len - 1))
+        } else {
+            let first: u64 = first.parse().ok()?;
+            let last = match last {
+                "" => u64::MAX,
+                last => last.parse().ok()?,
+            };
+            if last < first {
+                return None;
+            }
+            (first < len).then(|| (first, last.min(len - 1)))
+        };
+        ranges.extend(range);
+    }
+    Some(ranges)
+}
+
+/// A `multipart/byteranges` body with one part per range, each carrying the
+/// original `Content-Type`.
+fn multipart(response: Response, ranges: &[(u64, u64)], len: u64) -> Response {
+    let boundary = format!("ok-{:016x}", RandomState::new().build_hasher().finish());
+    let content_type = response.header("content-type").map(str::to_string);
+    let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+    for &(start, end) in ranges {
+        let mut head = format!("\r\n--{}\r\n", boundary);
+        if let Some(content_type) = &content_type {
+            head.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        head.push_str(&format!(
+            "Content-Range: bytes {}-{}/{}\r\n\r\n",
+            start, end, len
+        ));
+        parts.push(BodyPart::Bytes(head.into_bytes()));
+        parts.push(BodyPart::Range {
+            start,
+            len: end - start + 1,
+        });
+    }
+    parts.push(BodyPart::Bytes(
+        format!("\r\n--{}--\r\n", boundary).into_bytes(),
+    ));
+    response
+        .with_status(206)
+        .replace_header(
+            "Content-Type",
+            format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .with_body_parts(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_ranges, partial_content};
+    use crate::handler::{Request, Response};
+
+    fn digits() -> Response {
+        Response::text("0123456789")
+            .with_header("Accept-Ranges", "bytes")
+            .with_header("ETag", "\"digits\"")
+            .with_header("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT")
+    }
+
+    fn get(range: &str) -> Request {
+        Request::new("GET", "/").with_header("Range", range.to_string())
+    }
+
+    #[test]
+    fn parses_range_specs() {
+        assert_eq!(parse_ranges("bytes=0-4", 10), Some(vec![(0, 4)]));
+        assert_eq!(parse_ranges("bytes=5-", 10), Some(vec![(5, 9)]));
+        assert_eq!(parse_ranges("bytes=-3", 10), Some(vec![(7, 9)]));
+        assert_eq!(parse_ranges("bytes=-30", 10), Some(vec![(0, 9)]));
+        assert_eq!(
+            parse_ranges("bytes=8-20, 0-0", 10),
+            Some(vec![(8, 9), (0, 0)])
+        );
+        assert_eq!(parse_ranges("bytes=10-, -0", 10), Some(vec![]));
+        assert_eq!(parse_ranges("bytes=5-4", 10), None);
+        assert_eq!(parse_ranges("bytes=a-b", 10), None);
+        assert_eq!(parse_ranges("items=0-1", 10), None);
+        assert_eq!(
+            parse_ranges(&format!("bytes={}", "0-0,".repeat(33)), 10),
+            None
+        );
+    }
+
+    #[test]
+    fn answers_single_and_multiple_ranges() {
+        let response = partial_content(&get("bytes=2-4"), digits());
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.header("content-range"), Some("bytes 2-4/10"));
+        assert_eq!(response.body(), b"234");
+
+        let response = partial_content(&get("bytes=0-1,-2"), digits());
+        assert_eq!(response.status(), 206);
+        let content_type = response.header("content-type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8_lossy(response.body()), expected);
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        let response = partial_content(&get("bytes=10-20"), digits());
+        assert_eq!(response.status(), 416);
+        assert_eq!(response.header("content-range"), Some("bytes */10"));
+        assert!(response.body().is_empty());
+    }
+
+    #[test]
+    fn sends_the_whole_body_when_ranges_do_not_apply() {
+        let whole = |request: &Request, response| partial_content(request, response).status();
+        assert_eq!(whole(&get("bytes=0-1"), Response::text("0123456789")), 200);
+        assert_eq!(whole(&get("bytes=x"), digits()), 200);
+        let head = Request::new("HEAD", "/").with_header("Range", "bytes=0-1");
+        assert_eq!(whole(&head, digits()), 200);
+
+        let current = get("bytes=0-1").with_header("If-Range", "\"digits\"");
+        assert_eq!(whole(&current, digits()), 206);
+        let date = get("bytes=0-1").with_header("If-Range", "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(whole(&date, digits()), 206);
+        let stale = get("bytes=0-1").with_header("If-Range", "\"other\"");
+        assert_eq!(whole(&stale, digits()), 200);
+        let weak = get("bytes=0-1").with_header("If-Range", "W/\"digits\"");
+        assert_eq!(whole(&weak, digits()), 200);
+    }
+}
@@ -0,0 +1,85 @@
+//! Record mode: each public request and the response sent for it, written as
+//! one JSON line through the log thread, for `ok replay` to re-issue later.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::handler::{Handler, Middleware, Request, Response};
+use crate::logging::LogSender;
+use crate::{json_body, json_pairs, json_string};
+
+/// Queues a record of every request that passes through it.
+pub(crate) struct Recorder {
+    log_tx: LogSender,
+}
+
+impl Recorder {
+    pub(crate) fn new(log_tx: LogSender) -> Self {
+        Recorder { log_tx }
+    }
+}
+
+impl Middleware for Recorder {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let response = next.handle(request);
+        self.log_tx.record(record_json(request, &response));
+        response
+    }
+}
+
+fn record_json(request: &Request, response: &Response) -> String {
+    let time_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis());
+    let target = match request.query() {
+        Some(query) => format!("{}?{}", request.path(), query),
+        None => request.path().to_string(),
+    };
+    format!(
+        "{{\"time_ms\":{},\"peer\":{},\"method\":{},\"target\":{},\"version\":{},\
+         \"headers\":{},{},\"response\":{{\"status\":{},\"headers\":{},{}}}}}",
+        time_ms,
+        json_string(request.peer()),
+        json_string(request.method()),
+        json_string(&target),
+        json_string(request.version()),
+        json_pairs(request.headers()),
+        json_body(request.body()),
+        response.status(),
+        json_pairs(response.headers_to_send()),
+        json_body(response.body()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Recorder;
+    use crate::handler::{Chain, Handler, Request, Response};
+    use crate::logging::{log_channel, LogLevel};
+
+    #[test]
+    fn records_requests_with_their_responses() {
+        let (log_tx, log_rx) = log_channel(10, LogLevel::Off);
+        let chain = Chain::new(
+            vec![Box::new(Recorder::new(log_tx))],
+            Box::new(|_: &Request| Response::text("OK")),
+        );
+        let request = Request::new("POST", "/orders?id=1")
+            .with_header("Host", "example.com")
+            .with_body(vec![0xff]);
+        chain.handle(&request);
+        let records: Vec<String> = log_rx.try_iter().collect();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].starts_with("{\"time_ms\":"), "{}", records[0]);
+        assert!(
+            records[0].ends_with(concat!(
+                "\"peer\":\"unknown\",\"method\":\"POST\",\"target\":\"/orders?id=1\",",
+                "\"version\":\"HTTP/1.1\",\"headers\":[[\"Host\",\"example.com\"]],",
+                "\"body_base64\":\"/w==\",\"response\":{\"status\":200,",
+                "\"headers\":[[\"Content-Type\",\"text/plain; charset=utf-8\"]],",
+                "\"body\":\"OK\"}}"
+            )),
+            "{}",
+            records[0]
+        );
+    }
+}
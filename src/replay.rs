@@ -0,0 +1,511 @@
+//! `ok replay`: re-issues the requests in a `RECORD_PATH` file against another
+//! server, with their original spacing or as fast as possible.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Headers that describe the recorded connection rather than the request;
+/// replay sets its own.
+const CONNECTION_HEADERS: &[&str] = &[
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Arguments of `ok replay`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ReplayArgs {
+    pub(crate) file: PathBuf,
+    /// `host:port`, optionally prefixed with `http://`.
+    pub(crate) target: String,
+    /// Send the next request as soon as the previous one is answered.
+    pub(crate) fast: bool,
+}
+
+/// One recorded request.
+#[derive(Debug, PartialEq, Eq)]
+struct Record {
+    time_ms: u64,
+    method: String,
+    target: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    status: u16,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Replays every record in `args.file`, printing one line per request.
+/// Returns whether every request was answered.
+pub(crate) fn run(args: &ReplayArgs) -> io::Result<bool> {
+    let authority = authority(&args.target)?;
+    let records = read_records(&args.file)?;
+    let started = Instant::now();
+    let first_ms = records.first().map_or(0, |record| record.time_ms);
+    let mut failed = 0;
+    for record in &records {
+        if !args.fast {
+            let due = Duration::from_millis(record.time_ms.saturating_sub(first_ms));
+            thread::sleep(due.saturating_sub(started.elapsed()));
+        }
+        match send(authority, record) {
+            Ok(status) => println!(
+                "{} {} -> {} (recorded {})",
+                record.method, record.target, status, record.status
+            ),
+            Err(e) => {
+                failed += 1;
+                println!("{} {} -> error: {}", record.method, record.target, e);
+            }
+        }
+    }
+    println!(
+        "Replayed {} requests to {} in {:.1?}, {} failed",
+        records.len(),
+        authority,
+        started.elapsed(),
+        failed
+    );
+    Ok(failed == 0)
+}
+
+fn authority(target: &str) -> io::Result<&str> {
+    if target.starts_with("https://") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "replay only supports plain http targets",
+        ));
+    }
+    let authority = target.strip_prefix("http://").unwrap_or(target);
+    Ok(authority.trim_end_matches('/'))
+}
+
+fn read_records(path: &Path) -> io::Result<Vec<Record>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            parse_record(line)
+                .map_err(|e| invalid(format!("{} line {}: {}", path.display(), number + 1, e)))
+        })
+        .collect()
+}
+
+fn parse_record(line: &str) -> Result<Record, String> {
+    let json = Json::parse(line)?;
+    let string = |value: Option<&Json>, name: &str| match value {
+        Some(Json::String(s)) => Ok(s.clone()),
+        _ => Err(format!("expected a string {:?}", name)),
+    };
+    let number = |value: Option<&Json>, name: &str| match value {
+        Some(Json::Number(n)) if *n >= 0.0 => Ok(*n as u64),
+        _ => Err(format!("expected a number {:?}", name)),
+    };
+    let headers = match json.get("headers") {
+        Some(Json::Array(pairs)) => pairs
+            .iter()
+            .map(|pair| match pair {
+                Json::Array(pair) => match pair.as_slice() {
+                    [Json::String(name), Json::String(value)] => Ok((name.clone(), value.clone())),
+                    _ => Err("expected [name, value] header pairs".to_string()),
+                },
+                _ => Err("expected [name, value] header pairs".to_string()),
+            })
+            .collect::<Result<_, _>>()?,
+        _ => return Err("expected a \"headers\" array".into()),
+    };
+    let body = match (json.get("body"), json.get("body_base64")) {
+        (Some(Json::String(body)), _) => body.clone().into_bytes(),
+        (_, Some(Json::String(encoded))) => decode_base64(encoded)?,
+        _ => Vec::new(),
+    };
+    let status = json
+        .get("response")
+        .map_or(Ok(0), |response| number(response.get("status"), "status"))?;
+    Ok(Record {
+        time_ms: number(json.get("time_ms"), "time_ms")?,
+        method: string(json.get("method"), "method")?,
+        target: string(json.get("target"), "target")?,
+        headers,
+        body,
+        status: u16::try_from(status).map_err(|_| "invalid status".to_string())?,
+    })
+}
+
+/// Sends `record` over a new connection and returns the response status.
+fn send(authority: &str, record: &Record) -> io::Result<u16> {
+    let mut stream = TcpStream::connect(authority)?;
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\n",
+        record.method, record.target, authority
+    );
+    for (name, value) in &record.headers {
+        if !CONNECTION_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        record.body.len()
+    ));
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&record.body)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let status_line = response.split(|&b| b == b'\n').next().unwrap_or(&[]);
+    String::from_utf8_lossy(status_line)
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid("malformed response".into()))
+}
+
+fn decode_base64(encoded: &str) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in encoded.bytes().filter(|&c| c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return Err("invalid base64 body".into()),
+        };
+        buffer = buffer << 6 | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(decoded)
+}
+
+/// Just enough JSON to read records back.
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser {
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("invalid JSON at byte {}: {}", self.pos, message)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(|b| b.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), String> {
+        if self.bytes[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {}", literal)))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.bytes.get(self.pos) {
+            Some(b'n') => self.expect("null").map(|_| Json::Null),
+            Some(b't') => self.expect("true").map(|_| Json::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return Err(self.error("expected ',' or ']'")),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let name = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    members.push((name, self.value()?));
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(members));
+                        }
+                        _ => return Err(self.error("expected ',' or '}'")),
+                    }
+                }
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                while self
+                    .bytes
+                    .get(self.pos)
+                    .is_some_and(|b| matches!(b, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'))
+                {
+                    self.pos += 1;
+                }
+                std::str::from_utf8(&self.bytes[start..self.pos])
+                    .ok()
+                    .and_then(|number| number.parse().ok())
+                    .map(Json::Number)
+                    .ok_or_else(|| self.error("invalid number"))
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect("\"")?;
+        let mut bytes = Vec::new();
+        loop {
+            let Some(&b) = self.bytes.get(self.pos) else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.bytes.get(self.pos).copied();
+                    self.pos += 1;
+                    let c = match escape {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut utf8 = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+                }
+                b => bytes.push(b),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("invalid UTF-8"))
+    }
+
+    /// The character of a `\uXXXX` escape, including surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let first = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&first) {
+            self.expect("\\u")?;
+            let second = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&second) {
+                return Err(self.error("invalid surrogate pair"));
+            }
+            0x10000 + ((first - 0xD800) << 10) + (second - 0xDC00)
+        } else {
+            first
+        };
+        char::from_u32(code).ok_or_else(|| self.error("invalid \\u escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("invalid \\u escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{authority, decode_base64, parse_record, run, Json, Record, ReplayArgs};
+    use ok::Server;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn parses_json() {
+        let json = Json::parse(r#" {"a": [1, -2.5e1, true, null], "b": "x\"\u00e9\ud83d\ude00"} "#)
+            .unwrap();
+        assert_eq!(
+            json.get("a"),
+            Some(&Json::Array(vec![
+                Json::Number(1.0),
+                Json::Number(-25.0),
+                Json::Bool(true),
+                Json::Null
+            ]))
+        );
+        assert_eq!(json.get("b"), Some(&Json::String("x\"é😀".into())));
+        for invalid in ["", "{", "[1,]", "\"a", "{\"a\" 1}", "1 2", "\"\\x\""] {
+            assert!(Json::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_records() {
+        let line = concat!(
+            "{\"time_ms\":1700000000123,\"peer\":\"127.0.0.1:5000\",\"method\":\"POST\",",
+            "\"target\":\"/orders?id=1\",\"version\":\"HTTP/1.1\",",
+            "\"headers\":[[\"Host\",\"x\"],[\"Accept\",\"*/*\"]],\"body_base64\":\"/w==\",",
+            "\"response\":{\"status\":201,\"headers\":[],\"body\":\"\"}}"
+        );
+        assert_eq!(
+            parse_record(line).unwrap(),
+            Record {
+                time_ms: 1700000000123,
+                method: "POST".into(),
+                target: "/orders?id=1".into(),
+                headers: vec![("Host".into(), "x".into()), ("Accept".into(), "*/*".into())],
+                body: vec![0xff],
+                status: 201,
+            }
+        );
+        assert!(parse_record("{\"method\":\"GET\"}").is_err());
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("Zm9vYmFy").unwrap(), b"foobar");
+        assert_eq!(decode_base64("Zm8=").unwrap(), b"fo");
+        assert_eq!(decode_base64("Zg==").unwrap(), b"f");
+        assert!(decode_base64("Z!").is_err());
+    }
+
+    #[test]
+    fn accepts_http_targets_only() {
+        assert_eq!(
+            authority("http://localhost:8080/").unwrap(),
+            "localhost:8080"
+        );
+        assert_eq!(authority("10.0.0.1:80").unwrap(), "10.0.0.1:80");
+        assert!(authority("https://example.com").is_err());
+    }
+
+    #[test]
+    fn replays_recorded_requests() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .capture_requests(10)
+            .log_sink(|_: &str| {})
+            .start()
+            .unwrap();
+        let record = |time_ms: u64, method: &str, target: &str, body: &str| {
+            format!(
+                "{{\"time_ms\":{},\"method\":\"{}\",\"target\":\"{}\",\
+                 \"headers\":[[\"Host\",\"old\"],[\"X-Id\",\"1\"]],\"body\":\"{}\",\
+                 \"response\":{{\"status\":200,\"headers\":[],\"body\":\"OK\"}}}}\n",
+                time_ms, method, target, body
+            )
+        };
+        let path = std::env::temp_dir().join(format!("ok-replay-{}.jsonl", std::process::id()));
+        let records = record(1000, "GET", "/a?x=1", "") + &record(1050, "PUT", "/b", "data");
+        fs::write(&path, records).unwrap();
+
+        let args = ReplayArgs {
+            file: path.clone(),
+            target: format!("http://{}", server.local_addr()),
+            fast: false,
+        };
+        let started = std::time::Instant::now();
+        assert!(run(&args).unwrap());
+        assert!(started.elapsed() >= std::time::Duration::from_millis(50));
+        fs::remove_file(&path).unwrap();
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream
+            .write_all(b"GET /__requests HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let mut captured = String::new();
+        stream.read_to_string(&mut captured).unwrap();
+        assert!(
+            captured.contains("\"path\":\"/a\",\"query\":\"x=1\""),
+            "{}",
+            captured
+        );
+        assert!(captured.contains("\"method\":\"PUT\""), "{}", captured);
+        assert!(captured.contains("\"body\":\"data\""), "{}", captured);
+        assert!(captured.contains("[\"X-Id\",\"1\"]"), "{}", captured);
+        assert!(!captured.contains("old"), "{}", captured);
+        server.shutdown();
+    }
+}
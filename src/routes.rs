@@ -0,0 +1,465 @@
+//! The built-in routes: `OK` at `/`, the favicon, configured static
+//! responses, and 404/501 for everything else.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use crate::compression::precompress;
+use crate::conditional::etag;
+use crate::handler::{Handler, Request, Response};
+use crate::probes::Probes;
+use crate::static_files::content_type;
+
+const OK_BODY: &[u8] = b"OK";
+
+const FAVICON_PNG: &[u8] = &[
+    0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x10, 0x08, 0x03, 0x00, 0x00, 0x00, 0x28, 0x2D, 0x0F,
+    0x53, 0x00, 0x00, 0x00, 0x06, 0x50, 0x4C, 0x54, 0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xA5,
+    0x67, 0xB9, 0xCF, 0x00, 0x00, 0x00, 0x01, 0x74, 0x52, 0x4E, 0x53, 0x00, 0x40, 0xE6, 0xD8, 0x66,
+    0x00, 0x00, 0x00, 0x2A, 0x49, 0x44, 0x41, 0x54, 0x18, 0xD3, 0x63, 0x60, 0xA0, 0x0E, 0x60, 0x64,
+    0x64, 0x04, 0x93, 0x10, 0x0C, 0x26, 0xA1, 0x1C, 0x08, 0x9F, 0x81, 0x11, 0x26, 0x08, 0xE3, 0x13,
+    0x14, 0x40, 0x68, 0x61, 0x40, 0x28, 0x41, 0x35, 0x14, 0xC3, 0x5A, 0x8A, 0x01, 0x00, 0x20, 0xDE,
+    0x00, 0x3D, 0xEB, 0xB1, 0x31, 0x2A, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42,
+    0x60, 0x82,
+];
+
+/// What the built-in handler answers at `/favicon.ico`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Favicon {
+    /// A small built-in PNG.
+    #[default]
+    Builtin,
+    /// An icon loaded with [`Favicon::load`].
+    Custom { content_type: String, body: Vec<u8> },
+    /// `204 No Content`, which browsers accept without logging an error.
+    NoContent,
+    /// `404 Not Found`, as for any other unknown path.
+    NotFound,
+}
+
+impl Favicon {
+    /// Reads an `.ico`, `.png` or `.svg` file, taking its content type from
+    /// the extension.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        if !matches!(extension.as_deref(), Some("ico" | "png" | "svg")) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: expected an .ico, .png or .svg file", path.display()),
+            ));
+        }
+        let body = fs::read(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        Ok(Favicon::Custom {
+            content_type: content_type(path).to_string(),
+            body,
+        })
+    }
+
+    fn response(self) -> Option<Response> {
+        let (content_type, body): (Cow<'static, str>, Cow<'static, [u8]>) = match self {
+            Favicon::Builtin => ("image/png".into(), FAVICON_PNG.into()),
+            Favicon::Custom { content_type, body } => (content_type.into(), body.into()),
+            Favicon::NoContent => {
+                return Some(
+                    Response::new(204).with_header("Cache-Control", "public, max-age=86400"),
+                )
+            }
+            Favicon::NotFound => return None,
+        };
+        Some(precompress(
+            Response::new(200)
+                .with_header("Content-Type", content_type)
+                .with_header("Cache-Control", "public, max-age=86400")
+                .with_header("ETag", etag(&body))
+                .with_header("Accept-Ranges", "bytes")
+                .with_body(body),
+        ))
+    }
+}
+
+/// A fixed response served for `GET` and `HEAD` requests to a configured path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StaticResponse {
+    status: u16,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+impl StaticResponse {
+    pub fn new(status: u16, content_type: impl Into<String>, body: impl Into<Vec<u8>>) -> Self {
+        StaticResponse {
+            status,
+            content_type: content_type.into(),
+            body: body.into(),
+        }
+    }
+
+    /// A `200` response with a `text/plain` body.
+    pub fn text(body: impl Into<String>) -> Self {
+        Self::new(200, "text/plain; charset=utf-8", body.into().into_bytes())
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+/// The configured routes of a server and the `Cache-Control` values set on
+/// them, shared with its workers. Replacing them swaps the whole table at
+/// once: requests already being handled finish with the table they started
+/// with, and invalid routes leave it unchanged.
+#[derive(Clone)]
+pub struct RouteTable {
+    routes: Arc<RwLock<Arc<Routes>>>,
+}
+
+struct Routes {
+    responses: HashMap<String, Response>,
+    /// `Cache-Control` by path, replacing any the response has.
+    cache_control: HashMap<String, String>,
+}
+
+impl RouteTable {
+    /// Validates `routes` and builds their responses once.
+    pub fn new(routes: HashMap<String, StaticResponse>) -> io::Result<Self> {
+        Self::with_cache_control(routes, HashMap::new())
+    }
+
+    pub(crate) fn with_cache_control(
+        routes: HashMap<String, StaticResponse>,
+        cache_control: HashMap<String, String>,
+    ) -> io::Result<Self> {
+        validate_cache_control(&cache_control)?;
+        let routes = Routes {
+            responses: build_routes(routes)?,
+            cache_control,
+        };
+        Ok(RouteTable {
+            routes: Arc::new(RwLock::new(Arc::new(routes))),
+        })
+    }
+
+    /// Replaces every route and every `Cache-Control` value together, or
+    /// returns an error and keeps both if any new one is invalid.
+    pub fn replace(
+        &self,
+        routes: HashMap<String, StaticResponse>,
+        cache_control: HashMap<String, String>,
+    ) -> io::Result<()> {
+        validate_cache_control(&cache_control)?;
+        let routes = Arc::new(Routes {
+            responses: build_routes(routes)?,
+            cache_control,
+        });
+        *self.routes.write().unwrap_or_else(PoisonError::into_inner) = routes;
+        Ok(())
+    }
+
+    fn current(&self) -> Arc<Routes> {
+        Arc::clone(&self.routes.read().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+fn build_routes(routes: HashMap<String, StaticResponse>) -> io::Result<HashMap<String, Response>> {
+    routes
+        .into_iter()
+        .map(|(path, response)| {
+            let response = build_response(&path, response)?;
+            Ok((path, response))
+        })
+        .collect()
+}
+
+/// Validates a configured response for `path` and builds it.
+pub(crate) fn build_response(path: &str, response: StaticResponse) -> io::Result<Response> {
+    let invalid = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("route {}: {}", path, reason),
+        )
+    };
+    if !path.starts_with('/') {
+        return Err(invalid("path must start with '/'"));
+    }
+    if !(200..=599).contains(&response.status) {
+        return Err(invalid("status must be between 200 and 599"));
+    }
+    if response.content_type.chars().any(|c| c.is_control()) {
+        return Err(invalid("content type must not contain control characters"));
+    }
+    Ok(precompress(
+        Response::new(response.status)
+            .with_header("Content-Type", response.content_type)
+            .with_header("ETag", etag(&response.body))
+            .with_body(response.body),
+    ))
+}
+
+/// Checks `Cache-Control` values by path before they are served.
+fn validate_cache_control(cache_control: &HashMap<String, String>) -> io::Result<()> {
+    for (path, value) in cache_control {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cache control for {}: {}", path, reason),
+            )
+        };
+        if !path.starts_with('/') {
+            return Err(invalid("path must start with '/'"));
+        }
+        if value.is_empty() || value.chars().any(|c| c.is_control()) {
+            return Err(invalid(
+                "value must be non-empty without control characters",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The handler used unless the embedder supplies their own.
+pub(crate) struct DefaultHandler {
+    ok: Response,
+    /// `None` when `/favicon.ico` is not found.
+    favicon: Option<Response>,
+    routes: RouteTable,
+    probes: Arc<Probes>,
+}
+
+impl DefaultHandler {
+    pub(crate) fn new(
+        favicon: Favicon,
+        routes: HashMap<String, StaticResponse>,
+        cache_control: HashMap<String, String>,
+        probes: Arc<Probes>,
+    ) -> io::Result<Self> {
+        Ok(DefaultHandler {
+            ok: Response::new(200)
+                .with_header("Content-Type", "text/plain; charset=utf-8")
+                .with_header("ETag", etag(OK_BODY))
+                .with_body(OK_BODY),
+            favicon: favicon.response(),
+            routes: RouteTable::with_cache_control(routes, cache_control)?,
+            probes,
+        })
+    }
+
+    pub(crate) fn route_table(&self) -> RouteTable {
+        self.routes.clone()
+    }
+}
+
+impl Handler for DefaultHandler {
+    /// Configured routes take precedence over the built-in ones.
+    fn handle(&self, request: &Request) -> Response {
+        if request.method() != "GET" && request.method() != "HEAD" {
+            return Response::new(501);
+        }
+        let routes = self.routes.current();
+        let response = match routes.responses.get(request.path()) {
+            Some(response) => response.clone(),
+            None => {
+                if let Some(response) = self.probes.respond(request.path()) {
+                    return response;
+                }
+                match request.path() {
+                    "/" => self.ok.clone(),
+                    "/favicon.ico" => self.favicon.clone().unwrap_or_else(|| Response::new(404)),
+                    _ => return Response::new(404),
+                }
+            }
+        };
+        match routes.cache_control.get(request.path()) {
+            Some(value) => response.replace_header("Cache-Control", value.clone()),
+            None => response,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DefaultHandler, Favicon, StaticResponse, OK_BODY};
+    use crate::conditional::etag;
+    use crate::handler::{Handler, Request};
+    use crate::probes::Probes;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn probes() -> Arc<Probes> {
+        Arc::new(Probes::new(Duration::ZERO))
+    }
+
+    fn handler_with(path: &str, response: StaticResponse) -> DefaultHandler {
+        let routes = HashMap::from([(path.to_string(), response)]);
+        DefaultHandler::new(Favicon::Builtin, routes, HashMap::new(), probes()).unwrap()
+    }
+
+    fn handler(favicon: Favicon) -> DefaultHandler {
+        DefaultHandler::new(favicon, HashMap::new(), HashMap::new(), probes()).unwrap()
+    }
+
+    fn status(handler: &DefaultHandler, method: &str, path: &str) -> u16 {
+        handler.handle(&Request::new(method, path)).status()
+    }
+
+    #[test]
+    fn routes_built_in_paths() {
+        let handler = handler(Favicon::Builtin);
+        let ok = handler.handle(&Request::new("GET", "/?probe=1"));
+        assert_eq!(ok.status(), 200);
+        assert_eq!(ok.body(), OK_BODY);
+        let favicon = handler.handle(&Request::new("HEAD", "/favicon.ico"));
+        assert_eq!(favicon.header("content-type"), Some("image/png"));
+        assert_eq!(status(&handler, "GET", "/missing"), 404);
+        assert_eq!(status(&handler, "POST", "/"), 501);
+        assert_eq!(status(&handler, "GET", "/readyz"), 200);
+    }
+
+    #[test]
+    fn hides_favicon_when_disabled() {
+        assert_eq!(
+            status(&handler(Favicon::NotFound), "GET", "/favicon.ico"),
+            404
+        );
+        assert_eq!(
+            status(&handler(Favicon::NoContent), "GET", "/favicon.ico"),
+            204
+        );
+    }
+
+    #[test]
+    fn loads_favicons_from_files() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("ok-favicon-{}.svg", std::process::id()));
+        std::fs::write(&path, "<svg/>").unwrap();
+        let favicon = Favicon::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let handler = handler(favicon.unwrap());
+        let response = handler.handle(&Request::new("GET", "/favicon.ico"));
+        assert_eq!(response.header("content-type"), Some("image/svg+xml"));
+        assert_eq!(response.body(), b"<svg/>");
+        assert!(Favicon::load(dir.join("favicon.gif")).is_err());
+        assert!(Favicon::load(dir.join("ok-missing-favicon.png")).is_err());
+    }
+
+    #[test]
+    fn tags_responses_and_sets_cache_control() {
+        let routes = HashMap::from([("/data".to_string(), StaticResponse::text("x"))]);
+        let cache_control = HashMap::from([
+            ("/".to_string(), "no-cache".to_string()),
+            ("/data".to_string(), "max-age=60".to_string()),
+            ("/favicon.ico".to_string(), "max-age=3600".to_string()),
+        ]);
+        let handler =
+            DefaultHandler::new(Favicon::Builtin, routes, cache_control, probes()).unwrap();
+        let get = |path| handler.handle(&Request::new("GET", path));
+        assert_eq!(get("/").header("etag"), Some(etag(OK_BODY).as_str()));
+        assert_eq!(get("/").header("cache-control"), Some("no-cache"));
+        assert_eq!(get("/data").header("etag"), Some(etag(b"x").as_str()));
+        assert_eq!(get("/data").header("cache-control"), Some("max-age=60"));
+        let favicon = get("/favicon.ico");
+        assert_eq!(
+            favicon
+                .headers()
+                .filter(|(n, _)| *n == "Cache-Control")
+                .count(),
+            1
+        );
+        assert_eq!(favicon.header("cache-control"), Some("max-age=3600"));
+        assert!(favicon.header("etag").is_some());
+
+        let invalid = HashMap::from([("/".to_string(), "a\r\nb".to_string())]);
+        let handler = DefaultHandler::new(Favicon::Builtin, HashMap::new(), invalid, probes());
+        assert!(handler.is_err());
+    }
+
+    #[test]
+    fn configured_routes_take_precedence() {
+        let handler = handler_with("/", StaticResponse::text("hello"));
+        let response = handler.handle(&Request::new("GET", "/"));
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), b"hello");
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/plain; charset=utf-8")
+        );
+    }
+
+    #[test]
+    fn replaces_routes_atomically() {
+        let handler = handler_with("/a", StaticResponse::text("a"));
+        let table = handler.route_table();
+        let routes = HashMap::from([("/b".to_string(), StaticResponse::text("b"))]);
+        table.replace(routes, HashMap::new()).unwrap();
+        assert_eq!(status(&handler, "GET", "/a"), 404);
+        assert_eq!(status(&handler, "GET", "/b"), 200);
+
+        let invalid = HashMap::from([
+            ("/c".to_string(), StaticResponse::text("c")),
+            ("d".to_string(), StaticResponse::text("d")),
+        ]);
+        assert!(table.replace(invalid, HashMap::new()).is_err());
+        assert_eq!(status(&handler, "GET", "/b"), 200);
+        assert_eq!(status(&handler, "GET", "/c"), 404);
+    }
+
+    #[test]
+    fn replaces_cache_control_with_the_routes() {
+        let cache_control = HashMap::from([("/a".to_string(), "no-store".to_string())]);
+        let routes = HashMap::from([("/a".to_string(), StaticResponse::text("a"))]);
+        let handler =
+            DefaultHandler::new(Favicon::Builtin, routes, cache_control, probes()).unwrap();
+        let table = handler.route_table();
+        let cache_control = |path| {
+            let response = handler.handle(&Request::new("GET", path));
+            response.header("cache-control").map(str::to_string)
+        };
+        assert_eq!(cache_control("/a").as_deref(), Some("no-store"));
+
+        let routes = HashMap::from([("/a".to_string(), StaticResponse::text("b"))]);
+        let replaced = HashMap::from([("/".to_string(), "max-age=60".to_string())]);
+        table.replace(routes, replaced).unwrap();
+        assert_eq!(cache_control("/a"), None);
+        assert_eq!(cache_control("/").as_deref(), Some("max-age=60"));
+
+        let invalid = HashMap::from([("/a".to_string(), "a\r\nb".to_string())]);
+        assert!(table.replace(HashMap::new(), invalid).is_err());
+        assert_eq!(status(&handler, "GET", "/a"), 200);
+        assert_eq!(cache_control("/").as_deref(), Some("max-age=60"));
+    }
+
+    #[test]
+    fn rejects_invalid_routes() {
+        let invalid = [
+            ("no-slash", StaticResponse::text("x")),
+            ("/", StaticResponse::new(99, "text/plain", "x")),
+            (
+                "/",
+                StaticResponse::new(200, "text/plain\r\nX-Injected: 1", "x"),
+            ),
+        ];
+        for (path, response) in invalid {
+            let routes = HashMap::from([(path.to_string(), response)]);
+            let handler = DefaultHandler::new(Favicon::Builtin, routes, HashMap::new(), probes());
+            assert!(handler.is_err());
+        }
+    }
+}
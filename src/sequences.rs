@@ -0,0 +1,266 @@
+//! Scripted response sequences for deterministic retry and circuit-breaker
+//! tests: each request to a sequence's path gets the next response in its
+//! list, for example three `503`s followed by `200 OK`.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use crate::handler::{Handler, Middleware, Request, Response};
+use crate::routes::{build_response, StaticResponse};
+
+/// Responses served in order, one per request, for every method. After the
+/// last one the sequence sticks on it unless it [wraps](Sequence::wrap).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sequence {
+    responses: Vec<StaticResponse>,
+    per_client: bool,
+    wrap: bool,
+}
+
+impl Sequence {
+    pub fn new(responses: impl IntoIterator<Item = StaticResponse>) -> Self {
+        Sequence {
+            responses: responses.into_iter().collect(),
+            per_client: false,
+            wrap: false,
+        }
+    }
+
+    /// Keeps a separate position for each client IP address instead of one
+    /// shared by all clients.
+    pub fn per_client(mut self, per_client: bool) -> Self {
+        self.per_client = per_client;
+        self
+    }
+
+    /// Starts over after the last response instead of repeating it.
+    pub fn wrap(mut self, wrap: bool) -> Self {
+        self.wrap = wrap;
+        self
+    }
+}
+
+struct Cursor {
+    responses: Vec<Response>,
+    per_client: bool,
+    wrap: bool,
+    /// Position by client IP, or under `""` when shared.
+    positions: Mutex<HashMap<String, usize>>,
+}
+
+impl Cursor {
+    fn next(&self, request: &Request) -> Response {
+        let client = if self.per_client {
+            client_ip(request.peer())
+        } else {
+            String::new()
+        };
+        let mut positions = self
+            .positions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let position = positions.entry(client).or_insert(0);
+        let response = self.responses[*position].clone();
+        let last = self.responses.len() - 1;
+        if *position < last {
+            *position += 1;
+        } else if self.wrap {
+            *position = 0;
+        }
+        response
+    }
+}
+
+/// The peer's IP address without the port, so reconnecting clients keep their
+/// position.
+fn client_ip(peer: &str) -> String {
+    peer.parse::<SocketAddr>()
+        .map_or_else(|_| peer.to_string(), |addr| addr.ip().to_string())
+}
+
+/// The configured sequences of a server and their positions, shared with its
+/// workers. Replacing them swaps the whole table at once and starts every
+/// sequence over; invalid sequences leave it unchanged.
+#[derive(Clone)]
+pub struct SequenceTable {
+    cursors: Arc<RwLock<Arc<HashMap<String, Cursor>>>>,
+}
+
+impl SequenceTable {
+    /// Validates `sequences` and builds their responses once.
+    pub(crate) fn new(sequences: HashMap<String, Sequence>) -> io::Result<Self> {
+        Ok(SequenceTable {
+            cursors: Arc::new(RwLock::new(Arc::new(build_cursors(sequences)?))),
+        })
+    }
+
+    /// Replaces every sequence, or returns an error and keeps the current ones
+    /// if any new sequence is invalid.
+    pub fn replace(&self, sequences: HashMap<String, Sequence>) -> io::Result<()> {
+        let cursors = Arc::new(build_cursors(sequences)?);
+        *self.cursors.write().unwrap_or_else(PoisonError::into_inner) = cursors;
+        Ok(())
+    }
+
+    /// Moves every sequence back to its first response, for every client.
+    pub(crate) fn reset(&self) {
+        for cursor in self.current().values() {
+            cursor
+                .positions
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+    }
+
+    fn current(&self) -> Arc<HashMap<String, Cursor>> {
+        Arc::clone(&self.cursors.read().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+fn build_cursors(sequences: HashMap<String, Sequence>) -> io::Result<HashMap<String, Cursor>> {
+    sequences
+        .into_iter()
+        .map(|(path, sequence)| {
+            if sequence.responses.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("sequence {}: needs at least one response", path),
+                ));
+            }
+            let responses = sequence
+                .responses
+                .into_iter()
+                .map(|response| build_response(&path, response))
+                .collect::<io::Result<_>>()?;
+            let cursor = Cursor {
+                responses,
+                per_client: sequence.per_client,
+                wrap: sequence.wrap,
+                positions: Mutex::new(HashMap::new()),
+            };
+            Ok((path, cursor))
+        })
+        .collect()
+}
+
+/// Answers requests to sequence paths; other requests go to the handler.
+pub(crate) struct Sequencer {
+    sequences: SequenceTable,
+}
+
+impl Sequencer {
+    pub(crate) fn new(sequences: SequenceTable) -> Self {
+        Sequencer { sequences }
+    }
+}
+
+impl Middleware for Sequencer {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        match self.sequences.current().get(request.path()) {
+            Some(cursor) => cursor.next(request),
+            None => next.handle(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sequence, SequenceTable, Sequencer};
+    use crate::handler::{Chain, Handler, Request, Response};
+    use crate::routes::StaticResponse;
+    use std::collections::HashMap;
+
+    fn setup(sequence: Sequence) -> (Chain, SequenceTable) {
+        let sequences = HashMap::from([("/flaky".to_string(), sequence)]);
+        let sequences = SequenceTable::new(sequences).unwrap();
+        let chain = Chain::new(
+            vec![Box::new(Sequencer::new(sequences.clone()))],
+            Box::new(|_: &Request| Response::new(404)),
+        );
+        (chain, sequences)
+    }
+
+    fn fail_then_succeed() -> Sequence {
+        let unavailable = StaticResponse::new(503, "text/plain", "down");
+        Sequence::new([unavailable.clone(), unavailable, StaticResponse::text("OK")])
+    }
+
+    fn statuses(chain: &Chain, requests: &[Request]) -> Vec<u16> {
+        requests
+            .iter()
+            .map(|request| chain.handle(request).status())
+            .collect()
+    }
+
+    #[test]
+    fn sticks_on_the_last_response() {
+        let (chain, sequences) = setup(fail_then_succeed());
+        let get = Request::new("GET", "/flaky");
+        let requests = vec![get; 5];
+        assert_eq!(statuses(&chain, &requests), [503, 503, 200, 200, 200]);
+        assert_eq!(chain.handle(&Request::new("GET", "/other")).status(), 404);
+        sequences.reset();
+        assert_eq!(chain.handle(&Request::new("POST", "/flaky")).status(), 503);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let (chain, _) = setup(fail_then_succeed().wrap(true));
+        let requests = vec![Request::new("GET", "/flaky"); 4];
+        assert_eq!(statuses(&chain, &requests), [503, 503, 200, 503]);
+    }
+
+    #[test]
+    fn keeps_a_position_per_client() {
+        let (chain, _) = setup(fail_then_succeed().per_client(true));
+        let from = |peer: &str| {
+            let mut request = Request::new("GET", "/flaky");
+            request.peer = peer.to_string();
+            request
+        };
+        let requests = [
+            from("10.0.0.1:5000"),
+            from("10.0.0.1:5001"),
+            from("10.0.0.2:5000"),
+            from("10.0.0.1:5002"),
+        ];
+        assert_eq!(statuses(&chain, &requests), [503, 503, 503, 200]);
+    }
+
+    #[test]
+    fn rejects_invalid_sequences() {
+        for sequence in [
+            Sequence::new([]),
+            Sequence::new([StaticResponse::new(99, "text/plain", "")]),
+        ] {
+            let sequences = HashMap::from([("/x".to_string(), sequence)]);
+            assert!(SequenceTable::new(sequences).is_err());
+        }
+        let sequences = HashMap::from([("x".to_string(), fail_then_succeed())]);
+        assert!(SequenceTable::new(sequences).is_err());
+    }
+
+    #[test]
+    fn replaces_sequences_atomically() {
+        let (chain, sequences) = setup(fail_then_succeed());
+        chain.handle(&Request::new("GET", "/flaky"));
+        let replaced = HashMap::from([
+            ("/flaky".to_string(), fail_then_succeed()),
+            (
+                "/new".to_string(),
+                Sequence::new([StaticResponse::text("new")]),
+            ),
+        ]);
+        sequences.replace(replaced).unwrap();
+        let requests = [Request::new("GET", "/flaky"), Request::new("GET", "/new")];
+        assert_eq!(statuses(&chain, &requests), [503, 200]);
+
+        let invalid = HashMap::from([("/other".to_string(), Sequence::new([]))]);
+        assert!(sequences.replace(invalid).is_err());
+        assert_eq!(statuses(&chain, &requests), [503, 200]);
+        assert_eq!(chain.handle(&Request::new("GET", "/other")).status(), 404);
+    }
+}
@@ -0,0 +1,852 @@
+//! The embeddable server: listener, worker pool and log thread.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::admin::{AdminHandler, Controls, Runtime};
+use crate::capture::{Capture, RequestLog};
+use crate::conditional::FinishResponse;
+use crate::diagnostics::Diagnostics;
+use crate::faults::{FaultInjector, Faults};
+use crate::grpc::GrpcHealth;
+use crate::handler::{AccessLog, BoxedHandler, BoxedMiddleware, Chain, Handler, Middleware};
+use crate::http1::handle_connection;
+use crate::logging::{
+    log_channel, AccessLogFilter, LogLevel, LogSender, LogSink, Logger, StdoutSink,
+};
+use crate::probes::Probes;
+use crate::record::Recorder;
+use crate::routes::{DefaultHandler, Favicon, RouteTable, StaticResponse};
+use crate::sequences::{Sequence, SequenceTable, Sequencer};
+use crate::static_files::StaticFiles;
+#[cfg(feature = "tls")]
+use crate::tls;
+
+const QUEUE_CAPACITY: usize = 100;
+const LOG_QUEUE_CAPACITY: usize = 100;
+/// Admin connections served at once, each on its own thread; more are
+/// closed right away.
+const MAX_ADMIN_CONNECTIONS: usize = 16;
+/// How often the log thread reports entries dropped because the queue was full.
+const DROPPED_LOG_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Settings shared by every connection of a running server.
+pub(crate) struct ServerState {
+    pub(crate) require_host: bool,
+    pub(crate) handler: Chain,
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        ServerState::with_probes(Arc::new(Probes::new(Duration::ZERO)))
+    }
+}
+
+impl ServerState {
+    /// The built-in routes, reporting readiness from `probes`.
+    pub(crate) fn with_probes(probes: Arc<Probes>) -> Self {
+        let handler = DefaultHandler::new(
+            Favicon::Builtin,
+            HashMap::new(),
+            HashMap::new(),
+            Arc::clone(&probes),
+        )
+        .expect("no routes to validate");
+        let middleware: Vec<BoxedMiddleware> = vec![
+            Box::new(FinishResponse::new(true)),
+            Box::new(GrpcHealth::new(probes)),
+        ];
+        ServerState {
+            require_host: false,
+            handler: Chain::new(middleware, Box::new(handler)),
+        }
+    }
+}
+
+/// Configures a [`Server`]. Created with [`Server::builder`].
+pub struct ServerBuilder {
+    bind: SocketAddr,
+    pool_size: Option<usize>,
+    routes: HashMap<String, StaticResponse>,
+    cache_control: HashMap<String, String>,
+    sequences: HashMap<String, Sequence>,
+    diagnostics: bool,
+    static_dir: Option<(String, PathBuf)>,
+    capture: usize,
+    favicon: Favicon,
+    require_host: bool,
+    compression: bool,
+    handler: Option<BoxedHandler>,
+    middleware: Vec<BoxedMiddleware>,
+    log_sink: Box<dyn LogSink>,
+    record: Option<Box<dyn LogSink>>,
+    log_level: LogLevel,
+    access_log: AccessLogFilter,
+    startup_delay: Duration,
+    drain_period: Duration,
+    admin: Option<SocketAddr>,
+    faults: Faults,
+    #[cfg(feature = "tls")]
+    tls: Option<(PathBuf, PathBuf)>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            bind: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            pool_size: None,
+            routes: HashMap::new(),
+            cache_control: HashMap::new(),
+            sequences: HashMap::new(),
+            diagnostics: false,
+            static_dir: None,
+            capture: 0,
+            favicon: Favicon::Builtin,
+            require_host: false,
+            compression: true,
+            handler: None,
+            middleware: Vec::new(),
+            log_sink: Box::new(StdoutSink),
+            record: None,
+            log_level: LogLevel::default(),
+            access_log: AccessLogFilter::default(),
+            startup_delay: Duration::ZERO,
+            drain_period: Duration::ZERO,
+            admin: None,
+            faults: Faults::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Address to listen on. Port `0` picks a free port; see [`Server::local_addr`].
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind = addr;
+        self
+    }
+
+    /// Number of worker threads, at least one. Defaults to the available
+    /// parallelism, or 4 when that is unknown.
+    pub fn pool_size(mut self, size: usize) -> Self {
+        self.pool_size = Some(size.max(1));
+        self
+    }
+
+    /// Serves `response` to `GET` and `HEAD` requests for `path`, taking
+    /// precedence over the built-in routes. Ignored when a custom
+    /// [`handler`](Self::handler) is set.
+    pub fn route(mut self, path: impl Into<String>, response: StaticResponse) -> Self {
+        self.routes.insert(path.into(), response);
+        self
+    }
+
+    /// Sends `Cache-Control: value` with the built-in handler's responses for
+    /// `path`: a configured route, `/` or `/favicon.ico`. Ignored when a
+    /// custom [`handler`](Self::handler) is set.
+    pub fn cache_control(mut self, path: impl Into<String>, value: impl Into<String>) -> Self {
+        self.cache_control.insert(path.into(), value.into());
+        self
+    }
+
+    /// Answers every request to `path` with the next response of `sequence`,
+    /// before the handler and any added middleware see it.
+    pub fn sequence(mut self, path: impl Into<String>, sequence: Sequence) -> Self {
+        self.sequences.insert(path.into(), sequence);
+        self
+    }
+
+    /// Serves the httpbin-style diagnostic endpoints (`/status/{code}`,
+    /// `/delay/{ms}`, `/bytes/{n}`, `/headers`, `/ip` and `/anything`) before
+    /// the handler. Off by default.
+    pub fn diagnostics(mut self, enabled: bool) -> Self {
+        self.diagnostics = enabled;
+        self
+    }
+
+    /// Serves the files under `dir` at `prefix` for `GET` and `HEAD`, with
+    /// their content type guessed from the extension. Paths that name no file
+    /// go on to the handler, and `..` or symlinks cannot leave `dir`.
+    pub fn static_dir(mut self, prefix: impl Into<String>, dir: impl Into<PathBuf>) -> Self {
+        self.static_dir = Some((prefix.into(), dir.into()));
+        self
+    }
+
+    /// Keeps the last `capacity` public requests in memory, served as JSON at
+    /// `/__requests` (and `/requests` on the admin listener) and cleared with
+    /// `DELETE`. `0`, the default, disables capturing.
+    pub fn capture_requests(mut self, capacity: usize) -> Self {
+        self.capture = capacity;
+        self
+    }
+
+    /// Whether `/favicon.ico` is served by the built-in handler. Defaults to
+    /// `true`; `false` is the same as [`Favicon::NotFound`].
+    pub fn show_favicon(mut self, show: bool) -> Self {
+        self.favicon = if show {
+            Favicon::Builtin
+        } else {
+            Favicon::NotFound
+        };
+        self
+    }
+
+    /// What the built-in handler answers at `/favicon.ico`. Defaults to
+    /// [`Favicon::Builtin`].
+    pub fn favicon(mut self, favicon: Favicon) -> Self {
+        self.favicon = favicon;
+        self
+    }
+
+    /// Whether HTTP/1.1 requests without a `Host` header are rejected with 400.
+    /// Defaults to `false`, so probes that omit it keep working.
+    pub fn require_host(mut self, require: bool) -> Self {
+        self.require_host = require;
+        self
+    }
+
+    /// Whether text-like responses of at least 256 bytes are compressed with
+    /// `gzip` or `deflate` for clients that send `Accept-Encoding`. Defaults
+    /// to `true`.
+    pub fn compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Answers every request with `handler` instead of the built-in `OK`,
+    /// favicon and configured routes.
+    pub fn handler(mut self, handler: impl Handler + Send + Sync + 'static) -> Self {
+        self.handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Wraps the handler in `middleware`. The first middleware added is the
+    /// outermost, so it sees each request first and each response last.
+    pub fn middleware(mut self, middleware: impl Middleware + Send + Sync + 'static) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Receives every log line (access log, dropped connections, errors) on a
+    /// dedicated thread. Defaults to [`StdoutSink`].
+    pub fn log_sink(mut self, sink: impl LogSink + 'static) -> Self {
+        self.log_sink = Box::new(sink);
+        self
+    }
+
+    /// Writes every public request, with the response sent for it, to `sink`
+    /// as one JSON line that `ok replay` can re-issue. Records go through the
+    /// log queue, so they are dropped along with log lines when it is full.
+    pub fn record(mut self, sink: impl LogSink + 'static) -> Self {
+        self.record = Some(Box::new(sink));
+        self
+    }
+
+    /// Verbosity of server-event logging. Defaults to [`LogLevel::Info`]; access
+    /// logs are unaffected, see [`access_log`](Self::access_log).
+    pub fn log_level(mut self, level: LogLevel) -> Self {
+        self.log_level = level;
+        self
+    }
+
+    /// Selects which requests get an access-log line. Defaults to all of them.
+    pub fn access_log(mut self, filter: AccessLogFilter) -> Self {
+        self.access_log = filter;
+        self
+    }
+
+    /// How long after start `/startupz` and `/readyz` keep answering 503.
+    /// Defaults to zero.
+    pub fn startup_delay(mut self, delay: Duration) -> Self {
+        self.startup_delay = delay;
+        self
+    }
+
+    /// How long [`Server::shutdown`] keeps serving with `/readyz` answering
+    /// 503 before it stops accepting connections, so load balancers can stop
+    /// routing to it first. Defaults to zero.
+    pub fn drain_period(mut self, period: Duration) -> Self {
+        self.drain_period = period;
+        self
+    }
+
+    /// Serves the admin API on its own listener at `addr`, normally a loopback
+    /// address. It can mark the server ready or not ready, make `/` answer
+    /// `503 UNHEALTHY`, delay every response, and report request stats:
+    ///
+    /// - `GET /stats`
+    /// - `POST /ready`, `POST /unready`
+    /// - `POST /root/healthy`, `POST /root/unhealthy`
+    /// - `POST /latency?ms=N`
+    /// - `POST /shutdown`, which makes [`Server::wait`] shut the server down
+    pub fn admin(mut self, addr: SocketAddr) -> Self {
+        self.admin = Some(addr);
+        self
+    }
+
+    /// Injects latency, error responses and broken connections into public
+    /// requests, logging each injected fault. Off by default.
+    pub fn faults(mut self, faults: Faults) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Terminates TLS with the PEM certificate chain and private key at these
+    /// paths, reloading them when the files change.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        self.tls = Some((cert_path.into(), key_path.into()));
+        self
+    }
+
+    /// Binds the listener and starts serving on background threads.
+    pub fn start(self) -> io::Result<Server> {
+        self.faults.validate()?;
+        let sequences = SequenceTable::new(self.sequences)?;
+        let static_files = match &self.static_dir {
+            Some((prefix, dir)) => Some(StaticFiles::new(prefix, dir)?),
+            None => None,
+        };
+        let probes = Arc::new(Probes::new(self.startup_delay));
+        let (handler, route_table): (BoxedHandler, _) = match self.handler {
+            Some(handler) => (handler, None),
+            None => {
+                let probes = Arc::clone(&probes);
+                let handler =
+                    DefaultHandler::new(self.favicon, self.routes, self.cache_control, probes)?;
+                let route_table = handler.route_table();
+                (Box::new(handler), Some(route_table))
+            }
+        };
+        #[cfg(feature = "tls")]
+        let tls_acceptor = match self.tls {
+            Some((cert, key)) => Some(Arc::new(tls::TlsAcceptor::new(cert, key)?)),
+            None => None,
+        };
+        let listener = TcpListener::bind(self.bind)?;
+        let local_addr = listener.local_addr()?;
+        let admin_listener = match self.admin {
+            Some(addr) => Some(TcpListener::bind(addr)?),
+            None => None,
+        };
+        let pool_size = self
+            .pool_size
+            .unwrap_or_else(|| thread::available_parallelism().map_or(4, |n| n.get()));
+
+        let (log_tx, log_rx) = log_channel(LOG_QUEUE_CAPACITY, self.log_level);
+        let log = Arc::new(log_tx.clone());
+        let mut log_sink = self.log_sink;
+        let recording = self.record.is_some();
+        let mut record_sink = self.record;
+        let log_thread = thread::spawn(move || {
+            log_rx.run(
+                &mut *log_sink,
+                record_sink
+                    .as_mut()
+                    .map(|sink| &mut **sink as &mut dyn LogSink),
+                DROPPED_LOG_REPORT_INTERVAL,
+            )
+        });
+
+        let controls = Arc::new(Controls::new());
+        let access_log = AccessLog::new(log_tx.clone(), self.access_log);
+        let mut middleware: Vec<BoxedMiddleware> = vec![Box::new(access_log)];
+        let requests = (self.capture > 0).then(|| Arc::new(RequestLog::new(self.capture)));
+        if let Some(requests) = &requests {
+            middleware.push(Box::new(Capture::new(Arc::clone(requests))));
+        }
+        if recording {
+            middleware.push(Box::new(Recorder::new(log_tx.clone())));
+        }
+        middleware.push(Box::new(FinishResponse::new(self.compression)));
+        middleware.push(Box::new(Runtime::new(Arc::clone(&controls))));
+        if !self.faults.is_empty() {
+            middleware.push(Box::new(FaultInjector::new(self.faults, log_tx.clone())));
+        }
+        middleware.push(Box::new(GrpcHealth::new(Arc::clone(&probes))));
+        middleware.push(Box::new(Sequencer::new(sequences.clone())));
+        if self.diagnostics {
+            middleware.push(Box::new(Diagnostics));
+        }
+        if let Some(static_files) = static_files {
+            middleware.push(Box::new(static_files));
+        }
+        middleware.extend(self.middleware);
+        let state = Arc::new(ServerState {
+            require_host: self.require_host,
+            handler: Chain::new(middleware, handler),
+        });
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let mut admin = None;
+        if let Some(admin_listener) = admin_listener {
+            let admin_addr = admin_listener.local_addr()?;
+            let access_log = AccessLog::new(log_tx.clone(), AccessLogFilter::default());
+            let admin_state = Arc::new(ServerState {
+                require_host: false,
+                handler: Chain::new(
+                    vec![Box::new(access_log)],
+                    Box::new(AdminHandler::new(
+                        Arc::clone(&controls),
+                        Arc::clone(&probes),
+                        sequences.clone(),
+                        requests,
+                    )),
+                ),
+            });
+            let admin_stopping = Arc::clone(&stopping);
+            let admin_thread = thread::spawn(move || {
+                // A thread per connection, so a slow client cannot hold up
+                // the others.
+                let active = Arc::new(AtomicUsize::new(0));
+                for stream in admin_listener.incoming() {
+                    if admin_stopping.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    if active.fetch_add(1, Ordering::SeqCst) >= MAX_ADMIN_CONNECTIONS {
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        continue;
+                    }
+                    let state = Arc::clone(&admin_state);
+                    let active = Arc::clone(&active);
+                    thread::spawn(move || {
+                        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                            handle_connection(stream, &state)
+                        }));
+                        active.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+            });
+            admin = Some((admin_addr, admin_thread));
+        }
+
+        let mut senders: Vec<Option<SyncSender<TcpStream>>> = Vec::with_capacity(pool_size);
+        let mut workers = Vec::with_capacity(pool_size);
+        for _ in 0..pool_size {
+            let (tx, rx) = sync_channel::<TcpStream>(QUEUE_CAPACITY);
+            senders.push(Some(tx));
+            let log_tx_clone = log_tx.clone();
+            let state = Arc::clone(&state);
+            #[cfg(feature = "tls")]
+            let tls_acceptor = tls_acceptor.clone();
+            workers.push(thread::spawn(move || {
+                for stream in rx {
+                    if let Err(err) = panic::catch_unwind(AssertUnwindSafe(|| {
+                        #[cfg(feature = "tls")]
+                        if let Some(acceptor) = &tls_acceptor {
+                            match acceptor.accept(stream, &log_tx_clone) {
+                                Ok(tls_stream) => handle_connection(tls_stream, &state),
+                                Err(e) => log_tx_clone
+                                    .event(LogLevel::Info, format!("TLS handshake failed: {}", e)),
+                            }
+                            return;
+                        }
+                        handle_connection(stream, &state)
+                    })) {
+                        log_tx_clone.event(
+                            LogLevel::Error,
+                            format!("Worker thread panicked: {:?}", err),
+                        );
+                    }
+                }
+            }));
+        }
+
+        let accept_stopping = Arc::clone(&stopping);
+        let accept_thread = thread::spawn(move || {
+            let mut next = 0;
+            for incoming in listener.incoming() {
+                if accept_stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stream = match incoming {
+                    Ok(s) => s,
+                    Err(e) => {
+                        log_tx.event(LogLevel::Error, format!("Accept error: {}", e));
+                        continue;
+                    }
+                };
+                dispatch_connection(&mut senders, stream, &log_tx, &mut next);
+            }
+        });
+
+        Ok(Server {
+            local_addr,
+            pool_size,
+            route_table,
+            probes,
+            controls,
+            sequences,
+            drain_period: self.drain_period,
+            admin_addr: admin.as_ref().map(|(addr, _)| *addr),
+            admin_thread: admin.map(|(_, thread)| thread),
+            stopping,
+            accept_thread: Some(accept_thread),
+            workers,
+            log: Some(log),
+            log_thread: Some(log_thread),
+        })
+    }
+}
+
+/// A running server. Dropping it shuts the server down.
+pub struct Server {
+    local_addr: SocketAddr,
+    pool_size: usize,
+    route_table: Option<RouteTable>,
+    probes: Arc<Probes>,
+    controls: Arc<Controls>,
+    sequences: SequenceTable,
+    drain_period: Duration,
+    admin_addr: Option<SocketAddr>,
+    admin_thread: Option<JoinHandle<()>>,
+    stopping: Arc<AtomicBool>,
+    accept_thread: Option<JoinHandle<()>>,
+    workers: Vec<JoinHandle<()>>,
+    /// The only strong reference behind every [`Logger`]; dropped before the
+    /// log thread is joined.
+    log: Option<Arc<LogSender>>,
+    log_thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// The address the listener is bound to, with the actual port when the
+    /// server was bound to port `0`.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn pool_size(&self) -> usize {
+        self.pool_size
+    }
+
+    /// A handle for logging events through the server's log sink, e.g. from
+    /// a thread that watches a config file.
+    pub fn logger(&self) -> Logger {
+        Logger::new(self.log.as_ref().map_or_else(Weak::new, Arc::downgrade))
+    }
+
+    /// The address of the admin listener, if [`ServerBuilder::admin`] was set.
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    /// A handle for replacing the configured routes while the server runs, or
+    /// `None` when a custom [`handler`](ServerBuilder::handler) is set.
+    pub fn route_table(&self) -> Option<RouteTable> {
+        self.route_table.clone()
+    }
+
+    /// Marks the server ready or not ready for `/readyz` and the gRPC health
+    /// service. A server is ready unless marked otherwise, once its startup
+    /// delay has passed and while it is not shutting down.
+    pub fn set_ready(&self, ready: bool) {
+        self.probes.set_ready(ready);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.probes.is_ready()
+    }
+
+    /// A handle for replacing the configured [`Sequence`]s while the server
+    /// runs.
+    pub fn sequence_table(&self) -> SequenceTable {
+        self.sequences.clone()
+    }
+
+    /// Moves every [`Sequence`] back to its first response, for every client.
+    pub fn reset_sequences(&self) {
+        self.sequences.reset();
+    }
+
+    /// Marks the server not ready, waits for the drain period, then stops
+    /// accepting connections, lets the workers finish the connections already
+    /// accepted, and waits for every server thread to exit.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    /// Blocks until the admin API's `POST /shutdown` is called, then shuts
+    /// the server down as [`shutdown`](Self::shutdown) does, draining first.
+    /// Without an [admin listener](ServerBuilder::admin) it never returns.
+    pub fn wait(mut self) {
+        self.controls.wait_for_shutdown();
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        let Some(accept_thread) = self.accept_thread.take() else {
+            return;
+        };
+        self.probes.start_draining();
+        thread::sleep(self.drain_period);
+        self.stopping.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it notices the flag.
+        let _ = TcpStream::connect_timeout(&connect_addr(self.local_addr), Duration::from_secs(1));
+        let _ = accept_thread.join();
+        if let (Some(addr), Some(admin_thread)) = (self.admin_addr, self.admin_thread.take()) {
+            let _ = TcpStream::connect_timeout(&connect_addr(addr), Duration::from_secs(1));
+            let _ = admin_thread.join();
+        }
+        self.join_workers();
+    }
+
+    fn join_workers(&mut self) {
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        self.log = None;
+        if let Some(log_thread) = self.log_thread.take() {
+            let _ = log_thread.join();
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// The address to connect to in order to reach a listener bound to `addr`,
+/// replacing an unspecified IP with loopback.
+fn connect_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+fn dispatch_connection(
+    senders: &mut [Option<SyncSender<TcpStream>>],
+    mut stream: TcpStream,
+    log_tx: &LogSender,
+    next: &mut usize,
+) -> bool {
+    if senders.is_empty() {
+        log_tx.event(
+            LogLevel::Warn,
+            "Connection dropped: no workers available".into(),
+        );
+        return false;
+    }
+    let mut dispatched = false;
+    for i in 0..senders.len() {
+        let idx = (*next + i) % senders.len();
+
+        let Some(tx) = senders[idx].as_ref() else {
+            continue;
+        };
+
+        match tx.try_send(stream) {
+            Ok(_) => {
+                dispatched = true;
+                break;
+            }
+            Err(TrySendError::Full(returned)) => {
+                stream = returned;
+                continue;
+            }
+            Err(TrySendError::Disconnected(returned)) => {
+                stream = returned;
+                log_tx.event(LogLevel::Error, format!("Worker {} disconnected", idx));
+                senders[idx] = None;
+                continue;
+            }
+        }
+    }
+    *next = (*next + 1) % senders.len();
+    if !dispatched {
+        log_tx.event(
+            LogLevel::Warn,
+            "Connection dropped: all workers unavailable or queues full".into(),
+        );
+    }
+    dispatched
+}
+
+/// Probes the local listener bound to `bind` with `GET path` (e.g.
+/// `/readyz`) and reports whether it answered 200. An unspecified bind
+/// address is probed on loopback.
+pub fn health_check(bind: SocketAddr, use_tls: bool, path: &str) -> bool {
+    let addr = connect_addr(bind);
+    let timeout = Duration::from_secs(5);
+    let Ok(mut stream) = TcpStream::connect_timeout(&addr, timeout) else {
+        return false;
+    };
+    stream.set_read_timeout(Some(timeout)).ok();
+    stream.set_write_timeout(Some(timeout)).ok();
+    if !use_tls {
+        return probe(&mut stream, "GET", path);
+    }
+    #[cfg(feature = "tls")]
+    {
+        tls::connect_for_health_check(stream)
+            .is_ok_and(|mut tls_stream| probe(&mut tls_stream, "GET", path))
+    }
+    #[cfg(not(feature = "tls"))]
+    false
+}
+
+/// Asks the server whose admin listener is bound to `admin` to shut down,
+/// with `POST /shutdown`, then waits until that listener closes once the
+/// drain period is over. Reports whether the server accepted the request.
+pub fn request_shutdown(admin: SocketAddr) -> bool {
+    let addr = connect_addr(admin);
+    let timeout = Duration::from_secs(5);
+    let Ok(mut stream) = TcpStream::connect_timeout(&addr, timeout) else {
+        return false;
+    };
+    stream.set_read_timeout(Some(timeout)).ok();
+    stream.set_write_timeout(Some(timeout)).ok();
+    if !probe(&mut stream, "POST", "/shutdown") {
+        return false;
+    }
+    while TcpStream::connect_timeout(&addr, timeout).is_ok() {
+        thread::sleep(Duration::from_millis(100));
+    }
+    true
+}
+
+fn probe(stream: &mut (impl Read + Write), method: &str, path: &str) -> bool {
+    const EXPECTED: &[u8] = b"HTTP/1.1 200 ";
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        method, path
+    );
+    if stream.write_all(request.as_bytes()).is_err() {
+        return false;
+    }
+    let mut status = [0u8; EXPECTED.len()];
+    stream.read_exact(&mut status).is_ok() && status == EXPECTED
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_check_succeeds_against_running_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &ServerState::default());
+        });
+        let bind = SocketAddr::from(([0, 0, 0, 0], port));
+        assert!(health_check(bind, false, "/readyz"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn health_check_fails_without_listener() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        assert!(!health_check(addr, false, "/"));
+    }
+
+    fn make_stream_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn retries_across_workers_when_queue_full() {
+        let (tx1, rx1) = sync_channel::<TcpStream>(1);
+        let (tx2, rx2) = sync_channel::<TcpStream>(1);
+        let mut senders = vec![Some(tx1), Some(tx2)];
+        let (client1, _server1) = make_stream_pair();
+        // Fill first queue
+        senders[0].as_ref().unwrap().try_send(client1).unwrap();
+
+        let (client2, server2) = make_stream_pair();
+        let (log_tx, _log_rx) = log_channel(10, LogLevel::Info);
+        let mut next = 0;
+
+        let dispatched = dispatch_connection(&mut senders, client2, &log_tx, &mut next);
+        assert!(dispatched);
+        let recv2 = rx2.try_recv().expect("worker 2 should receive");
+        assert_eq!(recv2.peer_addr().unwrap(), server2.local_addr().unwrap());
+        assert!(rx1.try_recv().is_ok());
+    }
+
+    #[test]
+    fn advances_round_robin_counter_with_multiple_workers() {
+        let (tx1, _rx1) = sync_channel::<TcpStream>(1);
+        let (tx2, rx2) = sync_channel::<TcpStream>(1);
+        let mut senders = vec![Some(tx1), Some(tx2)];
+        let (client, _server) = make_stream_pair();
+        let (log_tx, _log_rx) = log_channel(10, LogLevel::Info);
+        let mut next = 0;
+        let dispatched = dispatch_connection(&mut senders, client, &log_tx, &mut next);
+        assert!(dispatched);
+        assert_eq!(next, 1);
+        let _ = rx2.try_recv();
+    }
+
+    #[test]
+    fn drops_after_all_workers_tried() {
+        let (tx1, rx1) = sync_channel::<TcpStream>(1);
+        let (tx2, rx2) = sync_channel::<TcpStream>(1);
+        let mut senders = vec![Some(tx1), Some(tx2)];
+        let (client1, _server1) = make_stream_pair();
+        let (client2, _server2) = make_stream_pair();
+        senders[0].as_ref().unwrap().try_send(client1).unwrap();
+        senders[1].as_ref().unwrap().try_send(client2).unwrap();
+
+        let (client3, _server3) = make_stream_pair();
+        let (log_tx, log_rx) = log_channel(10, LogLevel::Info);
+        let mut next = 0;
+
+        let dispatched = dispatch_connection(&mut senders, client3, &log_tx, &mut next);
+        assert!(!dispatched);
+        assert!(log_rx.try_iter().any(|m| m.contains("unavailable")));
+        drop(rx1);
+        drop(rx2);
+    }
+
+    #[test]
+    fn skips_disconnected_workers() {
+        let (tx1, _rx1) = sync_channel::<TcpStream>(1);
+        let (tx2, rx2) = sync_channel::<TcpStream>(1);
+        let mut senders = vec![Some(tx1), Some(tx2)];
+        // Drop receiver for worker 0 to force Disconnected
+        drop(_rx1);
+
+        let (client, _server) = make_stream_pair();
+        let (log_tx, log_rx) = log_channel(10, LogLevel::Info);
+        let mut next = 0;
+
+        let dispatched = dispatch_connection(&mut senders, client, &log_tx, &mut next);
+        assert!(dispatched);
+        assert!(rx2.try_recv().is_ok());
+        assert!(senders[0].is_none());
+        assert!(log_rx.try_iter().any(|m| m.contains("disconnected")));
+    }
+}
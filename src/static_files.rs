@@ -0,0 +1,234 @@
+//! Serves the files of a directory under a URL prefix, for the odd
+//! `robots.txt` or JSON fixture next to `OK`. Files are streamed from disk
+//! when the response is written.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::conditional::http_date;
+use crate::handler::{Handler, Middleware, Request, Response};
+
+/// Content types by lowercase file extension; anything else is sent as
+/// `application/octet-stream`.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("css", "text/css; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("gif", "image/gif"),
+    ("htm", "text/html; charset=utf-8"),
+    ("html", "text/html; charset=utf-8"),
+    ("ico", "image/x-icon"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("wasm", "application/wasm"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xml", "application/xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+];
+
+/// Answers `GET` and `HEAD` for files under `root`; paths without a file go
+/// on to the handler.
+pub(crate) struct StaticFiles {
+    /// Always ends with `/`.
+    prefix: String,
+    /// Canonical, so resolved files can be checked to stay inside it.
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub(crate) fn new(prefix: &str, dir: &Path) -> io::Result<Self> {
+        if !prefix.starts_with('/') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("static prefix {:?} must start with '/'", prefix),
+            ));
+        }
+        let root = fs::canonicalize(dir)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", dir.display(), e)))?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", dir.display()),
+            ));
+        }
+        let mut prefix = prefix.to_string();
+        if !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        Ok(StaticFiles { prefix, root })
+    }
+
+    /// The file `path` names under the root, if it exists and stays inside
+    /// it. `None` for anything else, including traversal attempts.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.prefix)?;
+        let mut resolved = self.root.clone();
+        for segment in relative.split('/') {
+            let segment = percent_decode(segment)?;
+            let mut components = Path::new(&segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(name)), None) if name == segment.as_str() => {
+                    resolved.push(name)
+                }
+                _ => return None,
+            }
+        }
+        // Symlinks may still point outside the root.
+        let resolved = fs::canonicalize(resolved).ok()?;
+        (resolved.starts_with(&self.root) && resolved.is_file()).then_some(resolved)
+    }
+}
+
+impl Middleware for StaticFiles {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let Some(path) = self.resolve(request.path()) else {
+            return next.handle(request);
+        };
+        if !matches!(request.method(), "GET" | "HEAD") {
+            return Response::new(405).with_header("Allow", "GET, HEAD");
+        }
+        let opened = File::open(&path).and_then(|file| Ok((file.metadata()?, file)));
+        let Ok((metadata, file)) = opened else {
+            return Response::new(404);
+        };
+        let mut response = Response::new(200)
+            .with_header("Content-Type", content_type(&path))
+            .with_header("Accept-Ranges", "bytes");
+        if let Ok(modified) = metadata.modified() {
+            response = response.with_header("Last-Modified", http_date(modified));
+        }
+        response.with_file(file, metadata.len())
+    }
+}
+
+/// Decodes `%XX` escapes in one path segment. `None` for malformed escapes,
+/// non-UTF-8 results and NUL bytes.
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    if decoded.contains(&0) {
+        return None;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+pub(crate) fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    CONTENT_TYPES
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map_or("application/octet-stream", |(_, content_type)| content_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{percent_decode, StaticFiles};
+    use crate::handler::{Chain, Handler, Request, Response};
+    use std::fs;
+    use std::path::Path;
+
+    fn with_dir(name: &str, test: impl FnOnce(&Path)) {
+        let dir = std::env::temp_dir().join(format!("ok-static-{}-{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("public/data")).unwrap();
+        fs::write(dir.join("public/robots.txt"), "User-agent: *\n").unwrap();
+        fs::write(dir.join("public/data/a b.json"), "{}").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        test(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn chain(prefix: &str, dir: &Path) -> Chain {
+        Chain::new(
+            vec![Box::new(
+                StaticFiles::new(prefix, &dir.join("public")).unwrap(),
+            )],
+            Box::new(|_: &Request| Response::text("OK")),
+        )
+    }
+
+    #[test]
+    fn serves_files_with_their_metadata() {
+        with_dir("serve", |dir| {
+            let chain = chain("/files", dir);
+            let response = chain.handle(&Request::new("GET", "/files/robots.txt"));
+            assert_eq!(response.status(), 200);
+            assert_eq!(
+                response.header("content-type"),
+                Some("text/plain; charset=utf-8")
+            );
+            assert!(response.header("last-modified").unwrap().ends_with(" GMT"));
+            assert_eq!(response.body_len(), 14);
+            let mut body = Vec::new();
+            response
+                .write_body(u64::MAX, |chunk, _| {
+                    body.extend_from_slice(chunk);
+                    Ok::<_, std::io::Error>(())
+                })
+                .unwrap();
+            assert_eq!(body, b"User-agent: *\n");
+
+            let json = chain.handle(&Request::new("HEAD", "/files/data/a%20b.json"));
+            assert_eq!(json.header("content-type"), Some("application/json"));
+            let post = chain.handle(&Request::new("POST", "/files/robots.txt"));
+            assert_eq!(post.status(), 405);
+        });
+    }
+
+    #[test]
+    fn leaves_other_paths_to_the_handler() {
+        with_dir("traversal", |dir| {
+            let chain = chain("/", dir);
+            for path in [
+                "/",
+                "/data",
+                "/data/",
+                "/missing.txt",
+                "/../secret.txt",
+                "/%2e%2e/secret.txt",
+                "/data/..%2f..%2fsecret.txt",
+                "/data//a%20b.json",
+                "/robots.txt%00",
+                "/%zz",
+            ] {
+                let response = chain.handle(&Request::new("GET", path));
+                assert_eq!(response.body(), b"OK", "{}", path);
+            }
+        });
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc").as_deref(), Some("a b/c"));
+        assert_eq!(percent_decode("%e2%82%ac").as_deref(), Some("€"));
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%ff"), None);
+        assert_eq!(percent_decode("a%00"), None);
+    }
+}
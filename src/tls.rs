@@ -0,0 +1,333 @@
+//! Optional TLS termination (cargo feature `tls`), built on rustls.
+//!
+//! The certificate chain and private key are read from PEM files and reloaded
+//! when either file changes, so rotated certificates are picked up without a
+//! restart. Connections already established keep their original certificate.
+
+use std::io;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, ServerConfig, ServerConnection,
+    SignatureScheme, StreamOwned,
+};
+
+use crate::logging::{LogLevel, LogSender};
+use crate::Transport;
+
+pub(crate) type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+#[cfg(test)]
+fn reload_check_interval() -> Duration {
+    Duration::ZERO
+}
+
+#[cfg(not(test))]
+fn reload_check_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+impl Transport for TlsStream {
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    fn is_http2(&mut self) -> bool {
+        self.conn.alpn_protocol() == Some(b"h2")
+    }
+}
+
+/// Modification time and length of the certificate and key files, used to
+/// notice when they have been replaced.
+type FileStamps = [Option<(SystemTime, u64)>; 2];
+
+struct Loaded {
+    config: Arc<ServerConfig>,
+    stamps: FileStamps,
+    checked_at: Instant,
+}
+
+pub(crate) struct TlsAcceptor {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+impl TlsAcceptor {
+    /// Loads the certificate chain and key, failing if either is unusable.
+    pub(crate) fn new(cert_path: PathBuf, key_path: PathBuf) -> io::Result<Self> {
+        let stamps = file_stamps(&cert_path, &key_path);
+        let config = load_config(&cert_path, &key_path)?;
+        Ok(TlsAcceptor {
+            cert_path,
+            key_path,
+            loaded: Mutex::new(Loaded {
+                config,
+                stamps,
+                checked_at: Instant::now(),
+            }),
+        })
+    }
+
+    /// Performs the TLS handshake on an accepted connection.
+    pub(crate) fn accept(&self, mut sock: TcpStream, log_tx: &LogSender) -> io::Result<TlsStream> {
+        let timeout = Duration::from_secs(5);
+        sock.set_read_timeout(Some(timeout)).ok();
+        sock.set_write_timeout(Some(timeout)).ok();
+        let mut conn =
+            ServerConnection::new(self.current_config(log_tx)).map_err(io::Error::other)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+        Ok(StreamOwned::new(conn, sock))
+    }
+
+    /// Returns the active configuration, first reloading it if the files on
+    /// disk changed. A failed reload keeps the previous certificate in use.
+    fn current_config(&self, log_tx: &LogSender) -> Arc<ServerConfig> {
+        let mut loaded = self.loaded.lock().unwrap_or_else(PoisonError::into_inner);
+        if loaded.checked_at.elapsed() >= reload_check_interval() {
+            loaded.checked_at = Instant::now();
+            let stamps = file_stamps(&self.cert_path, &self.key_path);
+            if stamps != loaded.stamps {
+                loaded.stamps = stamps;
+                match load_config(&self.cert_path, &self.key_path) {
+                    Ok(config) => {
+                        loaded.config = config;
+                        log_tx.event(LogLevel::Info, "Reloaded TLS certificate".into());
+                    }
+                    Err(e) => log_tx.event(
+                        LogLevel::Error,
+                        format!("TLS certificate reload failed, keeping previous: {}", e),
+                    ),
+                }
+            }
+        }
+        Arc::clone(&loaded.config)
+    }
+}
+
+fn file_stamps(cert_path: &Path, key_path: &Path) -> FileStamps {
+    [cert_path, key_path].map(|path| {
+        std::fs::metadata(path)
+            .and_then(|m| Ok((m.modified()?, m.len())))
+            .ok()
+    })
+}
+
+fn load_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let pem_error = |path: &Path, e: rustls::pki_types::pem::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    };
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(cert_path, e))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no certificates found", cert_path.display()),
+        ));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| pem_error(key_path, e))?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Opens a TLS connection for the local health check. The certificate is not
+/// verified against any roots — the probe only talks to our own listener on
+/// loopback, which commonly serves a self-signed or internal certificate —
+/// but handshake signatures are still checked.
+pub(crate) fn connect_for_health_check(
+    sock: TcpStream,
+) -> io::Result<StreamOwned<ClientConnection, TcpStream>> {
+    let provider = Arc::new(ring::default_provider());
+    let verifier = Arc::new(AnyServerCertificate(Arc::clone(&provider)));
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    let server_name = ServerName::try_from("localhost").expect("valid server name");
+    let conn = ClientConnection::new(Arc::new(config), server_name).map_err(io::Error::other)?;
+    Ok(StreamOwned::new(conn, sock))
+}
+
+#[derive(Debug)]
+struct AnyServerCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyServerCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect_for_health_check, TlsAcceptor};
+    use crate::http1::handle_connection;
+    use crate::logging::{log_channel, LogLevel};
+    use crate::server::{health_check, ServerState};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+
+    struct TempCert {
+        dir: PathBuf,
+    }
+
+    impl TempCert {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("ok-tls-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let cert = TempCert { dir };
+            cert.regenerate();
+            cert
+        }
+
+        /// Writes a fresh self-signed certificate for `localhost` and returns its DER.
+        fn regenerate(&self) -> Vec<u8> {
+            let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            std::fs::write(self.cert_path(), certified.cert.pem()).unwrap();
+            std::fs::write(self.key_path(), certified.signing_key.serialize_pem()).unwrap();
+            certified.cert.der().to_vec()
+        }
+
+        fn cert_path(&self) -> PathBuf {
+            self.dir.join("cert.pem")
+        }
+
+        fn key_path(&self) -> PathBuf {
+            self.dir.join("key.pem")
+        }
+    }
+
+    impl Drop for TempCert {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Serves `connections` TLS connections with `handle_connection`.
+    fn serve(acceptor: Arc<TlsAcceptor>, connections: usize) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (log_tx, _log_rx) = log_channel(10, LogLevel::Info);
+            let state = ServerState::default();
+            for stream in listener.incoming().take(connections) {
+                if let Ok(tls) = acceptor.accept(stream.unwrap(), &log_tx) {
+                    handle_connection(tls, &state);
+                }
+            }
+        });
+        port
+    }
+
+    fn get_root(port: u16) -> (String, Vec<u8>) {
+        let sock = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut tls = connect_for_health_check(sock).unwrap();
+        tls.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = tls.read_to_end(&mut response);
+        let peer_cert = tls.conn.peer_certificates().unwrap()[0].to_vec();
+        (String::from_utf8_lossy(&response).into_owned(), peer_cert)
+    }
+
+    #[test]
+    fn serves_requests_over_tls() {
+        let cert = TempCert::new("serve");
+        let acceptor = TlsAcceptor::new(cert.cert_path(), cert.key_path()).unwrap();
+        let port = serve(Arc::new(acceptor), 1);
+        let (response, _) = get_root(port);
+        assert!(
+            response.starts_with("HTTP/1.1 200 OK\r\n"),
+            "unexpected response: {}",
+            response
+        );
+        assert!(response.ends_with("OK"));
+    }
+
+    #[test]
+    fn reloads_certificate_when_files_change() {
+        let cert = TempCert::new("reload");
+        let acceptor = TlsAcceptor::new(cert.cert_path(), cert.key_path()).unwrap();
+        let port = serve(Arc::new(acceptor), 2);
+        let (_, first) = get_root(port);
+        let replacement = cert.regenerate();
+        let (_, second) = get_root(port);
+        assert_ne!(first, second);
+        assert_eq!(second, replacement);
+    }
+
+    #[test]
+    fn rejects_missing_key_file() {
+        let cert = TempCert::new("missing");
+        std::fs::remove_file(cert.key_path()).unwrap();
+        assert!(TlsAcceptor::new(cert.cert_path(), cert.key_path()).is_err());
+    }
+
+    #[test]
+    fn health_check_probes_over_tls() {
+        let cert = TempCert::new("health");
+        let acceptor = TlsAcceptor::new(cert.cert_path(), cert.key_path()).unwrap();
+        let port = serve(Arc::new(acceptor), 1);
+        assert!(health_check(([127, 0, 0, 1], port).into(), true, "/"));
+    }
+}
@@ -0,0 +1,573 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use ok::{
+    request_shutdown, AccessLogFilter, BodyLimit, Echo, Faults, Handler, LogLevel, Request,
+    Response, Sequence, Server, StaticResponse,
+};
+
+fn get(addr: SocketAddr, path: &str) -> String {
+    send(
+        addr,
+        &format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path),
+    )
+}
+
+fn send(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn serves_ok_on_an_ephemeral_port() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .pool_size(1)
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    assert_ne!(server.local_addr().port(), 0);
+    let response = get(server.local_addr(), "/");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nOK"));
+    server.shutdown();
+}
+
+#[test]
+fn serves_configured_routes() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .route(
+            "/ready",
+            StaticResponse::new(503, "application/json", "{\"ready\":false}"),
+        )
+        .show_favicon(false)
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    let response = get(server.local_addr(), "/ready");
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "{}",
+        response
+    );
+    assert!(response.contains("Content-Type: application/json\r\n"));
+    assert!(response.ends_with("{\"ready\":false}"));
+    assert!(get(server.local_addr(), "/favicon.ico").starts_with("HTTP/1.1 404 "));
+}
+
+#[test]
+fn sends_access_log_lines_to_the_log_sink() {
+    let (tx, rx) = mpsc::channel();
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .log_sink(move |line: &str| {
+            let _ = tx.send(line.to_string());
+        })
+        .start()
+        .unwrap();
+    get(server.local_addr(), "/");
+    let line = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(line.contains("\"GET / HTTP/1.1\""), "{}", line);
+}
+
+#[test]
+fn logs_only_requests_selected_by_the_access_log_filter() {
+    let (tx, rx) = mpsc::channel();
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .access_log(AccessLogFilter::default().errors_only(true))
+        .log_sink(move |line: &str| {
+            let _ = tx.send(line.to_string());
+        })
+        .start()
+        .unwrap();
+    get(server.local_addr(), "/");
+    get(server.local_addr(), "/missing");
+    let line = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(line.contains("\"GET /missing HTTP/1.1\""), "{}", line);
+    server.shutdown();
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn replaces_routes_while_running() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .route("/ready", StaticResponse::new(503, "text/plain", "no"))
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    let table = server.route_table().unwrap();
+    let routes = HashMap::from([("/ready".to_string(), StaticResponse::text("yes"))]);
+    table.replace(routes, HashMap::new()).unwrap();
+    let response = get(server.local_addr(), "/ready");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("yes"));
+
+    let invalid = HashMap::from([("ready".to_string(), StaticResponse::text("x"))]);
+    assert!(table.replace(invalid, HashMap::new()).is_err());
+    assert!(get(server.local_addr(), "/ready").ends_with("yes"));
+}
+
+#[test]
+fn reports_readiness_separately_from_liveness() {
+    let status = |addr, path| get(addr, path)[9..12].to_string();
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .startup_delay(Duration::from_millis(300))
+        .drain_period(Duration::from_millis(300))
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    let addr = server.local_addr();
+    assert_eq!(status(addr, "/livez"), "200");
+    assert_eq!(status(addr, "/startupz"), "503");
+    assert_eq!(status(addr, "/readyz"), "503");
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(status(addr, "/startupz"), "200");
+    assert_eq!(status(addr, "/readyz"), "200");
+
+    server.set_ready(false);
+    assert_eq!(status(addr, "/readyz"), "503");
+    assert_eq!(status(addr, "/livez"), "200");
+    server.set_ready(true);
+
+    let shutdown = thread::spawn(move || server.shutdown());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(status(addr, "/readyz"), "503");
+    assert_eq!(status(addr, "/livez"), "200");
+    shutdown.join().unwrap();
+}
+
+#[test]
+fn serves_the_admin_api_on_its_own_listener() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .admin("127.0.0.1:0".parse().unwrap())
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    let public = server.local_addr();
+    let admin = server.admin_addr().unwrap();
+    let post = |addr, path: &str| {
+        send(
+            addr,
+            &format!(
+                "POST {} HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n",
+                path
+            ),
+        )
+    };
+
+    // An idle admin client must not hold up the others.
+    let idle = TcpStream::connect(admin).unwrap();
+    assert!(post(public, "/unready").starts_with("HTTP/1.1 501 "));
+    assert!(post(admin, "/unready").starts_with("HTTP/1.1 200 "));
+    drop(idle);
+    assert!(get(public, "/readyz").starts_with("HTTP/1.1 503 "));
+    assert!(!server.is_ready());
+
+    post(admin, "/root/unhealthy");
+    assert!(get(public, "/").starts_with("HTTP/1.1 503 "));
+    let stats = get(admin, "/stats");
+    assert!(stats.contains("\"root\":\"unhealthy\""), "{}", stats);
+    assert!(stats.contains("\"5xx\":3"), "{}", stats);
+    server.shutdown();
+}
+
+#[test]
+fn serves_response_sequences_until_reset() {
+    let down = StaticResponse::new(503, "text/plain", "down");
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .admin("127.0.0.1:0".parse().unwrap())
+        .sequence("/flaky", Sequence::new([down, StaticResponse::text("OK")]))
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    let public = server.local_addr();
+    assert!(get(public, "/flaky").starts_with("HTTP/1.1 503 "));
+    assert!(get(public, "/flaky").starts_with("HTTP/1.1 200 "));
+    assert!(get(public, "/flaky").starts_with("HTTP/1.1 200 "));
+
+    let reset = send(
+        server.admin_addr().unwrap(),
+        "POST /sequences/reset HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n",
+    );
+    assert!(reset.starts_with("HTTP/1.1 200 "), "{}", reset);
+    assert!(get(public, "/flaky").starts_with("HTTP/1.1 503 "));
+    server.reset_sequences();
+    assert!(get(public, "/flaky").starts_with("HTTP/1.1 503 "));
+    server.shutdown();
+}
+
+#[test]
+fn replaces_sequences_while_running() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    assert!(get(server.local_addr(), "/flaky").starts_with("HTTP/1.1 404 "));
+    let down = StaticResponse::new(503, "text/plain", "down");
+    let sequences = HashMap::from([("/flaky".to_string(), Sequence::new([down]))]);
+    server.sequence_table().replace(sequences).unwrap();
+    assert!(get(server.local_addr(), "/flaky").starts_with("HTTP/1.1 503 "));
+    server.shutdown();
+}
+
+#[test]
+fn serves_diagnostic_endpoints_when_enabled() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .diagnostics(true)
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    let addr = server.local_addr();
+    assert!(get(addr, "/status/502").starts_with("HTTP/1.1 502 "));
+    assert!(get(addr, "/bytes/10").contains("Content-Length: 10\r\n"));
+    let ip = get(addr, "/ip");
+    assert!(ip.contains("{\"origin\":\"127.0.0.1:"), "{}", ip);
+    let anything = send(
+        addr,
+        "PUT /anything?x=1 HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\nhi",
+    );
+    assert!(anything.contains("\"method\":\"PUT\""), "{}", anything);
+    assert!(anything.contains("\"body\":\"hi\""), "{}", anything);
+    server.shutdown();
+
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    assert!(get(server.local_addr(), "/status/502").starts_with("HTTP/1.1 404 "));
+    server.shutdown();
+}
+
+#[test]
+fn echoes_requests_of_any_method() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .handler(Echo::Text)
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    let request = "DELETE /items/7 HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\ngone";
+    let response = send(server.local_addr(), request);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(
+        response.ends_with(&format!("\r\n\r\n{}", request)),
+        "{}",
+        response
+    );
+    server.shutdown();
+}
+
+#[test]
+fn captures_recent_requests() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .admin("127.0.0.1:0".parse().unwrap())
+        .capture_requests(10)
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    let public = server.local_addr();
+    let admin = server.admin_addr().unwrap();
+    get(public, "/orders?id=3");
+    let captured = get(public, "/__requests");
+    assert!(
+        captured.contains("\"method\":\"GET\",\"path\":\"/orders\",\"query\":\"id=3\""),
+        "{}",
+        captured
+    );
+    assert!(get(admin, "/requests").contains("\"path\":\"/orders\""));
+
+    let delete = "DELETE /requests HTTP/1.1\r\nHost: x\r\n\r\n";
+    assert!(send(admin, delete).starts_with("HTTP/1.1 204 "));
+    assert!(get(public, "/__requests").ends_with("\r\n\r\n[]\n"));
+    server.shutdown();
+}
+
+#[test]
+fn records_requests_with_their_responses() {
+    let (tx, rx) = mpsc::channel();
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .record(move |line: &str| tx.send(line.to_string()).unwrap())
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    get(server.local_addr(), "/?a=1");
+    server.shutdown();
+    let records: Vec<String> = rx.try_iter().collect();
+    assert_eq!(records.len(), 1, "{:?}", records);
+    let record = &records[0];
+    assert!(record.contains("\"target\":\"/?a=1\""), "{}", record);
+    assert!(
+        record.contains("\"response\":{\"status\":200"),
+        "{}",
+        record
+    );
+}
+
+#[test]
+fn injects_configured_faults() {
+    let start = |faults: Faults| {
+        Server::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .faults(faults)
+            .log_sink(|_: &str| {})
+            .start()
+            .unwrap()
+    };
+    let server = start(Faults::default().errors(1.0, [503]));
+    let response = get(server.local_addr(), "/");
+    assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+    server.shutdown();
+
+    let server = start(Faults::default().resets(1.0));
+    assert_eq!(get(server.local_addr(), "/"), "");
+    server.shutdown();
+
+    let server = start(Faults::default().truncations(1.0));
+    let response = get(server.local_addr(), "/");
+    assert!(response.contains("Content-Length: 2\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nO"), "{}", response);
+    server.shutdown();
+
+    assert!(Server::builder()
+        .faults(Faults::default().resets(2.0))
+        .start()
+        .is_err());
+}
+
+#[test]
+fn stops_accepting_after_shutdown() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    let addr = server.local_addr();
+    server.shutdown();
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn drains_and_stops_when_asked_through_the_admin_api() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .admin("127.0.0.1:0".parse().unwrap())
+        .drain_period(Duration::from_millis(300))
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    let (public, admin) = (server.local_addr(), server.admin_addr().unwrap());
+    let waiting = thread::spawn(move || server.wait());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || tx.send(request_shutdown(admin)).unwrap());
+    thread::sleep(Duration::from_millis(100));
+    // Still serving during the drain period, but no longer ready.
+    assert!(get(public, "/readyz").starts_with("HTTP/1.1 503 "));
+    assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    waiting.join().unwrap();
+    assert!(TcpStream::connect(public).is_err());
+}
+
+#[test]
+fn rejects_invalid_routes_at_start() {
+    let result = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .route("/bad", StaticResponse::new(200, "text/plain\r\nX: y", "x"))
+        .start();
+    assert!(result.is_err());
+}
+
+#[test]
+fn answers_with_a_custom_handler_and_middleware() {
+    let echo = |request: &Request| {
+        let body = format!(
+            "{} {} {:?} {}",
+            request.method(),
+            request.path(),
+            request.query(),
+            String::from_utf8_lossy(request.body())
+        );
+        let token = request.header("x-token").unwrap_or("").to_string();
+        Response::text(body).with_header("X-Token", token)
+    };
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .handler(echo)
+        .middleware(|request: &Request, next: &dyn Handler| {
+            next.handle(request).with_header("X-Wrapped", "yes")
+        })
+        .middleware(BodyLimit::new(8))
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+
+    let response = send(
+        server.local_addr(),
+        "POST /items?id=7 HTTP/1.1\r\nHost: x\r\nX-Token: t\r\nContent-Length: 5\r\n\r\nhello",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("X-Token: t\r\n"));
+    assert!(response.contains("X-Wrapped: yes\r\n"));
+    assert!(response.ends_with("POST /items Some(\"id=7\") hello"));
+
+    let response = send(
+        server.local_addr(),
+        "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 9\r\n\r\n123456789",
+    );
+    assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+    assert!(response.contains("X-Wrapped: yes\r\n"));
+}
+
+#[test]
+fn serves_a_static_directory() {
+    let dir = std::env::temp_dir().join(format!("ok-static-dir-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let fixture = "[1,2,3]".repeat(10_000);
+    std::fs::write(dir.join("fixture.json"), &fixture).unwrap();
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .static_dir("/static", &dir)
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    let response = get(server.local_addr(), "/static/fixture.json");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+    assert!(response.contains("\r\nContent-Length: 70000\r\n"));
+    assert!(response.contains("\r\nLast-Modified: "));
+    assert!(response.ends_with(&format!("\r\n\r\n{}", fixture)));
+    let modified = response
+        .lines()
+        .find_map(|line| line.strip_prefix("Last-Modified: "))
+        .unwrap();
+    let request = format!(
+        "GET /static/fixture.json HTTP/1.1\r\nHost: localhost\r\nIf-Modified-Since: {}\r\n\r\n",
+        modified
+    );
+    let cached = send(server.local_addr(), &request);
+    assert!(cached.starts_with("HTTP/1.1 304 "), "{}", cached);
+    assert!(cached.ends_with("\r\n\r\n"), "{}", cached);
+    let request = "GET /static/fixture.json HTTP/1.1\r\nHost: localhost\r\n\
+                   Range: bytes=1-5,-2\r\n\r\n";
+    let partial = send(server.local_addr(), request);
+    assert!(partial.starts_with("HTTP/1.1 206 "), "{}", partial);
+    assert!(partial.contains("Content-Range: bytes 1-5/70000\r\n\r\n1,2,3\r\n"));
+    assert!(partial.contains("Content-Range: bytes 69998-69999/70000\r\n\r\n3]\r\n"));
+    let escaped = get(server.local_addr(), "/static/%2e%2e/Cargo.toml");
+    assert!(escaped.starts_with("HTTP/1.1 404 "), "{}", escaped);
+    server.shutdown();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn compresses_responses_for_clients_that_accept_it() {
+    let fixture = "{\"id\":1,\"name\":\"fixture\"}\n".repeat(100);
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .route(
+            "/fixture.json",
+            StaticResponse::new(200, "application/json", fixture.clone()),
+        )
+        .log_sink(|_: &str| {})
+        .start()
+        .unwrap();
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream
+        .write_all(
+            b"GET /fixture.json HTTP/1.1\r\nHost: localhost\r\n\
+              Accept-Encoding: br;q=1.0, gzip;q=0.8\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let head = String::from_utf8_lossy(&response[..end]);
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    assert!(head.contains("\r\nContent-Encoding: gzip\r\n"), "{}", head);
+    assert!(head.contains("\r\nVary: Accept-Encoding\r\n"), "{}", head);
+    let body = &response[end..];
+    assert_eq!(body[..2], [0x1f, 0x8b]);
+    assert!(body.len() < fixture.len() / 4, "{}", body.len());
+    assert!(head.contains(&format!("\r\nContent-Length: {}\r\n", body.len())));
+
+    let identity = get(server.local_addr(), "/fixture.json");
+    assert!(
+        identity.contains("\r\nVary: Accept-Encoding\r\n"),
+        "{}",
+        identity
+    );
+    assert!(!identity.contains("Content-Encoding"), "{}", identity);
+    assert!(identity.ends_with(&fixture));
+    server.shutdown();
+}
+
+#[test]
+fn logs_events_through_the_server_logger() {
+    let (tx, rx) = mpsc::channel();
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .log_level(LogLevel::Info)
+        .log_sink(move |line: &str| {
+            let _ = tx.send(line.to_string());
+        })
+        .start()
+        .unwrap();
+    let logger = server.logger();
+    logger.log(LogLevel::Debug, "hidden");
+    logger.log(LogLevel::Info, "Reloaded routes");
+    let line = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(line.contains("Reloaded routes"), "{}", line);
+    // A handle that outlives the server must not keep it from stopping.
+    server.shutdown();
+    logger.log(LogLevel::Error, "dropped");
+    assert!(rx.try_iter().all(|line| !line.contains("hidden")));
+}
+
+#[test]
+fn filters_the_access_log_on_the_status_sent() {
+    let dir = std::env::temp_dir().join(format!("ok-finished-log-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("digits.txt"), "0123456789").unwrap();
+    let (tx, rx) = mpsc::channel();
+    let server = Server::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .static_dir("/static", &dir)
+        .access_log(AccessLogFilter::default().errors_only(true))
+        .log_sink(move |line: &str| {
+            let _ = tx.send(line.to_string());
+        })
+        .start()
+        .unwrap();
+    let request = "GET /static/digits.txt HTTP/1.1\r\nHost: localhost\r\n\
+                   Range: bytes=20-30\r\n\r\n";
+    let response = send(server.local_addr(), request);
+    assert!(response.starts_with("HTTP/1.1 416 "), "{}", response);
+    let line = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(
+        line.contains("\"GET /static/digits.txt HTTP/1.1\""),
+        "{}",
+        line
+    );
+    server.shutdown();
+    std::fs::remove_dir_all(&dir).unwrap();
+}